@@ -0,0 +1,356 @@
+//! The asynchronous HTTP Client, enabled by the `async` feature.
+//!
+//! The TCP connection and the TLS session are driven by non-blocking
+//! sockets registered in the [async-io](https://docs.rs/async-io) reactor,
+//! the responses can be awaited from any executor, and many requests can
+//! be sent concurrently from a single thread.
+//!
+//! A request is cancelled by dropping its future, the connection is closed.
+//!
+//! # Example
+//! ```no_run
+//! extern crate cabot;
+//! extern crate futures_lite;
+//!
+//! use futures_lite::future;
+//! use cabot::RequestBuilder;
+//! use cabot::async_client::AsyncClient;
+//!
+//! let client = AsyncClient::new();
+//! let rust = RequestBuilder::new("https://www.rust-lang.org/").build().unwrap();
+//! let crates = RequestBuilder::new("https://crates.io/").build().unwrap();
+//! let (rust, crates) = future::block_on(future::zip(client.execute(&rust),
+//!                                                   client.execute(&crates)));
+//! println!("{} {}", rust.unwrap().status_code(), crates.unwrap().status_code());
+//! ```
+
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_io::Async;
+use futures_lite::io::{AsyncRead, AsyncWrite};
+use rustls::{Session, ClientSession};
+
+use super::constants;
+use super::dns::Resolver;
+use super::http;
+use super::parser::ResponseParser;
+use super::request::Request;
+use super::response::Response;
+use super::results::{CabotResult, CabotError};
+use super::timings::Timer;
+
+const BUFFER_PAGE_SIZE: usize = 1024;
+
+
+/// Perform the http query asynchronously.
+///
+/// The responses are parsed like the [Client](../client/struct.Client.html)
+/// does, netrc, cache, progress monitor and Digest authentication are not
+/// supported. The DNS lookup is blocking, and the body is sent without
+/// waiting for the answer to the `Expect: 100-continue` header.
+#[derive(Debug, Clone)]
+pub struct AsyncClient {
+    verbose: bool,
+    compressed: bool,
+}
+
+
+impl AsyncClient {
+    /// Construct a new `AsyncClient`
+    pub fn new() -> Self {
+        AsyncClient {
+            verbose: false,
+            compressed: false,
+        }
+    }
+
+    /// Display the request and the response headers on stderr.
+    pub fn set_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Request a compressed response, using the `Accept-Encoding` header,
+    /// and decode the response body.
+    pub fn set_compressed(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    /// Execute the query [Request](../request/struct.Request.html), the
+    /// future resolves to the associate
+    /// [Response](../response/struct.Response.html).
+    ///
+    /// Nothing is sent until the future is polled.
+    pub fn execute(&self,
+                   request: &Request)
+                   -> impl Future<Output = CabotResult<Response>> + Send {
+        let mut request = request.clone();
+        if self.compressed && request.header("Accept-Encoding").is_none() {
+            request.add_header(&format!("Accept-Encoding: {}", constants::ACCEPT_ENCODING));
+        }
+        ResponseFuture {
+            request: request,
+            verbose: self.verbose,
+            compressed: self.compressed,
+            timer: Timer::new(),
+            state: State::Resolve,
+        }
+    }
+}
+
+
+enum State {
+    Resolve,
+    Connect(SocketAddr, Pin<Box<Future<Output = io::Result<Async<TcpStream>>> + Send>>),
+    Transfer(Connection),
+    Done,
+}
+
+
+struct ResponseFuture {
+    request: Request,
+    verbose: bool,
+    compressed: bool,
+    timer: Timer,
+    state: State,
+}
+
+
+impl ResponseFuture {
+    fn poll_response(&mut self, cx: &mut Context) -> CabotResult<Poll<Response>> {
+        loop {
+            match self.state {
+                State::Resolve => {
+                    let addr = Resolver::new(self.verbose).get_addr(self.request.authority())?;
+                    self.timer.dns_lookup();
+                    info!("Connecting to {}", addr);
+                    self.state = State::Connect(addr, Box::pin(Async::<TcpStream>::connect(addr)));
+                }
+                State::Connect(addr, ref mut connect) => {
+                    let sock = match connect.as_mut().poll(cx) {
+                        Poll::Pending => return Ok(Poll::Pending),
+                        Poll::Ready(sock) => {
+                            sock.map_err(|err| {
+                                    CabotError::ConnectError(format!("{}: {}", addr, err))
+                                })?
+                        }
+                    };
+                    self.timer.connect(Some(addr));
+                    let connection = Connection::new(&self.request, sock, self.verbose)?;
+                    self.state = State::Transfer(connection);
+                }
+                State::Transfer(ref mut connection) => {
+                    let parser = match connection.poll_transfer(cx, &mut self.timer)? {
+                        Poll::Pending => return Ok(Poll::Pending),
+                        Poll::Ready(parser) => parser,
+                    };
+                    self.state = State::Done;
+                    let mut response = parser.finish()?;
+                    let timer = mem::replace(&mut self.timer, Timer::new());
+                    response.set_timings(timer.finish());
+                    if self.compressed {
+                        response.decode_content()?;
+                    }
+                    return Ok(Poll::Ready(response));
+                }
+                State::Done => panic!("Response polled after completion"),
+            }
+        }
+    }
+}
+
+
+impl Future for ResponseFuture {
+    type Output = CabotResult<Response>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.poll_response(cx) {
+            Ok(Poll::Ready(response)) => Poll::Ready(Ok(response)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(err) => {
+                self.state = State::Done;
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+
+/// A connection sending a request, then receiving its response until it
+/// is complete or the server closes the connection.
+struct Connection {
+    sock: Async<TcpStream>,
+    tls: Option<ClientSession>,
+    is_handshaking: bool,
+    // The request, written to the TLS session once the handshake is complete
+    plaintext: Vec<u8>,
+    // Bytes to write to the socket, and the count already written
+    outgoing: Vec<u8>,
+    written: usize,
+    parser: ResponseParser,
+}
+
+
+impl Connection {
+    fn new(request: &Request, sock: Async<TcpStream>, verbose: bool) -> CabotResult<Self> {
+        let mut raw_request = request.to_bytes();
+        http::log_request(raw_request.as_slice(), verbose);
+        // The body stream is read at once, when the connection is established
+        request.write_body_stream(&mut raw_request).map_err(io_error)?;
+        let (tls, plaintext, outgoing) = match request.scheme() {
+            "http" => (None, Vec::new(), raw_request),
+            "https" => (Some(http::tls_session(request, &[])?), raw_request, Vec::new()),
+            _ => {
+                return Err(CabotError::SchemeError(format!("Unrecognized scheme {}",
+                                                           request.scheme())))
+            }
+        };
+        Ok(Connection {
+            sock: sock,
+            tls: tls,
+            is_handshaking: true,
+            plaintext: plaintext,
+            outgoing: outgoing,
+            written: 0,
+            parser: ResponseParser::new().set_http_method(request.http_method()),
+        })
+    }
+
+    /// Send the request and receive the response, the parser is returned
+    /// once the response is complete or the connection is closed.
+    fn poll_transfer(&mut self,
+                     cx: &mut Context,
+                     timer: &mut Timer)
+                     -> CabotResult<Poll<ResponseParser>> {
+        let mut buf = [0; BUFFER_PAGE_SIZE];
+        loop {
+            if let Some(ref mut session) = self.tls {
+                if self.is_handshaking && !session.is_handshaking() {
+                    info!("Handshake complete");
+                    timer.tls_handshake();
+                    self.is_handshaking = false;
+                }
+                if !self.is_handshaking && !self.plaintext.is_empty() {
+                    let count = session.write(self.plaintext.as_slice()).map_err(io_error)?;
+                    self.plaintext.drain(..count);
+                }
+                while session.wants_write() {
+                    session.write_tls(&mut self.outgoing).map_err(io_error)?;
+                }
+            }
+
+            while self.written < self.outgoing.len() {
+                let sock = Pin::new(&mut self.sock);
+                match sock.poll_write(cx, &self.outgoing[self.written..]) {
+                    Poll::Pending => return Ok(Poll::Pending),
+                    Poll::Ready(count) => self.written += count.map_err(io_error)?,
+                }
+            }
+            self.outgoing.clear();
+            self.written = 0;
+
+            let count = match Pin::new(&mut self.sock).poll_read(cx, &mut buf) {
+                Poll::Pending => return Ok(Poll::Pending),
+                Poll::Ready(count) => count.map_err(io_error)?,
+            };
+            debug!("Read {} bytes", count);
+            if count == 0 {
+                return Ok(Poll::Ready(mem::replace(&mut self.parser, ResponseParser::new())));
+            }
+            match self.tls {
+                Some(ref mut session) => {
+                    let mut tls_bytes = &buf[..count];
+                    while !tls_bytes.is_empty() {
+                        session.read_tls(&mut tls_bytes).map_err(io_error)?;
+                        session.process_new_packets()
+                            .map_err(|err| CabotError::CertificateError(format!("{}", err)))?;
+                    }
+                    let mut plaintext = [0; BUFFER_PAGE_SIZE];
+                    loop {
+                        let count = session.read(&mut plaintext).map_err(io_error)?;
+                        if count == 0 {
+                            break;
+                        }
+                        timer.first_byte();
+                        self.parser.feed(&plaintext[..count])?;
+                    }
+                }
+                None => {
+                    timer.first_byte();
+                    self.parser.feed(&buf[..count])?;
+                }
+            }
+            if self.parser.is_complete() {
+                return Ok(Poll::Ready(mem::replace(&mut self.parser, ResponseParser::new())));
+            }
+        }
+    }
+}
+
+
+fn io_error(err: io::Error) -> CabotError {
+    CabotError::IOError(format!("{}", err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    use futures_lite::future;
+
+    use super::*;
+    use super::super::request::RequestBuilder;
+    use super::super::testing::{MockServer, MockResponse};
+
+    #[test]
+    fn test_execute() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello 0"));
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let response = future::block_on(AsyncClient::new().execute(&request)).unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body_as_string().unwrap(), "Hello 0".to_owned());
+        assert!(response.timings().unwrap().first_byte().is_some());
+    }
+
+    #[test]
+    fn test_execute_concurrently() {
+        // The responses are delayed, the requests are answered together
+        let delay = Duration::from_millis(500);
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello 0").set_delay(delay));
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello 1").set_delay(delay));
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let client = AsyncClient::new();
+        let started = Instant::now();
+        let (first, second) = future::block_on(future::zip(client.execute(&request),
+                                                           client.execute(&request)));
+        assert!(started.elapsed() < delay * 2);
+        let mut bodies = vec![first.unwrap().body_as_string().unwrap(),
+                              second.unwrap().body_as_string().unwrap()];
+        bodies.sort();
+        assert_eq!(bodies, vec!["Hello 0".to_owned(), "Hello 1".to_owned()]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let request = RequestBuilder::new(url.as_str()).build().unwrap();
+        let mut response = Box::pin(AsyncClient::new().execute(&request));
+        assert!(future::block_on(future::poll_once(response.as_mut())).is_none());
+        let (mut sock, _) = listener.accept().unwrap();
+        drop(response);
+        // The connection is closed, the server never answered
+        let mut request = Vec::new();
+        sock.read_to_end(&mut request).unwrap();
+    }
+}
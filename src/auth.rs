@@ -0,0 +1,523 @@
+//! HTTP Authentication.
+//!
+//! `Basic` and `Bearer` credentials are sent with the request, `Digest`
+//! credentials ([rfc7616](https://tools.ietf.org/html/rfc7616)) are sent
+//! in response to the challenge of the server, the
+//! [Client](../client/struct.Client.html) performs the round trip.
+//!
+//! # Example
+//! ```
+//! use cabot::auth::DigestChallenge;
+//!
+//! let challenge = DigestChallenge::from_header(
+//!     "Digest realm=\"http-auth@example.org\", qop=\"auth\", \
+//!      nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\"")
+//!     .unwrap();
+//! assert_eq!(challenge.realm(), "http-auth@example.org");
+//! assert_eq!(challenge.algorithm(), "MD5");
+//! ```
+
+use base64;
+use md5;
+use rand;
+use sha2::{Sha256, Sha512Trunc256, Digest};
+
+use super::request::Request;
+use super::response::Response;
+use super::results::{CabotResult, CabotError};
+
+
+/// Credentials used to authenticate a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Authentication {
+    /// Basic authentication with a user and a password.
+    Basic(String, String),
+    /// Bearer token, such as an OAuth 2.0 access token.
+    Bearer(String),
+    /// Digest authentication with a user and a password.
+    Digest(String, String),
+}
+
+impl Authentication {
+    /// The `Authorization` header value to send with the request.
+    ///
+    /// Digest authentication requires a server challenge, in that case
+    /// `None` is returned.
+    pub fn authorization(&self) -> Option<String> {
+        match *self {
+            Authentication::Basic(ref user, ref password) => {
+                let credentials = format!("{}:{}", user, password);
+                Some(format!("Basic {}", base64::encode(credentials.as_bytes())))
+            }
+            Authentication::Bearer(ref token) => Some(format!("Bearer {}", token)),
+            Authentication::Digest(_, _) => None,
+        }
+    }
+}
+
+
+/// A Digest challenge sent by the server in a `WWW-Authenticate` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: String,
+    qop: Vec<String>,
+    userhash: bool,
+    stale: bool,
+}
+
+
+impl DigestChallenge {
+    /// Parse the Digest challenge of a `WWW-Authenticate` header value,
+    /// which may list many challenges.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::AuthenticationError in case the header has no
+    ///    Digest challenge or miss the realm or the nonce.
+    pub fn from_header(header: &str) -> CabotResult<Self> {
+        let header = header.trim();
+        let params = match challenge_params(header, "digest") {
+            Some(params) => params,
+            None => {
+                return Err(CabotError::AuthenticationError(format!("Not a Digest challenge: {}",
+                                                                   header)))
+            }
+        };
+        let mut realm = None;
+        let mut nonce = None;
+        let mut opaque = None;
+        let mut algorithm = "MD5".to_owned();
+        let mut qop = Vec::new();
+        let mut userhash = false;
+        let mut stale = false;
+        for (key, value) in parse_auth_params(params) {
+            match key.to_lowercase().as_str() {
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                "opaque" => opaque = Some(value),
+                "algorithm" => algorithm = value,
+                "qop" => {
+                    qop = value.split(',')
+                        .map(|s| s.trim().to_owned())
+                        .filter(|s| !s.is_empty())
+                        .collect()
+                }
+                "userhash" => userhash = value.eq_ignore_ascii_case("true"),
+                "stale" => stale = value.eq_ignore_ascii_case("true"),
+                _ => debug!("Ignoring Digest parameter {}", key),
+            }
+        }
+        if realm.is_none() || nonce.is_none() {
+            return Err(CabotError::AuthenticationError(format!("Incomplete Digest challenge: \
+                                                                {}",
+                                                               header)));
+        }
+        Ok(DigestChallenge {
+            realm: realm.unwrap(),
+            nonce: nonce.unwrap(),
+            opaque: opaque,
+            algorithm: algorithm,
+            qop: qop,
+            userhash: userhash,
+            stale: stale,
+        })
+    }
+
+    /// The protection space of the challenge.
+    pub fn realm(&self) -> &str {
+        self.realm.as_str()
+    }
+
+    /// The nonce generated by the server.
+    pub fn nonce(&self) -> &str {
+        self.nonce.as_str()
+    }
+
+    /// The hash algorithm, `MD5` if not specified by the server.
+    pub fn algorithm(&self) -> &str {
+        self.algorithm.as_str()
+    }
+
+    /// True if the previous request has been rejected due to a stale nonce.
+    pub fn stale(&self) -> bool {
+        self.stale
+    }
+
+    fn hash(&self, data: &str) -> CabotResult<String> {
+        self.hash_bytes(data.as_bytes())
+    }
+
+    fn hash_bytes(&self, data: &[u8]) -> CabotResult<String> {
+        let algorithm = self.algorithm.to_uppercase();
+        match algorithm.trim_right_matches("-SESS") {
+            "MD5" => Ok(format!("{:x}", md5::compute(data))),
+            "SHA-256" => {
+                let mut hasher = Sha256::default();
+                hasher.input(data);
+                Ok(format!("{:x}", hasher.result()))
+            }
+            "SHA-512-256" => {
+                let mut hasher = Sha512Trunc256::default();
+                hasher.input(data);
+                Ok(format!("{:x}", hasher.result()))
+            }
+            _ => {
+                Err(CabotError::AuthenticationError(format!("Unsupported Digest algorithm: {}",
+                                                            self.algorithm)))
+            }
+        }
+    }
+
+    /// Compute the `Authorization` header value answering the challenge.
+    ///
+    /// `nc` is the number of request sent with this nonce, starting at 1,
+    /// and `cnonce` is the client nonce.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::AuthenticationError in case the algorithm or the
+    ///    quality of protection is not supported.
+    pub fn authorization(&self,
+                         user: &str,
+                         password: &str,
+                         http_method: &str,
+                         request_uri: &str,
+                         body: Option<&[u8]>,
+                         nc: u32,
+                         cnonce: &str)
+                         -> CabotResult<String> {
+        let qop = if self.qop.is_empty() {
+            None
+        } else if self.qop.iter().any(|q| q == "auth") {
+            Some("auth")
+        } else if self.qop.iter().any(|q| q == "auth-int") {
+            Some("auth-int")
+        } else {
+            return Err(CabotError::AuthenticationError(format!("Unsupported Digest qop: {:?}",
+                                                               self.qop)));
+        };
+        let nc = format!("{:08x}", nc);
+
+        let mut ha1 = self.hash(&format!("{}:{}:{}", user, self.realm, password))?;
+        if self.algorithm.to_uppercase().ends_with("-SESS") {
+            ha1 = self.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce))?;
+        }
+        let ha2 = if qop == Some("auth-int") {
+            let body_hash = self.hash_bytes(body.unwrap_or(&[]))?;
+            self.hash(&format!("{}:{}:{}", http_method, request_uri, body_hash))?
+        } else {
+            self.hash(&format!("{}:{}", http_method, request_uri))?
+        };
+        let response = match qop {
+            Some(qop) => {
+                self.hash(&format!("{}:{}:{}:{}:{}:{}", ha1, self.nonce, nc, cnonce, qop, ha2))?
+            }
+            None => self.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2))?,
+        };
+
+        let username = if self.userhash {
+            self.hash(&format!("{}:{}", user, self.realm))?
+        } else {
+            user.to_owned()
+        };
+
+        let mut header = format!("Digest username=\"{}\", realm=\"{}\", uri=\"{}\", \
+                                  algorithm={}, nonce=\"{}\"",
+                                 quote(&username),
+                                 quote(&self.realm),
+                                 quote(request_uri),
+                                 self.algorithm,
+                                 quote(&self.nonce));
+        if let Some(qop) = qop {
+            header.push_str(&format!(", nc={}, cnonce=\"{}\", qop={}", nc, cnonce, qop));
+        }
+        header.push_str(&format!(", response=\"{}\"", response));
+        if let Some(ref opaque) = self.opaque {
+            header.push_str(&format!(", opaque=\"{}\"", quote(opaque)));
+        }
+        if self.userhash {
+            header.push_str(", userhash=true");
+        }
+        Ok(header)
+    }
+}
+
+
+/// Build the request answering the Digest challenge of the response.
+///
+/// Return `None` if the request does not use Digest authentication or if
+/// the response is not a Digest challenge.
+///
+/// Errors:
+///
+///  - CabotError::AuthenticationError in case the body stream of the
+///    request has already been sent, the request can't be sent again.
+pub fn digest_request(request: &Request, response: &Response) -> CabotResult<Option<Request>> {
+    if response.status_code() != 401 {
+        return Ok(None);
+    }
+    let (user, password) = match request.authentication() {
+        Some(&Authentication::Digest(ref user, ref password)) => (user, password),
+        _ => return Ok(None),
+    };
+    let challenge = response.header_values("WWW-Authenticate")
+        .into_iter()
+        .filter_map(|value| DigestChallenge::from_header(value).ok())
+        .next();
+    let challenge = match challenge {
+        Some(challenge) => challenge,
+        None => return Ok(None),
+    };
+    if request.is_body_stream_sent() {
+        return Err(CabotError::AuthenticationError("Cannot answer the Digest challenge, the \
+                                                    body stream has already been sent"
+            .to_owned()));
+    }
+    info!("Answering Digest challenge for realm {}", challenge.realm());
+    let cnonce = format!("{:016x}", rand::random::<u64>());
+    let authorization = challenge.authorization(user,
+                                   password,
+                                   request.http_method(),
+                                   request.request_uri(),
+                                   request.body(),
+                                   1,
+                                   &cnonce)?;
+    let mut request = request.clone();
+    request.add_header(&format!("Authorization: {}", authorization));
+    Ok(Some(request))
+}
+
+
+/// The parameters of the challenge of the scheme in a header value listing
+/// challenges separated by commas, such as `Basic realm="x", Digest ...`.
+fn challenge_params<'a>(header: &'a str, scheme: &str) -> Option<&'a str> {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = Some(0);
+    for (pos, c) in header.char_indices() {
+        if let Some(first) = start.take() {
+            let candidate = header[first..].trim_left();
+            let token_len = candidate.find(|c: char| c.is_whitespace() || c == '=' || c == ',')
+                .unwrap_or(candidate.len());
+            let rest = &candidate[token_len..];
+            if candidate[..token_len].eq_ignore_ascii_case(scheme) &&
+               !rest.trim_left().starts_with('=') {
+                return Some(rest);
+            }
+        }
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && c == ',' {
+            start = Some(pos + 1);
+        }
+    }
+    None
+}
+
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+/// Parse a comma separated list of `key=value` or `key="quoted value"`.
+fn parse_auth_params(params: &str) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace() || *c == ',') {
+            chars.next();
+        }
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' || c.is_whitespace() {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            break;
+        }
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek() != Some(&'=') {
+            // Another authentication scheme starts here
+            break;
+        }
+        chars.next();
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(c) = chars.next() {
+                            value.push(c);
+                        }
+                    }
+                    '"' => break,
+                    _ => value.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c.is_whitespace() {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+        }
+        result.push((key, value));
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHALLENGE: &'static str = "Digest realm=\"http-auth@example.org\", qop=\"auth, \
+                                     auth-int\", algorithm=MD5, \
+                                     nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+                                     opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"";
+
+    #[test]
+    fn test_basic_authorization() {
+        let auth = Authentication::Basic("Aladdin".to_owned(), "open sesame".to_owned());
+        assert_eq!(auth.authorization(),
+                   Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==".to_owned()));
+    }
+
+    #[test]
+    fn test_bearer_authorization() {
+        let auth = Authentication::Bearer("mF_9.B5f-4.1JqM".to_owned());
+        assert_eq!(auth.authorization(), Some("Bearer mF_9.B5f-4.1JqM".to_owned()));
+    }
+
+    #[test]
+    fn test_digest_authorization_requires_challenge() {
+        let auth = Authentication::Digest("Mufasa".to_owned(), "Circle of Life".to_owned());
+        assert_eq!(auth.authorization(), None);
+    }
+
+    #[test]
+    fn test_parse_digest_challenge() {
+        let challenge = DigestChallenge::from_header(CHALLENGE).unwrap();
+        assert_eq!(challenge.realm(), "http-auth@example.org");
+        assert_eq!(challenge.nonce(), "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v");
+        assert_eq!(challenge.algorithm(), "MD5");
+        assert_eq!(challenge.qop, vec!["auth".to_owned(), "auth-int".to_owned()]);
+        assert_eq!(challenge.opaque,
+                   Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS".to_owned()));
+        assert!(!challenge.stale());
+    }
+
+    #[test]
+    fn test_parse_not_digest_challenge() {
+        let challenge = DigestChallenge::from_header("Basic realm=\"simple\"");
+        assert!(challenge.is_err());
+        let challenge = DigestChallenge::from_header("Digest realm=\"no nonce\"");
+        assert!(challenge.is_err());
+    }
+
+    #[test]
+    fn test_digest_md5_rfc7616() {
+        let challenge = DigestChallenge::from_header(CHALLENGE).unwrap();
+        let header = challenge.authorization("Mufasa",
+                           "Circle of Life",
+                           "GET",
+                           "/dir/index.html",
+                           None,
+                           1,
+                           "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ")
+            .unwrap();
+        assert_eq!(header,
+                   "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", \
+                    uri=\"/dir/index.html\", algorithm=MD5, \
+                    nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", nc=00000001, \
+                    cnonce=\"f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ\", qop=auth, \
+                    response=\"8ca523f5e9506fed4657c9700eebdbec\", \
+                    opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"");
+    }
+
+    #[test]
+    fn test_digest_sha256_rfc7616() {
+        let challenge = CHALLENGE.replace("algorithm=MD5", "algorithm=SHA-256");
+        let challenge = DigestChallenge::from_header(&challenge).unwrap();
+        let header = challenge.authorization("Mufasa",
+                           "Circle of Life",
+                           "GET",
+                           "/dir/index.html",
+                           None,
+                           1,
+                           "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ")
+            .unwrap();
+        assert!(header.contains("response=\"753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf\
+                                 8db5856cb6c1\""));
+    }
+
+    #[test]
+    fn test_parse_many_challenges() {
+        let header = format!("Basic realm=\"simple, or not\", {}", CHALLENGE);
+        let challenge = DigestChallenge::from_header(&header).unwrap();
+        assert_eq!(challenge.realm(), "http-auth@example.org");
+        assert_eq!(challenge.opaque,
+                   Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS".to_owned()));
+        let header = format!("{}, Basic realm=\"simple\"", CHALLENGE);
+        let challenge = DigestChallenge::from_header(&header).unwrap();
+        assert_eq!(challenge.opaque,
+                   Some("FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS".to_owned()));
+        let challenge = DigestChallenge::from_header("Basic realm=\"Digest realm\"");
+        assert!(challenge.is_err());
+    }
+
+    // The example of rfc7616 section 3.9.2, the username and the response
+    // printed in the rfc are wrong (see its errata), these are recomputed.
+    #[test]
+    fn test_digest_sha512_256_rfc7616() {
+        let challenge = DigestChallenge::from_header(
+            "Digest realm=\"api@example.org\", qop=\"auth\", algorithm=SHA-512-256, \
+             nonce=\"5TsQWLVdgBdmrQ0XsxbDODV+57QdFR34I9HAbC/RVvkK\", \
+             opaque=\"HRPCssKJSGjCrkzDg8OhwpzCiGPChXYjwrI2QmXDnsOS\", charset=UTF-8, \
+             userhash=true")
+            .unwrap();
+        let header = challenge.authorization("J\u{e4}s\u{f8}n Doe",
+                           "Secret, or not?",
+                           "GET",
+                           "/doe.json",
+                           None,
+                           1,
+                           "NTg6RKcb9boFIAS3KrFK9BGeh+iDa/sm6jUMp2wds69v")
+            .unwrap();
+        assert_eq!(header,
+                   "Digest username=\"793263caabb707a56211940d90411ea4a575adeccb7e360aeb624ed06\
+                    ece9b0b\", realm=\"api@example.org\", uri=\"/doe.json\", \
+                    algorithm=SHA-512-256, nonce=\"5TsQWLVdgBdmrQ0XsxbDODV+57QdFR34I9HAbC/RVvkK\", \
+                    nc=00000001, cnonce=\"NTg6RKcb9boFIAS3KrFK9BGeh+iDa/sm6jUMp2wds69v\", \
+                    qop=auth, \
+                    response=\"3798d4131c277846293534c3edc11bd8a5e4cdcbff78b05db9d95eeb1cec68a5\", \
+                    opaque=\"HRPCssKJSGjCrkzDg8OhwpzCiGPChXYjwrI2QmXDnsOS\", userhash=true");
+    }
+
+    #[test]
+    fn test_digest_unsupported_algorithm() {
+        let challenge = CHALLENGE.replace("algorithm=MD5", "algorithm=SHA3-256");
+        let challenge = DigestChallenge::from_header(&challenge).unwrap();
+        let header = challenge.authorization("Mufasa", "", "GET", "/", None, 1, "cnonce");
+        assert!(header.is_err());
+    }
+}
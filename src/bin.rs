@@ -4,36 +4,102 @@ extern crate log;
 extern crate pretty_env_logger;
 
 extern crate clap;
+extern crate url;
 extern crate cabot;
 
-use std::fmt::Arguments;
-use std::fs::OpenOptions;
-use std::io::{self, Write, stderr};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Write, stderr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use log::LogLevel::Info;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use url::form_urlencoded;
 
 use cabot::results::{CabotResult, CabotError};
-use cabot::http;
-use cabot::request::RequestBuilder;
+use cabot::netrc::Netrc;
+use cabot::progress::{Monitor, Progress};
+use cabot::timings::Timings;
+use cabot::trace::{TraceEvent, Tracer};
+#[cfg(unix)]
+use cabot::transport::UnixConnector;
+use cabot::transport::{Connector, TcpConnector};
+use cabot::download::{self, Downloader};
+use cabot::multipart::{Multipart, Part};
+use cabot::request::{Request, RequestBuilder};
+use cabot::response::Response;
+use cabot::client::Client;
+use cabot::sse::EventSource;
+use cabot::websocket::{Message, WebSocket};
 use cabot::constants;
 
 
-pub fn run() -> CabotResult<()> {
-    let matches = App::new("cabot")
+/// The exit codes of the command, the codes of curl for the same errors.
+const EXIT_CODES: &'static str = "EXIT CODES:
+    1     Other errors, such as an unsupported scheme or a WebSocket error
+    3     Malformed URL
+    6     Could not resolve the host
+    7     Failed to connect to the host
+    8     Invalid HTTP response
+    22    HTTP error status, with -f/--fail or --fail-with-body
+    23    Error writing the output
+    26    Error reading the netrc file
+    28    Operation timeout
+    33    Range error, resuming a download
+    35    TLS handshake error
+    56    Failure sending or receiving data
+    61    Error decoding the response body
+    94    Authentication error";
+
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("cabot")
         .version(constants::VERSION)
         .author("Guillaume Gauvrit <guillaume@gauvr.it>")
         .about("http(s) client")
+        .after_help(EXIT_CODES)
         .arg(Arg::with_name("URL")
             .index(1)
             .required(true)
-            .help("URL to request"))
+            .help("URL to request, ws:// and wss:// URLs open a WebSocket connection sending \
+                   the lines of the standard input"))
         .arg(Arg::with_name("REQUEST")
             .short("X")
             .long("request")
             .default_value("GET")
             .help("Specify request command to use"))
+        .arg(Arg::with_name("HEAD")
+            .short("I")
+            .long("head")
+            .conflicts_with_all(&["CONTINUE_AT", "PARALLEL_SEGMENTS", "SSE"])
+            .help("Send a HEAD request and show the response headers only"))
+        .arg(Arg::with_name("INCLUDE")
+            .short("i")
+            .long("include")
+            .conflicts_with_all(&["PARALLEL_SEGMENTS", "SSE"])
+            .help("Include the response headers in the output"))
+        .arg(Arg::with_name("DUMP_HEADER")
+            .short("D")
+            .long("dump-header")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with_all(&["PARALLEL_SEGMENTS", "SSE"])
+            .help("Write the response headers to FILE, - for stdout"))
+        .arg(Arg::with_name("TRACE")
+            .long("trace")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Write a hex dump of the data sent and received to FILE, - for stdout"))
+        .arg(Arg::with_name("TRACE_ASCII")
+            .long("trace-ascii")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with("TRACE")
+            .help("Like --trace, without the hex output"))
         .arg(Arg::with_name("HEADER")
             .short("H")
             .long("header")
@@ -45,6 +111,72 @@ pub fn run() -> CabotResult<()> {
             .long("output")
             .takes_value(true)
             .help("Write to FILE instead of stdout"))
+        .arg(Arg::with_name("CONTINUE_AT")
+            .short("C")
+            .long("continue-at")
+            .takes_value(true)
+            .requires("FILE")
+            .conflicts_with("COMPRESSED")
+            .validator(|offset| if offset == "-" || offset.parse::<u64>().is_ok() {
+                Ok(())
+            } else {
+                Err("Offset must be a number of bytes or -".to_owned())
+            })
+            .help("Resumed transfer offset, - to continue the download of the output file"))
+        .arg(Arg::with_name("PARALLEL_SEGMENTS")
+            .long("parallel-segments")
+            .takes_value(true)
+            .requires("FILE")
+            .conflicts_with("CONTINUE_AT")
+            .validator(|segments| match segments.parse::<u64>() {
+                Ok(segments) if segments > 0 && segments <= download::MAX_SEGMENTS => Ok(()),
+                _ => Err(format!("Segments must be a number from 1 to {}", download::MAX_SEGMENTS)),
+            })
+            .help("Download the output file using N connections in parallel"))
+        .arg(Arg::with_name("PROGRESS_BAR")
+            .short("#")
+            .long("progress-bar")
+            .help("Display transfer progress as a bar"))
+        .arg(Arg::with_name("SILENT")
+            .short("s")
+            .long("silent")
+            .help("Silent mode, don't display the progress meter and the errors"))
+        .arg(Arg::with_name("SHOW_ERROR")
+            .short("S")
+            .long("show-error")
+            .help("Display the errors, even in silent mode"))
+        .arg(Arg::with_name("FAIL")
+            .short("f")
+            .long("fail")
+            .help("Fail with exit code 22 on HTTP error status, without writing the body"))
+        .arg(Arg::with_name("FAIL_WITH_BODY")
+            .long("fail-with-body")
+            .conflicts_with("FAIL")
+            .help("Fail with exit code 22 on HTTP error status, after writing the body"))
+        .arg(Arg::with_name("LIMIT_RATE")
+            .long("limit-rate")
+            .takes_value(true)
+            .validator(|rate| parse_rate(rate.as_str()).map(|_| ()))
+            .help("Limit transfer speed to RATE bytes per second, with k, m or g suffix"))
+        .arg(Arg::with_name("SPEED_LIMIT")
+            .short("Y")
+            .long("speed-limit")
+            .takes_value(true)
+            .validator(|rate| parse_rate(rate.as_str()).map(|_| ()))
+            .help("Stop transfers slower than this (bytes per second)"))
+        .arg(Arg::with_name("SPEED_TIME")
+            .short("y")
+            .long("speed-time")
+            .takes_value(true)
+            .validator(|time| time.parse::<u64>().map(|_| ()).map_err(|err| format!("{}", err)))
+            .help("Trigger 'speed-limit' abort after this time (seconds), default is 30"))
+        .arg(Arg::with_name("WRITE_OUT")
+            .short("w")
+            .long("write-out")
+            .takes_value(true)
+            .conflicts_with("PARALLEL_SEGMENTS")
+            .help("Use output FORMAT after completion, such as %{http_code} or \
+                   %{time_total}, @file to read the format from a file"))
         .arg(Arg::with_name("VERBOSE")
             .short("v")
             .long("verbose")
@@ -53,164 +185,1128 @@ pub fn run() -> CabotResult<()> {
             .short("d")
             .long("data")
             .takes_value(true)
-            .help("Post Data (Using utf-8 encoding)"))
+            .multiple(true)
+            .number_of_values(1)
+            .help("Post Data (Using utf-8 encoding), many data are joined with &"))
+        .arg(Arg::with_name("DATA_URLENCODE")
+            .long("data-urlencode")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Post Data url encoded (content, =content, name=content, @file or \
+                   name@file)"))
+        .arg(Arg::with_name("FORM")
+            .short("F")
+            .long("form")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with_all(&["BODY", "DATA_URLENCODE", "GET"])
+            .validator(|form| if form.contains('=') {
+                Ok(())
+            } else {
+                Err("Form must be name=content".to_owned())
+            })
+            .help("Specify multipart form data (name=value, name=@file or name=<file, \
+                   with ;type= and ;filename=)"))
+        .arg(Arg::with_name("GET")
+            .short("G")
+            .long("get")
+            .help("Put the post data in the URL and use GET"))
         .arg(Arg::with_name("UA")
             .short("A")
             .long("user-agent")
             .default_value(constants::USER_AGENT)
             .help("Post Data (Using utf-8 encoding)"))
-        .get_matches();
+        .arg(Arg::with_name("USER")
+            .short("u")
+            .long("user")
+            .takes_value(true)
+            .help("Server user and password (user:password)"))
+        .arg(Arg::with_name("DIGEST")
+            .long("digest")
+            .requires("USER")
+            .help("Use HTTP Digest Authentication"))
+        .arg(Arg::with_name("BEARER")
+            .long("oauth2-bearer")
+            .takes_value(true)
+            .conflicts_with("USER")
+            .help("OAuth 2 Bearer Token"))
+        .arg(Arg::with_name("NETRC")
+            .short("n")
+            .long("netrc")
+            .help("Must read .netrc for user name and password"))
+        .arg(Arg::with_name("NETRC_FILE")
+            .long("netrc-file")
+            .takes_value(true)
+            .help("Specify FILE for netrc"))
+        .arg(Arg::with_name("NETRC_OPTIONAL")
+            .long("netrc-optional")
+            .help("Use either .netrc or URL"))
+        .arg(Arg::with_name("JSON")
+            .long("json")
+            .takes_value(true)
+            .conflicts_with_all(&["BODY", "DATA_URLENCODE", "FORM"])
+            .help("Post JSON Data, with JSON Content-Type and Accept headers"))
+        .arg(Arg::with_name("COMPRESSED")
+            .long("compressed")
+            .help("Request compressed response"))
+        .arg(Arg::with_name("UNIX_SOCKET")
+            .long("unix-socket")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("Connect through this Unix domain socket"))
+        .arg(Arg::with_name("ABSTRACT_UNIX_SOCKET")
+            .long("abstract-unix-socket")
+            .value_name("PATH")
+            .takes_value(true)
+            .conflicts_with("UNIX_SOCKET")
+            .help("Connect via abstract Unix domain socket"))
+        .arg(Arg::with_name("SSE")
+            .long("sse")
+            .conflicts_with_all(&["FILE", "WRITE_OUT", "COMPRESSED"])
+            .help("Print the data of the Server-Sent Events as they arrive, reconnecting when \
+                   the connection is lost"))
+}
 
-    let url = matches.value_of("URL").unwrap();
-    let http_method = matches.value_of("REQUEST").unwrap();
+
+pub fn run(matches: &ArgMatches) -> CabotResult<()> {
+    let mut url = matches.value_of("URL").unwrap().to_owned();
+    let head = matches.is_present("HEAD");
+    let include = head || matches.is_present("INCLUDE");
+    let dump_header = matches.value_of("DUMP_HEADER");
+    let fail = matches.is_present("FAIL");
+    let http_method = if head { "HEAD" } else { matches.value_of("REQUEST").unwrap() };
     let verbose = matches.is_present("VERBOSE");
-    let body = matches.value_of("BODY");
     let ua = matches.value_of("UA").unwrap();
+    let compressed = matches.is_present("COMPRESSED");
+
+    let mut monitor = Monitor::new();
+    if !matches.is_present("SILENT") {
+        if matches.is_present("PROGRESS_BAR") {
+            let meter = ProgressMeter::new(true);
+            monitor = monitor.set_callback(move |progress| meter.update(progress));
+        } else if matches.is_present("FILE") {
+            let meter = ProgressMeter::new(false);
+            monitor = monitor.set_callback(move |progress| meter.update(progress));
+        }
+    }
+    if let Some(rate) = matches.value_of("LIMIT_RATE") {
+        monitor = monitor.set_limit_rate(parse_rate(rate).unwrap());
+    }
+    if matches.is_present("SPEED_LIMIT") || matches.is_present("SPEED_TIME") {
+        let limit = matches.value_of("SPEED_LIMIT").map(|rate| parse_rate(rate).unwrap());
+        let time = matches.value_of("SPEED_TIME").map(|time| time.parse().unwrap());
+        monitor = monitor.set_speed_limit(limit.unwrap_or(1),
+                                          Duration::from_secs(time.unwrap_or(30)));
+    }
 
     let headers: Vec<&str> = match matches.values_of("HEADER") {
         Some(headers) => headers.collect(),
         None => Vec::new(),
     };
 
-    let mut builder = RequestBuilder::new(url)
+    let mut data: Vec<String> = match matches.values_of("BODY") {
+        Some(values) => values.map(|value| value.to_owned()).collect(),
+        None => Vec::new(),
+    };
+    if let Some(values) = matches.values_of("DATA_URLENCODE") {
+        for value in values {
+            data.push(urlencode_data(value)?);
+        }
+    }
+    let mut body = if data.is_empty() {
+        None
+    } else {
+        Some(data.join("&"))
+    };
+
+    if matches.is_present("GET") {
+        if let Some(query) = body.take() {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(query.as_str());
+        }
+    }
+
+    let mut builder = RequestBuilder::new(url.as_str())
         .set_http_method(http_method)
         .set_user_agent(ua)
         .add_headers(&headers.as_slice());
 
-    if body.is_some() {
-        builder = builder.set_body_as_str(body.unwrap());
+    let has_header = |name: &str| {
+        headers.iter().any(|header| {
+            header.splitn(2, ':').next().unwrap_or("").trim().eq_ignore_ascii_case(name)
+        })
+    };
+
+    if let Some(body) = body {
+        if !has_header("Content-Type") {
+            builder = builder.add_header("Content-Type: application/x-www-form-urlencoded");
+        }
+        builder = builder.set_body_as_str(body.as_str());
     }
 
-    let request = builder.build()?;
+    if let Some(forms) = matches.values_of("FORM") {
+        let mut multipart = Multipart::new();
+        for form in forms {
+            multipart = multipart.add_part(form_part(form)?);
+        }
+        builder = builder.multipart(multipart);
+    }
 
-    if let Some(path) = matches.value_of("FILE") {
-        let mut f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)
-            .unwrap();
-        http::http_query(&request, &mut CabotBinWrite::new(&mut f, verbose), verbose)?;
+    if let Some(json) = matches.value_of("JSON") {
+        if !has_header("Content-Type") {
+            builder = builder.add_header("Content-Type: application/json");
+        }
+        if !has_header("Accept") {
+            builder = builder.add_header("Accept: application/json");
+        }
+        builder = builder.set_body_as_str(json);
+    }
+
+    if compressed {
+        builder = builder.add_header(&format!("Accept-Encoding: {}", constants::ACCEPT_ENCODING));
+    }
+
+    if let Some(user) = matches.value_of("USER") {
+        let mut credentials = user.splitn(2, ':');
+        let user = credentials.next().unwrap();
+        let password = credentials.next().unwrap_or("");
+        builder = if matches.is_present("DIGEST") {
+            builder.digest_auth(user, password)
+        } else {
+            builder.basic_auth(user, password)
+        };
+    }
+
+    if let Some(token) = matches.value_of("BEARER") {
+        builder = builder.bearer_auth(token);
+    }
+
+    let continue_at = match matches.value_of("CONTINUE_AT") {
+        Some("-") => {
+            let path = matches.value_of("FILE").unwrap();
+            Some(fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0))
+        }
+        Some(offset) => Some(offset.parse().unwrap()),
+        None => None,
+    };
+    if let Some(offset) = continue_at {
+        if offset > 0 {
+            builder = builder.range(offset, None);
+            // The part is sent only if the content has not changed
+            if let Some(etag) = read_etag(matches.value_of("FILE").unwrap()) {
+                builder = builder.add_header(&format!("If-Range: {}", etag));
+            }
+        }
+    }
+
+    let mut request = builder.build()?;
+
+    if matches.is_present("NETRC") || matches.is_present("NETRC_FILE") ||
+       matches.is_present("NETRC_OPTIONAL") {
+        let path = match matches.value_of("NETRC_FILE") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Netrc::default_path(),
+        };
+        let netrc = match path {
+            Some(ref path) if matches.is_present("NETRC_OPTIONAL") && !path.exists() => None,
+            Some(path) => Some(Netrc::from_file(path)?),
+            None => None,
+        };
+        if let Some(netrc) = netrc {
+            request = netrc.authenticate(&request);
+        }
+    }
+
+    let write_out = matches.value_of("WRITE_OUT");
+    let connector = connector(matches.value_of("UNIX_SOCKET"),
+                              matches.value_of("ABSTRACT_UNIX_SOCKET"))?;
+    let tracer = match (matches.value_of("TRACE"), matches.value_of("TRACE_ASCII")) {
+        (Some(path), _) => Some(tracer(path, false)?),
+        (None, Some(path)) => Some(tracer(path, true)?),
+        (None, None) => None,
+    };
+    let tracer = tracer.as_ref();
+    let mut response = None;
+    let segments = matches.value_of("PARALLEL_SEGMENTS").map(|segments| segments.parse().unwrap());
+    if request.scheme() == "ws" || request.scheme() == "wss" {
+        websocket(&request, verbose, &connector, tracer)?;
+    } else if matches.is_present("SSE") {
+        event_stream(&request, verbose, &connector, tracer)?;
+    } else if let (Some(path), Some(segments)) = (matches.value_of("FILE"), segments) {
+        Downloader::new()
+            .set_segments(segments)
+            .set_verbose(verbose)
+            .set_connector(connector)
+            .download(&request, path)?;
+    } else if let (Some(path), Some(offset)) = (matches.value_of("FILE"), continue_at) {
+        response = Some(resume(&request,
+                               path,
+                               offset,
+                               dump_header,
+                               include,
+                               fail,
+                               verbose,
+                               &monitor,
+                               &connector,
+                               tracer)?);
     } else {
-        http::http_query(&request,
-                         &mut CabotBinWrite::new(&mut io::stdout(), verbose),
-                         verbose)?;
+        let queried = query(&request,
+                            dump_header,
+                            fail,
+                            verbose,
+                            compressed,
+                            &monitor,
+                            &connector,
+                            tracer)?;
+        if let Some(path) = matches.value_of("FILE") {
+            // The file is kept if the response is an error and --fail is used
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .map_err(|err| CabotError::WriteError(format!("{}: {}", path, err)))?;
+            write_response(&queried, &mut f, include, verbose)?;
+        } else {
+            write_response(&queried, &mut io::stdout(), include, verbose)?;
+        }
+        response = Some(queried);
     };
 
+    if let (Some(format), Some(response)) = (write_out, response.as_ref()) {
+        let format = if format.starts_with('@') {
+            let path = &format[1..];
+            let mut format = String::new();
+            let read = if path == "-" {
+                io::stdin().read_to_string(&mut format)
+            } else {
+                File::open(path).and_then(|mut file| file.read_to_string(&mut format))
+            };
+            read.map_err(|err| CabotError::IOError(format!("{}: {}", path, err)))?;
+            format
+        } else {
+            format.to_owned()
+        };
+        let output = write_out_format(format.as_str(), &request, response);
+        io::stdout()
+            .write_all(output.as_bytes())
+            .map_err(|err| CabotError::WriteError(format!("{}", err)))?;
+    }
+
+    if let Some(response) = response {
+        if matches.is_present("FAIL_WITH_BODY") && response.status_code() >= 400 {
+            return Err(status_error(&response));
+        }
+    }
+
     Ok(())
 }
 
 
+/// Encode a `--data-urlencode` value like curl does.
+fn urlencode_data(data: &str) -> CabotResult<String> {
+    let (name, content) = if let Some(pos) = data.find('=') {
+        (&data[..pos], data[pos + 1..].to_owned())
+    } else if let Some(pos) = data.find('@') {
+        let path = &data[pos + 1..];
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|err| CabotError::IOError(format!("{}: {}", path, err)))?;
+        (&data[..pos], content)
+    } else {
+        ("", data.to_owned())
+    };
+    let content: String = form_urlencoded::byte_serialize(content.as_bytes()).collect();
+    if name.is_empty() {
+        Ok(content)
+    } else {
+        Ok(format!("{}={}", name, content))
+    }
+}
+
 
-fn main() {
-    pretty_env_logger::init().unwrap();
-    debug!("Starting cabot");
-    match run() {
-        Ok(()) => {
-            debug!("Command cabot ended succesfully");
+/// Build a multipart part from a `-F/--form` value like curl does.
+fn form_part(form: &str) -> CabotResult<Part> {
+    let mut split = form.splitn(2, '=');
+    let name = split.next().unwrap();
+    let mut params = split.next().unwrap_or("").split(';');
+    let content = params.next().unwrap();
+    let mut part = if content.starts_with('@') {
+        Part::file(name, &content[1..])?
+    } else if content.starts_with('<') {
+        let path = &content[1..];
+        let mut content = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut content))
+            .map_err(|err| CabotError::IOError(format!("{}: {}", path, err)))?;
+        Part::bytes(name, content)
+    } else {
+        Part::text(name, content)
+    };
+    for param in params {
+        let mut split = param.splitn(2, '=');
+        match (split.next().unwrap().trim(), split.next()) {
+            ("type", Some(content_type)) => part = part.set_content_type(content_type),
+            ("filename", Some(filename)) => part = part.set_filename(filename),
+            _ => debug!("Ignoring form parameter {}", param),
         }
-        Err(CabotError::SchemeError(scheme)) => {
-            let _ = writeln!(&mut std::io::stderr(), "Unamanaged scheme: {}", scheme);
-            std::process::exit(1);
+    }
+    Ok(part)
+}
+
+
+/// The file storing the `ETag` of a resumable download.
+fn etag_path(path: &str) -> String {
+    format!("{}.etag", path)
+}
+
+
+/// Read the `ETag` saved by a previous download, weak validators can't be
+/// used to resume a download.
+fn read_etag(path: &str) -> Option<String> {
+    let mut etag = String::new();
+    match File::open(etag_path(path)).and_then(|mut file| file.read_to_string(&mut etag)) {
+        Ok(_) if !etag.trim().is_empty() && !etag.starts_with("W/") => {
+            Some(etag.trim().to_owned())
         }
-        Err(CabotError::OpaqueUrlError(err)) => {
-            let _ = writeln!(&mut std::io::stderr(), "Opaque URL Error: {}", err);
-            std::process::exit(1);
+        _ => None,
+    }
+}
+
+
+/// Download the content to the file from the offset, the file is completed
+/// on `206 Partial Content`, and rewritten if the whole content is sent.
+///
+/// The body is written to the file as it is received, so an interrupted
+/// download can be resumed again. The file is left unchanged if the
+/// response is an error. The head of the response is written before the
+/// body if `include` is true.
+fn resume(request: &Request,
+          path: &str,
+          offset: u64,
+          dump_header: Option<&str>,
+          include: bool,
+          fail: bool,
+          verbose: bool,
+          monitor: &Monitor,
+          connector: &Arc<Connector>,
+          tracer: Option<&Tracer>)
+          -> CabotResult<Response> {
+    let mut stream = client(verbose, connector, tracer)
+        .set_monitor(monitor.clone())
+        .stream(request)?;
+    let response = stream.response().clone();
+    if fail && response.status_code() >= 400 {
+        return Err(status_error(&response));
+    }
+    if let Some(dump_path) = dump_header {
+        write_dump_header(&response, dump_path)?;
+    }
+    let append = match response.status_code() {
+        206 => {
+            match response.content_range().and_then(|range| range.range()) {
+                Some((first, _)) if first == offset => true,
+                _ => {
+                    return Err(CabotError::RangeError(format!("Unexpected Content-Range: {}",
+                                                              response.header("Content-Range")
+                                                                  .unwrap_or(""))))
+                }
+            }
         }
-        Err(CabotError::UrlParseError(err)) => {
-            let _ = writeln!(&mut std::io::stderr(), "URL Parse Error: {}", err);
-            std::process::exit(1);
+        200 => {
+            if offset > 0 {
+                info!("The server sent the whole content, restarting the download");
+            }
+            false
         }
-        Err(CabotError::IOError(err)) => {
-            let _ = writeln!(&mut std::io::stderr(), "IOError: {}", err);
-            std::process::exit(1);
+        416 => {
+            let length = response.content_range().and_then(|range| range.complete_length());
+            return Err(CabotError::RangeError(match length {
+                Some(length) if length == offset => {
+                    format!("Cannot resume {} at byte {}, the download is already complete",
+                            path,
+                            offset)
+                }
+                Some(length) => {
+                    format!("Cannot resume {} at byte {}, the content length is {}",
+                            path,
+                            offset,
+                            length)
+                }
+                None => format!("Cannot resume {} at byte {}, range not satisfiable", path, offset),
+            }));
         }
-        Err(CabotError::CertificateError(err)) => {
-            let _ = writeln!(&mut std::io::stderr(), "CertificateError: {}", err);
-            std::process::exit(1);
-        }        
-        // Unexpexcted Error, not used
-        Err(CabotError::HttpResponseParseError(_)) => {
-            let _ = writeln!(&mut std::io::stderr(), "Unexpected error");
-            std::process::exit(1);
+        _ => {
+            return Err(CabotError::RangeError(format!("Cannot resume {}: {} {}",
+                                                      path,
+                                                      response.http_version(),
+                                                      response.status_line())))
         }
-        Err(CabotError::EncodingError(_)) => {
-            let _ = writeln!(&mut std::io::stderr(), "Unexpected error");
-            std::process::exit(1);
+    };
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|err| CabotError::WriteError(format!("{}: {}", path, err)))?;
+    save_etag(path, response.header("ETag"))?;
+    write_response_head(&response, verbose);
+    if include {
+        file.write_all(format_response_head(&response).as_bytes())
+            .map_err(|err| CabotError::WriteError(format!("{}: {}", path, err)))?;
+    }
+    let mut buf = [0; 8192];
+    loop {
+        let count = stream.read(&mut buf).map_err(read_error)?;
+        if count == 0 {
+            break;
         }
+        file.write_all(&buf[..count])
+            .map_err(|err| CabotError::WriteError(format!("{}: {}", path, err)))?;
     }
+    Ok(response)
 }
 
 
-// Internal Of the Binary
+/// Save the `ETag` of the download to resume it later, a previous one is
+/// removed if the response has none.
+fn save_etag(path: &str, etag: Option<&str>) -> CabotResult<()> {
+    let etag_path = etag_path(path);
+    let saved = match etag {
+        Some(etag) => File::create(&etag_path).and_then(|mut file| file.write_all(etag.as_bytes())),
+        None => {
+            match fs::remove_file(&etag_path) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                removed => removed,
+            }
+        }
+    };
+    saved.map_err(|err| CabotError::WriteError(format!("{}: {}", etag_path, err)))
+}
 
 
-struct CabotBinWrite<'a> {
-    out: &'a mut Write,
-    verbose: bool,
+/// Convert an error reading the body of a response stream.
+fn read_error(err: io::Error) -> CabotError {
+    match err.kind() {
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::WouldBlock => CabotError::TimeoutError(format!("{}", err)),
+        io::ErrorKind::InvalidData |
+        io::ErrorKind::UnexpectedEof => CabotError::HttpResponseParseError(format!("{}", err)),
+        _ => CabotError::IOError(format!("{}", err)),
+    }
 }
 
-impl<'a> CabotBinWrite<'a> {
-    pub fn new(out: &'a mut Write, verbose: bool) -> Self {
-        CabotBinWrite {
-            out: out,
-            verbose: verbose,
-        }
+
+/// Send the request, and write the head of the response to the
+/// `--dump-header` file.
+///
+/// Nothing is written if `fail` is true and the response has an error
+/// status.
+fn query(request: &Request,
+         dump_header: Option<&str>,
+         fail: bool,
+         verbose: bool,
+         compressed: bool,
+         monitor: &Monitor,
+         connector: &Arc<Connector>,
+         tracer: Option<&Tracer>)
+         -> CabotResult<Response> {
+    // The client answers the Digest challenge of the server
+    let response = client(verbose, connector, tracer)
+        .set_compressed(compressed)
+        .set_monitor(monitor.clone())
+        .set_interim_handler(move |response: &Response| write_response_head(response, verbose))
+        .execute(request)?;
+    if fail && response.status_code() >= 400 {
+        return Err(status_error(&response));
     }
+    if let Some(path) = dump_header {
+        write_dump_header(&response, path)?;
+    }
+    Ok(response)
 }
 
 
-impl<'a> Write for CabotBinWrite<'a> {
-    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+/// Send the lines of the standard input as text messages, and write the
+/// messages received, until the standard input or the connection is
+/// closed.
+fn websocket(request: &Request,
+             verbose: bool,
+             connector: &Arc<Connector>,
+             tracer: Option<&Tracer>)
+             -> CabotResult<()> {
+    let client = client(verbose, connector, tracer);
+    let mut websocket = WebSocket::connect(&client, request)?;
+    write_response_head(websocket.response(), verbose);
 
-        let response: Vec<&[u8]> = constants::SPLIT_HEADERS_RE.splitn(buf, 2).collect();
+    let (sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
 
-        // If there is headers and we logged them
-        if response.len() == 2 && (log_enabled!(Info) || self.verbose) {
-            let headers = response.get(0).unwrap();
-            let headers = String::from_utf8_lossy(headers);
-            let split: Vec<&str> = constants::SPLIT_HEADER_RE.split(&headers).collect();
-            if log_enabled!(Info) {
-                for part in split {
-                    info!("< {}", part);
+    // Stop waiting for the messages to send the lines read
+    websocket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut stdout = io::stdout();
+    let mut closing = false;
+    loop {
+        while !closing {
+            match lines.try_recv() {
+                Ok(line) => websocket.send(Message::Text(line))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // Wait for the messages in flight, then for the close of the server
+                    websocket.set_read_timeout(Some(Duration::new(5, 0)))?;
+                    websocket.send(Message::Close(Some((1000, String::new()))))?;
+                    closing = true;
                 }
-            } else if self.verbose {
-                for part in split {
-                    writeln!(&mut stderr(), "< {}", part).unwrap();
+            }
+        }
+        let written = match websocket.receive() {
+            Ok(Message::Text(text)) => writeln!(stdout, "{}", text),
+            Ok(Message::Binary(data)) => stdout.write_all(&data),
+            Ok(Message::Ping(payload)) => {
+                write_verbose(&format!("< Ping [[{} bytes]]", payload.len()), verbose);
+                Ok(())
+            }
+            Ok(Message::Pong(payload)) => {
+                write_verbose(&format!("< Pong [[{} bytes]]", payload.len()), verbose);
+                Ok(())
+            }
+            Ok(Message::Close(close)) => {
+                if let Some((code, reason)) = close {
+                    write_verbose(&format!("< Close {} {}", code, reason), verbose);
                 }
+                return Ok(());
+            }
+            Err(CabotError::TimeoutError(_)) if !closing => Ok(()),
+            Err(err) => return Err(err),
+        };
+        written.and_then(|_| stdout.flush())
+            .map_err(|err| CabotError::WriteError(format!("{}", err)))?;
+    }
+}
+
+
+/// Print the data of the events, and reconnect when the connection is
+/// lost, until the server stops the event stream.
+fn event_stream(request: &Request,
+                verbose: bool,
+                connector: &Arc<Connector>,
+                tracer: Option<&Tracer>)
+                -> CabotResult<()> {
+    let client = client(verbose, connector, tracer);
+    let mut stdout = io::stdout();
+    for event in EventSource::new(client, request) {
+        match event {
+            Ok(event) => {
+                write_verbose(&format!("< event: {}, id: {}", event.event_type(), event.id()),
+                              verbose);
+                writeln!(stdout, "{}", event.data())
+                    .and_then(|_| stdout.flush())
+                    .map_err(|err| CabotError::WriteError(format!("{}", err)))?;
             }
+            Err(CabotError::IOError(err)) |
+            Err(CabotError::ConnectError(err)) |
+            Err(CabotError::TimeoutError(err)) => {
+                let _ = writeln!(&mut stderr(), "Connection lost: {}", err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+
+/// The error of the `-f/--fail` and `--fail-with-body` options.
+fn status_error(response: &Response) -> CabotError {
+    CabotError::StatusError(format!("The requested URL returned error: {}",
+                                    response.status_line()))
+}
+
+
+/// The client of the queries, tracing the connections if `--trace` or
+/// `--trace-ascii` is used.
+fn client(verbose: bool, connector: &Arc<Connector>, tracer: Option<&Tracer>) -> Client {
+    let client = Client::new()
+        .set_verbose(verbose)
+        .set_connector(connector.clone());
+    match tracer {
+        Some(tracer) => client.set_tracer(tracer.clone()),
+        None => client,
+    }
+}
+
+
+/// A tracer writing the events to `path`, - for stdout, in the format
+/// of curl `--trace`, or `--trace-ascii` if `ascii` is true.
+fn tracer(path: &str, ascii: bool) -> CabotResult<Tracer> {
+    let out: Box<Write + Send> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path)
+            .map_err(|err| CabotError::WriteError(format!("{}: {}", path, err)))?)
+    };
+    let out = Mutex::new(out);
+    Ok(Tracer::new(move |event: &TraceEvent| {
+        let mut out = out.lock().unwrap();
+        let written = match *event {
+            TraceEvent::Info(message) => writeln!(out, "== Info: {}", message),
+            TraceEvent::Sent(data) => write_trace_data(&mut *out, "=> Send", data, ascii),
+            TraceEvent::Received(data) => write_trace_data(&mut *out, "<= Recv", data, ascii),
+        };
+        if let Err(err) = written.and_then(|_| out.flush()) {
+            error!("Cannot write the trace: {}", err);
         }
+    }))
+}
+
 
-        let body = if response.len() == 2 {
-            let start = response.get(0).unwrap().len() + 4;
-            &buf[start..]
+/// Write the data sent or received, 16 bytes per line in hexadecimal and
+/// ASCII, or up to 64 characters per line, ending at new lines, in ASCII
+/// only.
+fn write_trace_data(out: &mut Write, direction: &str, data: &[u8], ascii: bool) -> io::Result<()> {
+    writeln!(out, "{} data, {} bytes (0x{:x})", direction, data.len(), data.len())?;
+    let printable = |byte: u8| if byte >= 0x20 && byte < 0x7f {
+        byte as char
+    } else {
+        '.'
+    };
+    let mut offset = 0;
+    while offset < data.len() {
+        let line = if ascii {
+            let end = data.len().min(offset + 64);
+            match data[offset..end].iter().position(|byte| *byte == b'\n') {
+                Some(pos) => &data[offset..offset + pos + 1],
+                None => &data[offset..end],
+            }
         } else {
-            &buf[..]
+            &data[offset..data.len().min(offset + 16)]
         };
+        write!(out, "{:04x}: ", offset)?;
+        if ascii {
+            let text: String = line.iter()
+                .filter(|byte| **byte != b'\r' && **byte != b'\n')
+                .map(|byte| printable(*byte))
+                .collect();
+            writeln!(out, "{}", text)?;
+        } else {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = line.iter().map(|byte| printable(*byte)).collect();
+            writeln!(out, "{:<48}{}", hex.join(" "), text)?;
+        }
+        offset += line.len();
+    }
+    Ok(())
+}
+
+
+/// The connector of the `--unix-socket` or `--abstract-unix-socket`
+/// options, TCP otherwise.
+fn connector(unix_socket: Option<&str>,
+             abstract_unix_socket: Option<&str>)
+             -> CabotResult<Arc<Connector>> {
+    #[cfg(unix)]
+    {
+        if let Some(path) = unix_socket {
+            return Ok(Arc::new(UnixConnector::new(path)));
+        }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(name) = abstract_unix_socket {
+            return Ok(Arc::new(UnixConnector::abstract_name(name)));
+        }
+    }
+    if unix_socket.is_some() || abstract_unix_socket.is_some() {
+        return Err(CabotError::IOError("Unix domain sockets are not supported on this platform"
+            .to_owned()));
+    }
+    Ok(Arc::new(TcpConnector::new()))
+}
+
+
+fn format_seconds(duration: Duration) -> String {
+    format!("{}.{:06}", duration.as_secs(), duration.subsec_nanos() / 1000)
+}
+
+
+/// The value of a `-w/--write-out` variable, `None` if it is unknown.
+fn write_out_variable(name: &str, request: &Request, response: &Response) -> Option<String> {
+    let timings = response.timings();
+    let time = |phase: fn(&Timings) -> Duration| {
+        format_seconds(timings.map(phase).unwrap_or(Duration::from_secs(0)))
+    };
+    let size_download = response.raw_body().map(|body| body.len()).unwrap_or(0) as u64;
+    let value = match name {
+        "http_code" | "response_code" => format!("{:03}", response.status_code()),
+        "http_version" => response.http_version().trim_left_matches("HTTP/").to_owned(),
+        "method" => request.http_method().to_owned(),
+        "url_effective" => {
+            format!("{}://{}{}",
+                    request.scheme(),
+                    request.authority(),
+                    request.request_uri())
+        }
+        "content_type" => response.header("Content-Type").unwrap_or("").to_owned(),
+        "num_headers" => format!("{}", response.headers().len()),
+        "size_download" => format!("{}", size_download),
+        "size_upload" => format!("{}", request.content_length().unwrap_or(0)),
+        "size_header" => {
+            let status_line = response.http_version().len() + response.status_line().len() + 3;
+            let headers: usize = response.headers().iter().map(|header| header.len() + 2).sum();
+            format!("{}", status_line + headers + 2)
+        }
+        "speed_download" => {
+            let total = timings.map(|timings| timings.total()).unwrap_or(Duration::from_secs(0));
+            let millis = total.as_secs() * 1000 + (total.subsec_nanos() / 1_000_000) as u64;
+            format!("{}", size_download * 1000 / (millis + 1))
+        }
+        "time_namelookup" => time(Timings::dns_lookup),
+        "time_connect" => time(Timings::connect),
+        "time_appconnect" => {
+            time(|timings| timings.tls_handshake().unwrap_or(Duration::from_secs(0)))
+        }
+        "time_starttransfer" => {
+            time(|timings| timings.first_byte().unwrap_or(Duration::from_secs(0)))
+        }
+        "time_total" => time(Timings::total),
+        "remote_ip" => {
+            timings.and_then(|timings| timings.remote_addr())
+                .map(|addr| format!("{}", addr.ip()))
+                .unwrap_or("".to_owned())
+        }
+        "remote_port" => {
+            timings.and_then(|timings| timings.remote_addr())
+                .map(|addr| format!("{}", addr.port()))
+                .unwrap_or("".to_owned())
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
 
-        if log_enabled!(Info) {
-            info!("< [[{} bytes]]", body.len());
-        } else if self.verbose {
-            writeln!(&mut stderr(), "< [[{} bytes]]", body.len()).unwrap();
+/// Format the `-w/--write-out` output like curl does, with `%{variable}`
+/// and `\n`, `\r`, `\t` escapes.
+fn write_out_format(format: &str, request: &Request, response: &Response) -> String {
+    let mut output = String::with_capacity(format.len());
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().cloned()) {
+            ('%', Some('%')) => {
+                chars.next();
+                output.push('%');
+            }
+            ('%', Some('{')) => {
+                chars.next();
+                let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                match write_out_variable(name.as_str(), request, response) {
+                    Some(value) => output.push_str(value.as_str()),
+                    None => {
+                        let _ = writeln!(&mut stderr(), "Unknown --write-out variable: {}", name);
+                    }
+                }
+            }
+            ('\\', Some(escape)) if "nrt\\".contains(escape) => {
+                chars.next();
+                output.push(match escape {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    _ => '\\',
+                });
+            }
+            (c, _) => output.push(c),
         }
+    }
+    output
+}
+
 
-        self.out.write_all(body)?;
-        self.flush()?;
-        Ok(())
+fn write_verbose(line: &str, verbose: bool) {
+    if log_enabled!(Info) {
+        info!("{}", line);
+    } else if verbose {
+        writeln!(&mut stderr(), "{}", line).unwrap();
     }
+}
+
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.out.flush()
+fn write_response_head(response: &Response, verbose: bool) {
+    if log_enabled!(Info) {
+        info!("< {} {}", response.http_version(), response.status_line());
+        for header in response.headers() {
+            info!("< {}", header);
+        }
+    } else if verbose {
+        writeln!(&mut stderr(),
+                 "< {} {}",
+                 response.http_version(),
+                 response.status_line())
+            .unwrap();
+        for header in response.headers() {
+            writeln!(&mut stderr(), "< {}", header).unwrap();
+        }
     }
+}
 
-    // Don't implemented unused method
 
-    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not Implemented"))
+/// The head of the response, as received.
+fn format_response_head(response: &Response) -> String {
+    let mut head = format!("{} {}\r\n", response.http_version(), response.status_line());
+    for header in response.headers() {
+        head.push_str(header);
+        head.push_str("\r\n");
     }
+    head.push_str("\r\n");
+    head
+}
 
 
-    fn write_fmt(&mut self, _: Arguments) -> io::Result<()> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not Implemented"))
+/// Write the head of the response to `path`, - for stdout.
+fn write_dump_header(response: &Response, path: &str) -> CabotResult<()> {
+    let head = format_response_head(response);
+    let written = if path == "-" {
+        let mut stdout = io::stdout();
+        stdout.write_all(head.as_bytes()).and_then(|_| stdout.flush())
+    } else {
+        File::create(path).and_then(|mut file| file.write_all(head.as_bytes()))
+    };
+    written.map_err(|err| CabotError::WriteError(format!("{}: {}", path, err)))
+}
+
+
+/// Write the body of the response to `out`, preceded by its head if
+/// `include` is true.
+fn write_response(response: &Response,
+                  out: &mut Write,
+                  include: bool,
+                  verbose: bool)
+                  -> CabotResult<()> {
+    let body = response.body().unwrap_or(&[]);
+    if log_enabled!(Info) {
+        info!("< {} {}", response.http_version(), response.status_line());
+        for header in response.headers() {
+            info!("< {}", header);
+        }
+        info!("< [[{} bytes]]", body.len());
+    } else if verbose {
+        writeln!(&mut stderr(),
+                 "< {} {}",
+                 response.http_version(),
+                 response.status_line())
+            .unwrap();
+        for header in response.headers() {
+            writeln!(&mut stderr(), "< {}", header).unwrap();
+        }
+        writeln!(&mut stderr(), "< [[{} bytes]]", body.len()).unwrap();
+    }
+    if include {
+        out.write_all(format_response_head(response).as_bytes())
+            .map_err(|err| CabotError::WriteError(format!("{}", err)))?;
+    }
+    out.write_all(body).map_err(|err| CabotError::WriteError(format!("{}", err)))?;
+    out.flush().map_err(|err| CabotError::WriteError(format!("{}", err)))?;
+    Ok(())
+}
+
+
+
+fn main() {
+    pretty_env_logger::init().unwrap();
+    debug!("Starting cabot");
+    let matches = app().get_matches();
+    match run(&matches) {
+        Ok(()) => {
+            debug!("Command cabot ended succesfully");
+        }
+        Err(err) => {
+            let (code, message) = describe_error(&err);
+            if !matches.is_present("SILENT") || matches.is_present("SHOW_ERROR") {
+                let _ = writeln!(&mut std::io::stderr(), "{}", message);
+            }
+            std::process::exit(code);
+        }
+    }
+}
+
+
+// Internal Of the Binary
+
+
+/// The exit code and the message of an error, see `EXIT_CODES`.
+fn describe_error(err: &CabotError) -> (i32, String) {
+    match *err {
+        CabotError::SchemeError(ref scheme) => (1, format!("Unamanaged scheme: {}", scheme)),
+        CabotError::OpaqueUrlError(ref err) => (3, format!("Opaque URL Error: {}", err)),
+        CabotError::UrlParseError(ref err) => (3, format!("URL Parse Error: {}", err)),
+        CabotError::DnsError(ref err) => (6, format!("DnsError: {}", err)),
+        CabotError::ConnectError(ref err) => (7, format!("ConnectError: {}", err)),
+        CabotError::HttpResponseParseError(ref err) => {
+            (8, format!("HTTP Response Parse Error: {}", err))
+        }
+        CabotError::StatusError(ref err) => (22, format!("StatusError: {}", err)),
+        CabotError::WriteError(ref err) => (23, format!("WriteError: {}", err)),
+        CabotError::NetrcError(ref err) => (26, format!("NetrcError: {}", err)),
+        CabotError::TimeoutError(ref err) => (28, format!("TimeoutError: {}", err)),
+        CabotError::RangeError(ref err) => (33, format!("RangeError: {}", err)),
+        CabotError::CertificateError(ref err) => (35, format!("CertificateError: {}", err)),
+        CabotError::IOError(ref err) => (56, format!("IOError: {}", err)),
+        CabotError::EncodingError(ref err) => (61, format!("EncodingError: {}", err)),
+        CabotError::AuthenticationError(ref err) => {
+            (94, format!("AuthenticationError: {}", err))
+        }
+        CabotError::JsonError(ref err) => (1, format!("JsonError: {}", err)),
+        CabotError::CacheError(ref err) => (1, format!("CacheError: {}", err)),
+        CabotError::UpgradeError(ref err) => (1, format!("UpgradeError: {}", err)),
+        CabotError::WebSocketError(ref err) => (1, format!("WebSocketError: {}", err)),
+        CabotError::EventSourceError(ref err) => (1, format!("EventSourceError: {}", err)),
+    }
+}
+
+
+/// Parse a rate in bytes per second, with a k, m or g suffix like curl.
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let (number, unit) = match rate.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&rate[..rate.len() - 1], 1024),
+        Some('m') => (&rate[..rate.len() - 1], 1024 * 1024),
+        Some('g') => (&rate[..rate.len() - 1], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+    number.parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| format!("Invalid rate: {}", rate))
+}
+
+
+/// Format a size using 5 characters, like the curl progress meter.
+fn format_size(size: u64) -> String {
+    let units = ["k", "M", "G", "T"];
+    if size < 100000 {
+        return format!("{}", size);
+    }
+    let mut size = size / 1024;
+    for unit in units.iter() {
+        if size < 10000 {
+            return format!("{}{}", size, unit);
+        }
+        size /= 1024;
+    }
+    format!("{}P", size)
+}
+
+
+fn format_time(time: Option<u64>) -> String {
+    match time {
+        Some(secs) => format!("{:02}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60),
+        None => "--:--:--".to_owned(),
+    }
+}
+
+
+fn percent(count: u64, total: Option<u64>) -> u64 {
+    match total {
+        Some(total) if total > 0 => count * 100 / total,
+        Some(_) => 100,
+        None => 0,
+    }
+}
+
+
+const PROGRESS_REFRESH_MS: u64 = 200;
+const PROGRESS_BAR_WIDTH: u64 = 72;
+const PROGRESS_HEAD: [&'static str; 2] =
+    ["  % Total    % Received % Xferd  Average Speed   Time    Time     Time  Current",
+     "                                 Dload  Upload   Total   Spent    Left  Speed"];
+
+
+/// Display the progress of the transfer on stderr, as a meter or as a bar.
+struct ProgressMeter {
+    bar: bool,
+    // Time of the last display, none before the first one
+    displayed: Mutex<Option<Instant>>,
+}
+
+impl ProgressMeter {
+    pub fn new(bar: bool) -> Self {
+        ProgressMeter {
+            bar: bar,
+            displayed: Mutex::new(None),
+        }
+    }
+
+    pub fn update(&self, progress: &Progress) {
+        let mut displayed = self.displayed.lock().unwrap();
+        let line = match *displayed {
+            Some(last) if !progress.is_finished() &&
+                          last.elapsed() < Duration::from_millis(PROGRESS_REFRESH_MS) => {
+                return;
+            }
+            Some(_) => self.line(progress),
+            None if self.bar => self.line(progress),
+            None => {
+                format!("{}\n{}\n{}", PROGRESS_HEAD[0], PROGRESS_HEAD[1], self.line(progress))
+            }
+        };
+        let end = if progress.is_finished() { "\n" } else { "" };
+        let _ = write!(&mut stderr(), "\r{}{}", line, end);
+        *displayed = Some(Instant::now());
+    }
+
+    fn line(&self, progress: &Progress) -> String {
+        let downloaded = progress.downloaded();
+        let total = progress.download_total();
+        if self.bar {
+            return match total {
+                Some(_) => {
+                    let percent = percent(downloaded, total);
+                    let width = (percent * PROGRESS_BAR_WIDTH / 100) as usize;
+                    format!("{:<bar$} {:>3}%",
+                            "#".repeat(width),
+                            percent,
+                            bar = PROGRESS_BAR_WIDTH as usize)
+                }
+                None => {
+                    format!("{:<bar$} {}",
+                            "#",
+                            format_size(downloaded),
+                            bar = PROGRESS_BAR_WIDTH as usize)
+                }
+            };
+        }
+        let speed = progress.download_speed();
+        let spent = progress.elapsed().as_secs();
+        let time_total = match total {
+            Some(total) if speed > 0 => Some(total / speed),
+            _ => None,
+        };
+        format!("{:>3} {:>5}  {:>3} {:>5}  {:>3} {:>5}  {:>5}  {:>5} {:>8} {:>8} {:>8} {:>5}",
+                percent(downloaded, total),
+                format_size(total.unwrap_or(downloaded)),
+                percent(downloaded, total),
+                format_size(downloaded),
+                percent(progress.uploaded(), progress.upload_total()),
+                format_size(progress.uploaded()),
+                format_size(speed),
+                format_size(progress.upload_speed()),
+                format_time(time_total),
+                format_time(Some(spent)),
+                format_time(time_total.map(|time| if time > spent { time - spent } else { 0 })),
+                format_size(speed))
     }
 }
@@ -0,0 +1,874 @@
+//! HTTP cache of responses.
+//!
+//! A private cache following [rfc7234](https://tools.ietf.org/html/rfc7234):
+//! responses to `GET` requests are stored according to their
+//! `Cache-Control`, `Expires` and `Vary` headers, fresh responses are
+//! served without sending the request, and stale responses are revalidated
+//! using their `ETag` or `Last-Modified` validators.
+//!
+//! Responses are stored as received, before decoding their content.
+//!
+//! # Example
+//! ```no_run
+//! use cabot::{Client, RequestBuilder};
+//! use cabot::cache::Cache;
+//!
+//! let client = Client::new().set_cache(Cache::memory());
+//! let request = RequestBuilder::new("https://www.rust-lang.org/en-US/")
+//!     .build()
+//!     .unwrap();
+//! let response = client.execute(&request).unwrap();
+//! // Served from the cache while it is fresh
+//! let response = client.execute(&request).unwrap();
+//! println!("{:?}", response.cache_status());
+//! ```
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use md5;
+
+use super::date;
+use super::request::Request;
+use super::response::{Response, ResponseBuilder};
+use super::results::{CabotResult, CabotError};
+
+// Status codes that can be stored without explicit freshness information
+const HEURISTIC_STATUS_CODES: [usize; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+
+/// How the response has been obtained by the cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheStatus {
+    /// The response was fresh in the cache, no request has been sent.
+    Hit,
+    /// The response has been fetched from the server.
+    Miss,
+    /// The stored response has been validated by the server using a
+    /// `304 Not Modified` response.
+    Revalidated,
+}
+
+
+/// Parse the directives of `Cache-Control` headers, names are lowercased.
+fn cache_control(values: &[&str]) -> Vec<(String, Option<String>)> {
+    let mut directives = Vec::new();
+    for value in values {
+        let mut quoted = false;
+        let mut directive = String::new();
+        for c in value.chars().chain(",".chars()) {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => {
+                    let mut split = directive.splitn(2, '=');
+                    let name = split.next().unwrap().trim().to_lowercase();
+                    if !name.is_empty() {
+                        let value = split.next().map(|value| value.trim().to_owned());
+                        directives.push((name, value));
+                    }
+                    directive.clear();
+                }
+                _ => directive.push(c),
+            }
+        }
+    }
+    directives
+}
+
+
+fn has_directive(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|&(ref key, _)| key == name)
+}
+
+
+/// Seconds of a directive, `None` if it is missing or invalid.
+fn directive_seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives.iter()
+        .filter(|&&(ref key, _)| key == name)
+        .filter_map(|&(_, ref value)| value.as_ref().and_then(|value| value.parse().ok()))
+        .map(Duration::from_secs)
+        .next()
+}
+
+
+fn header_values<'a>(headers: &'a [String], name: &str) -> Vec<&'a str> {
+    headers.iter()
+        .filter_map(|header| {
+            let mut split = header.splitn(2, ':');
+            let key = split.next().unwrap();
+            match split.next() {
+                Some(value) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+
+fn header_name(header: &str) -> &str {
+    header.splitn(2, ':').next().unwrap().trim()
+}
+
+
+fn elapsed(from: SystemTime, to: SystemTime) -> Duration {
+    to.duration_since(from).unwrap_or(Duration::from_secs(0))
+}
+
+
+fn timestamp(time: SystemTime) -> u64 {
+    elapsed(UNIX_EPOCH, time).as_secs()
+}
+
+
+/// The names of the request headers selecting the stored response,
+/// `None` for `Vary: *`.
+fn vary_names(headers: &[String]) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+    for value in header_values(headers, "Vary") {
+        for name in value.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+            names.push(name.to_lowercase());
+        }
+    }
+    Some(names)
+}
+
+
+/// A stored response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    status_line: String,
+    headers: Vec<String>,
+    body: Vec<u8>,
+    // Request headers named by the Vary header of the response
+    vary: Vec<(String, Option<String>)>,
+    request_time: SystemTime,
+    response_time: SystemTime,
+}
+
+
+impl CacheEntry {
+    fn new(request: &Request,
+           response: &Response,
+           request_time: SystemTime,
+           response_time: SystemTime)
+           -> Self {
+        let headers: Vec<String> = response.headers().iter().map(|h| (*h).to_owned()).collect();
+        let vary = vary_names(&headers)
+            .unwrap_or(Vec::new())
+            .into_iter()
+            .map(|name| {
+                let value = request.header(name.as_str()).map(|value| value.to_owned());
+                (name, value)
+            })
+            .collect();
+        CacheEntry {
+            status_line: format!("{} {}", response.http_version(), response.status_line()),
+            headers: headers,
+            // Stored as received, decoded by the client
+            body: response.raw_body().unwrap_or(&[]).to_vec(),
+            vary: vary,
+            request_time: request_time,
+            response_time: response_time,
+        }
+    }
+
+    /// True if the request selects this stored response.
+    fn matches(&self, request: &Request) -> bool {
+        self.vary.iter().all(|&(ref name, ref value)| {
+            request.header(name.as_str()) == value.as_ref().map(|value| value.as_str())
+        })
+    }
+
+    fn cache_control(&self) -> Vec<(String, Option<String>)> {
+        cache_control(&header_values(&self.headers, "Cache-Control"))
+    }
+
+    fn status_code(&self) -> usize {
+        self.status_line.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or(0)
+    }
+
+    fn date(&self) -> SystemTime {
+        header_values(&self.headers, "Date")
+            .first()
+            .and_then(|value| date::parse(value))
+            .unwrap_or(self.response_time)
+    }
+
+    /// The time the response is fresh after its generation by the server.
+    fn freshness_lifetime(&self) -> Duration {
+        let directives = self.cache_control();
+        if let Some(max_age) = directive_seconds(&directives, "max-age") {
+            return max_age;
+        }
+        if let Some(expires) = header_values(&self.headers, "Expires").first() {
+            // Invalid dates represent a time in the past
+            return match date::parse(expires) {
+                Some(expires) => elapsed(self.date(), expires),
+                None => Duration::from_secs(0),
+            };
+        }
+        let last_modified = header_values(&self.headers, "Last-Modified")
+            .first()
+            .and_then(|value| date::parse(value));
+        match last_modified {
+            Some(last_modified) if HEURISTIC_STATUS_CODES.contains(&self.status_code()) => {
+                elapsed(last_modified, self.date()) / 10
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// The age of the response, the time since its generation by the server.
+    fn current_age(&self, now: SystemTime) -> Duration {
+        let apparent_age = elapsed(self.date(), self.response_time);
+        let age = header_values(&self.headers, "Age")
+            .first()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(0));
+        let corrected_age = age + elapsed(self.request_time, self.response_time);
+        let initial_age = if apparent_age > corrected_age {
+            apparent_age
+        } else {
+            corrected_age
+        };
+        initial_age + elapsed(self.response_time, now)
+    }
+
+    /// True if the response can be served without validation.
+    fn is_fresh(&self, request_directives: &[(String, Option<String>)], now: SystemTime) -> bool {
+        let directives = self.cache_control();
+        if has_directive(&directives, "no-cache") || has_directive(request_directives, "no-cache") {
+            return false;
+        }
+        let age = self.current_age(now);
+        let mut lifetime = self.freshness_lifetime();
+        if let Some(max_age) = directive_seconds(request_directives, "max-age") {
+            if age > max_age {
+                return false;
+            }
+        }
+        if let Some(min_fresh) = directive_seconds(request_directives, "min-fresh") {
+            if lifetime < min_fresh {
+                return false;
+            }
+            lifetime -= min_fresh;
+        }
+        if lifetime > age {
+            return true;
+        }
+        if has_directive(&directives, "must-revalidate") {
+            return false;
+        }
+        match request_directives.iter().find(|&&(ref key, _)| key == "max-stale") {
+            Some(&(_, None)) => true,
+            Some(&(_, Some(ref max_stale))) => {
+                match max_stale.parse() {
+                    Ok(max_stale) => age - lifetime <= Duration::from_secs(max_stale),
+                    Err(_) => false,
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// The request validating the stored response, if it has validators.
+    fn conditional_request(&self, request: &Request) -> Option<Request> {
+        let etag = header_values(&self.headers, "ETag");
+        let last_modified = header_values(&self.headers, "Last-Modified");
+        if etag.is_empty() && last_modified.is_empty() {
+            return None;
+        }
+        let mut request = request.clone();
+        if let Some(etag) = etag.first() {
+            request.add_header(&format!("If-None-Match: {}", etag));
+        }
+        if let Some(last_modified) = last_modified.first() {
+            request.add_header(&format!("If-Modified-Since: {}", last_modified));
+        }
+        Some(request)
+    }
+
+    /// Update the stored response with the headers of a `304 Not Modified`
+    /// response.
+    fn revalidate(&mut self,
+                  response: &Response,
+                  request_time: SystemTime,
+                  response_time: SystemTime) {
+        let updated: Vec<&str> = response.headers()
+            .into_iter()
+            .filter(|header| !header_name(header).eq_ignore_ascii_case("Content-Length"))
+            .collect();
+        self.headers.retain(|header| {
+            let name = header_name(header);
+            !updated.iter().any(|update| header_name(update).eq_ignore_ascii_case(name))
+        });
+        self.headers.extend(updated.iter().map(|header| (*header).to_owned()));
+        self.request_time = request_time;
+        self.response_time = response_time;
+    }
+
+    /// Build the response served to the client, with its `Age` header.
+    fn response(&self, now: SystemTime, status: CacheStatus) -> CabotResult<Response> {
+        let mut builder = ResponseBuilder::new().set_status_line(self.status_line.as_str());
+        for header in self.headers.iter() {
+            if !header_name(header).eq_ignore_ascii_case("Age") {
+                builder = builder.add_header(header.as_str());
+            }
+        }
+        let age = format!("Age: {}", self.current_age(now).as_secs());
+        let mut response = builder.add_header(age.as_str())
+            .set_body(self.body.as_slice())
+            .build()?;
+        response.set_cache_status(status);
+        Ok(response)
+    }
+
+    /// Serialize the entry, used to store it in a file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.body.len() + 1024);
+        bytes.extend_from_slice(format!("Request-Time: {}\r\nResponse-Time: {}\r\n",
+                                        timestamp(self.request_time),
+                                        timestamp(self.response_time))
+            .as_bytes());
+        for &(ref name, ref value) in self.vary.iter() {
+            let vary = match *value {
+                Some(ref value) => format!("Vary: {}: {}\r\n", name, value),
+                None => format!("Vary: {}\r\n", name),
+            };
+            bytes.extend_from_slice(vary.as_bytes());
+        }
+        bytes.extend_from_slice(format!("\r\n{}\r\n", self.status_line).as_bytes());
+        for header in self.headers.iter() {
+            bytes.extend_from_slice(format!("{}\r\n", header).as_bytes());
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(self.body.as_slice());
+        bytes
+    }
+
+    /// Deserialize an entry serialized by `to_bytes()`.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::CacheError in case the entry is malformed
+    pub fn from_bytes(bytes: &[u8]) -> CabotResult<Self> {
+        let malformed = || CabotError::CacheError("Malformed cache entry".to_owned());
+        let mut lines = Vec::new();
+        let mut pos = 0;
+        // Metadata and the response head end with an empty line
+        while lines.iter().filter(|line: &&String| line.is_empty()).count() < 2 {
+            let end = bytes[pos..].windows(2).position(|w| w == b"\r\n").ok_or_else(&malformed)?;
+            lines.push(String::from_utf8_lossy(&bytes[pos..pos + end]).into_owned());
+            pos += end + 2;
+        }
+        let mut lines = lines.into_iter();
+        let mut entry = CacheEntry {
+            status_line: String::new(),
+            headers: Vec::new(),
+            body: bytes[pos..].to_vec(),
+            vary: Vec::new(),
+            request_time: UNIX_EPOCH,
+            response_time: UNIX_EPOCH,
+        };
+        for line in lines.by_ref().take_while(|line| !line.is_empty()) {
+            let mut split = line.splitn(2, ": ");
+            let (key, value) = (split.next().unwrap(), split.next().ok_or_else(&malformed)?);
+            match key {
+                "Request-Time" | "Response-Time" => {
+                    let secs = value.parse().map_err(|_| malformed())?;
+                    let time = UNIX_EPOCH + Duration::from_secs(secs);
+                    if key == "Request-Time" {
+                        entry.request_time = time;
+                    } else {
+                        entry.response_time = time;
+                    }
+                }
+                "Vary" => {
+                    let mut split = value.splitn(2, ": ");
+                    let name = split.next().unwrap().to_owned();
+                    entry.vary.push((name, split.next().map(|value| value.to_owned())));
+                }
+                _ => return Err(malformed()),
+            }
+        }
+        entry.status_line = lines.next().ok_or_else(&malformed)?;
+        entry.headers = lines.take_while(|line| !line.is_empty()).collect();
+        Ok(entry)
+    }
+}
+
+
+/// Storage of the cache entries.
+///
+/// Storages are shared by the requests of the client, the entries are
+/// only read and written using `&self`.
+pub trait CacheStorage: Send + Sync {
+    /// Get the entry stored for the key.
+    fn get(&self, key: &str) -> Option<CacheEntry>;
+    /// Store the entry, replacing the existing one.
+    fn put(&self, key: &str, entry: &CacheEntry);
+    /// Remove the entry stored for the key.
+    fn remove(&self, key: &str);
+}
+
+
+/// Store the entries in memory, for the lifetime of the client.
+pub struct MemoryStorage {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+
+impl MemoryStorage {
+    /// Create an empty storage.
+    pub fn new() -> Self {
+        MemoryStorage { entries: Mutex::new(HashMap::new()) }
+    }
+}
+
+
+impl CacheStorage for MemoryStorage {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &str, entry: &CacheEntry) {
+        self.entries.lock().unwrap().insert(key.to_owned(), entry.clone());
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+
+/// Store the entries in a directory, one file per entry.
+pub struct DiskStorage {
+    directory: PathBuf,
+}
+
+
+impl DiskStorage {
+    /// Create the storage, the directory is created if it doesn't exist.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::IOError in case the directory can't be created
+    pub fn new<P: AsRef<Path>>(directory: P) -> CabotResult<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)
+            .map_err(|err| CabotError::IOError(format!("{}: {}", directory.display(), err)))?;
+        Ok(DiskStorage { directory: directory })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{:x}", md5::compute(key.as_bytes())))
+    }
+}
+
+
+impl CacheStorage for DiskStorage {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let path = self.path(key);
+        let mut bytes = Vec::new();
+        if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+            debug!("No cache entry for {} in {}: {}", key, path.display(), err);
+            return None;
+        }
+        match CacheEntry::from_bytes(bytes.as_slice()) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                warn!("Ignoring cache entry {}: {:?}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    fn put(&self, key: &str, entry: &CacheEntry) {
+        let path = self.path(key);
+        // Write then rename, readers never get a partial entry
+        let tmp_path = path.with_extension("tmp");
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(entry.to_bytes().as_slice()))
+            .and_then(|_| fs::rename(&tmp_path, &path));
+        if let Err(err) = result {
+            warn!("Cannot store cache entry {}: {}", path.display(), err);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = fs::remove_file(self.path(key));
+    }
+}
+
+
+/// The HTTP cache of a [Client](../client/struct.Client.html).
+pub struct Cache {
+    storage: Box<CacheStorage>,
+}
+
+
+impl Cache {
+    /// Create a cache using the given storage.
+    pub fn new<S: CacheStorage + 'static>(storage: S) -> Self {
+        Cache { storage: Box::new(storage) }
+    }
+
+    /// Create a cache stored in memory.
+    pub fn memory() -> Self {
+        Cache::new(MemoryStorage::new())
+    }
+
+    /// Create a cache stored in a directory, shared between runs.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::IOError in case the directory can't be created
+    pub fn disk<P: AsRef<Path>>(directory: P) -> CabotResult<Self> {
+        Ok(Cache::new(DiskStorage::new(directory)?))
+    }
+
+    fn key(request: &Request) -> String {
+        format!("{}://{}{}",
+                request.scheme(),
+                request.authority(),
+                request.request_uri())
+    }
+
+    /// Execute the request using the cache, `fetch` sends a request to the
+    /// server.
+    pub(crate) fn execute<F>(&self, request: &Request, fetch: F) -> CabotResult<Response>
+        where F: Fn(&Request) -> CabotResult<Response>
+    {
+        let key = Cache::key(request);
+        match request.http_method() {
+            "GET" => {}
+            "HEAD" | "OPTIONS" | "TRACE" => return fetch(request),
+            _ => {
+                let response = fetch(request)?;
+                if response.status_code() < 400 {
+                    debug!("Invalidating cache entry {}", key);
+                    self.storage.remove(&key);
+                }
+                return Ok(response);
+            }
+        }
+        // Conditional and partial requests are sent as is
+        let bypass = ["If-None-Match", "If-Modified-Since", "If-Range", "Range"];
+        if bypass.iter().any(|name| request.header(name).is_some()) {
+            return fetch(request);
+        }
+
+        let mut directives = cache_control(&request.header("Cache-Control")
+            .into_iter()
+            .collect::<Vec<&str>>());
+        if directives.is_empty() && request.header("Pragma") == Some("no-cache") {
+            directives.push(("no-cache".to_owned(), None));
+        }
+        let entry = if has_directive(&directives, "no-store") {
+            None
+        } else {
+            self.storage.get(&key).and_then(|entry| if entry.matches(request) {
+                Some(entry)
+            } else {
+                None
+            })
+        };
+
+        let request_time = SystemTime::now();
+        let (response, entry) = match entry {
+            Some(entry) => {
+                if entry.is_fresh(&directives, request_time) {
+                    debug!("Fresh cache entry {}", key);
+                    return entry.response(request_time, CacheStatus::Hit);
+                }
+                match entry.conditional_request(request) {
+                    Some(conditional) => {
+                        debug!("Revalidating cache entry {}", key);
+                        (fetch(&conditional)?, Some(entry))
+                    }
+                    None => (fetch(request)?, None),
+                }
+            }
+            None => (fetch(request)?, None),
+        };
+        let response_time = SystemTime::now();
+
+        if let Some(mut entry) = entry {
+            if response.status_code() == 304 {
+                entry.revalidate(&response, request_time, response_time);
+                self.storage.put(&key, &entry);
+                return entry.response(response_time, CacheStatus::Revalidated);
+            }
+        }
+        let entry = CacheEntry::new(request, &response, request_time, response_time);
+        if Cache::is_storable(&directives, &response, &entry) {
+            debug!("Storing cache entry {}", key);
+            self.storage.put(&key, &entry);
+        }
+        let mut response = response;
+        response.set_cache_status(CacheStatus::Miss);
+        Ok(response)
+    }
+
+    fn is_storable(request_directives: &[(String, Option<String>)],
+                   response: &Response,
+                   entry: &CacheEntry)
+                   -> bool {
+        let directives = entry.cache_control();
+        if has_directive(request_directives, "no-store") || has_directive(&directives, "no-store") {
+            return false;
+        }
+        // Partial contents are not combined
+        if response.status_code() < 200 || response.status_code() == 206 {
+            return false;
+        }
+        if vary_names(&entry.headers).is_none() {
+            return false;
+        }
+        let explicit = has_directive(&directives, "max-age") ||
+                       has_directive(&directives, "public") ||
+                       response.header("Expires").is_some();
+        if !explicit && !HEURISTIC_STATUS_CODES.contains(&response.status_code()) {
+            return false;
+        }
+        // Useless without freshness or validators
+        entry.freshness_lifetime() > Duration::from_secs(0) ||
+        response.header("ETag").is_some() || response.header("Last-Modified").is_some()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::testing::{MockServer, MockResponse};
+
+    fn request(headers: &[&str]) -> Request {
+        RequestBuilder::new("http://localhost/doc")
+            .add_headers(headers)
+            .build()
+            .unwrap()
+    }
+
+    fn response(status_line: &str, headers: &[&str], body: &str) -> Response {
+        let mut builder = ResponseBuilder::new().set_status_line(status_line);
+        for header in headers {
+            builder = builder.add_header(header);
+        }
+        builder.set_body(body.as_bytes()).build().unwrap()
+    }
+
+    /// A client caching the responses in memory.
+    fn client() -> Client {
+        Client::new().set_cache(Cache::memory())
+    }
+
+    fn get(server: &MockServer, headers: &[&str]) -> Request {
+        RequestBuilder::new(&server.url("/doc"))
+            .add_headers(headers)
+            .build()
+            .unwrap()
+    }
+
+    fn mock(status_line: &str, headers: &[&str], body: &str) -> MockResponse {
+        headers.iter()
+            .fold(MockResponse::new(status_line), |response, header| response.add_header(header))
+            .set_body(body.as_bytes())
+    }
+
+    #[test]
+    fn test_cache_control() {
+        assert_eq!(cache_control(&["max-age=60, No-Cache=\"Set-Cookie, Foo\"", "public"]),
+                   vec![("max-age".to_owned(), Some("60".to_owned())),
+                        ("no-cache".to_owned(), Some("Set-Cookie, Foo".to_owned())),
+                        ("public".to_owned(), None)]);
+    }
+
+    #[test]
+    fn test_freshness_lifetime() {
+        let now = SystemTime::now();
+        let date = date::format(now);
+        let expires = date::format(now + Duration::from_secs(120));
+        let last_modified = date::format(now - Duration::from_secs(1000));
+        let cases: Vec<(Vec<String>, u64)> =
+            vec![(vec![format!("Date: {}", date), "Cache-Control: max-age=60".to_owned()], 60),
+                 (vec![format!("Date: {}", date), format!("Expires: {}", expires)], 120),
+                 (vec![format!("Date: {}", date), "Expires: 0".to_owned()], 0),
+                 (vec![format!("Date: {}", date), format!("Last-Modified: {}", last_modified)],
+                  100),
+                 (vec![format!("Date: {}", date)], 0)];
+        for (headers, lifetime) in cases {
+            let headers: Vec<&str> = headers.iter().map(|h| h.as_str()).collect();
+            let entry = CacheEntry::new(&request(&[]),
+                                        &response("HTTP/1.1 200 OK", &headers, ""),
+                                        now,
+                                        now);
+            assert_eq!(entry.freshness_lifetime(), Duration::from_secs(lifetime));
+        }
+    }
+
+    #[test]
+    fn test_current_age() {
+        let now = SystemTime::now();
+        let date = format!("Date: {}", date::format(now - Duration::from_secs(10)));
+        let entry = CacheEntry::new(&request(&[]),
+                                    &response("HTTP/1.1 200 OK", &[&date, "Age: 5"], ""),
+                                    now - Duration::from_secs(1),
+                                    now);
+        assert_eq!(entry.current_age(now + Duration::from_secs(30)).as_secs(), 40);
+    }
+
+    #[test]
+    fn test_fresh_hit() {
+        let server = MockServer::start();
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=60"], "Hello World!"));
+        let client = client();
+        let response = client.execute(&get(&server, &[])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Miss));
+        let response = client.execute(&get(&server, &[])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Hit));
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("Age"), Some("0"));
+        assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_revalidate() {
+        let server = MockServer::start();
+        server.add_response(mock("200 OK",
+                                 &["Cache-Control: no-cache", "ETag: \"v1\"", "X-Version: 1"],
+                                 "Hello World!"));
+        server.add_response(mock("304 Not Modified", &["ETag: \"v1\"", "X-Version: 2"], ""));
+        let client = client();
+        client.execute(&get(&server, &[])).unwrap();
+        let response = client.execute(&get(&server, &[])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Revalidated));
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header_values("X-Version"), vec!["2"]);
+        assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+        let requests = server.requests();
+        assert_eq!(requests[0].header("If-None-Match"), None);
+        assert_eq!(requests[1].header("If-None-Match"), Some("\"v1\""));
+    }
+
+    #[test]
+    fn test_stale_replaced() {
+        let last_modified = "Last-Modified: Sun, 06 Nov 1994 08:49:37 GMT";
+        let server = MockServer::start();
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=0", last_modified], "v1"));
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=60"], "v2"));
+        let client = client();
+        client.execute(&get(&server, &[])).unwrap();
+        let response = client.execute(&get(&server, &[])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Miss));
+        assert_eq!(server.requests()[1].header("If-Modified-Since"),
+                   Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+        let response = client.execute(&get(&server, &[])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Hit));
+        assert_eq!(response.body_as_string().unwrap(), "v2");
+    }
+
+    #[test]
+    fn test_vary() {
+        let vary = ["Cache-Control: max-age=60", "Vary: Accept-Language"];
+        let server = MockServer::start();
+        server.add_response(mock("200 OK", &vary, "en"));
+        server.add_response(mock("200 OK", &vary, "fr"));
+        let client = client();
+        let en = get(&server, &["Accept-Language: en"]);
+        let fr = get(&server, &["Accept-Language: fr"]);
+        client.execute(&en).unwrap();
+        assert_eq!(client.execute(&en).unwrap().cache_status(), Some(CacheStatus::Hit));
+        let response = client.execute(&fr).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Miss));
+        assert_eq!(response.body_as_string().unwrap(), "fr");
+    }
+
+    #[test]
+    fn test_not_stored() {
+        let server = MockServer::start();
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=60, no-store"], ""));
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=60", "Vary: *"], ""));
+        server.add_response(mock("200 OK", &[], ""));
+        server.add_response(mock("200 OK", &[], ""));
+        let client = client();
+        for _ in 0..4 {
+            let response = client.execute(&get(&server, &[])).unwrap();
+            assert_eq!(response.cache_status(), Some(CacheStatus::Miss));
+        }
+    }
+
+    #[test]
+    fn test_request_directives() {
+        let server = MockServer::start();
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=60"], ""));
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=60"], ""));
+        let client = client();
+        client.execute(&get(&server, &[])).unwrap();
+        let response = client.execute(&get(&server, &["Cache-Control: no-cache"])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Miss));
+        let response = client.execute(&get(&server, &["Cache-Control: min-fresh=30"])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Hit));
+    }
+
+    #[test]
+    fn test_unsafe_method_invalidates() {
+        let server = MockServer::start();
+        server.add_response(mock("200 OK", &["Cache-Control: max-age=60"], ""));
+        server.add_response(mock("204 No Content", &[], ""));
+        server.add_response(mock("200 OK", &[], ""));
+        let client = client();
+        client.execute(&get(&server, &[])).unwrap();
+        let post = RequestBuilder::new(&server.url("/doc"))
+            .set_http_method("POST")
+            .build()
+            .unwrap();
+        let response = client.execute(&post).unwrap();
+        assert_eq!(response.cache_status(), None);
+        let response = client.execute(&get(&server, &[])).unwrap();
+        assert_eq!(response.cache_status(), Some(CacheStatus::Miss));
+    }
+
+    #[test]
+    fn test_entry_bytes() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        let entry = CacheEntry::new(&request(&["Accept: text/plain"]),
+                                    &response("HTTP/1.1 200 OK",
+                                              &["Vary: Accept, Accept-Language"],
+                                              "Hello\r\n\r\nWorld!"),
+                                    now,
+                                    now);
+        let bytes = entry.to_bytes();
+        assert_eq!(CacheEntry::from_bytes(bytes.as_slice()).unwrap(), entry);
+        assert!(CacheEntry::from_bytes(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn test_disk_storage() {
+        let directory = env::temp_dir().join(format!("cabot-cache-{}", ::rand::random::<u32>()));
+        let storage = DiskStorage::new(&directory).unwrap();
+        let entry = CacheEntry::new(&request(&[]),
+                                    &response("HTTP/1.1 200 OK", &[], "Hello World!"),
+                                    UNIX_EPOCH,
+                                    UNIX_EPOCH);
+        assert_eq!(storage.get("key"), None);
+        storage.put("key", &entry);
+        assert_eq!(storage.get("key"), Some(entry));
+        storage.remove("key");
+        assert_eq!(storage.get("key"), None);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
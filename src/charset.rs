@@ -0,0 +1,168 @@
+//! Character encodings of text bodies.
+//!
+//! The charset of a body is read from its byte order mark, then from the
+//! `Content-Type` header, then from a `<meta>` tag in the first bytes of
+//! an HTML body. Labels are resolved as specified by the
+//! [WHATWG Encoding Standard](https://encoding.spec.whatwg.org/#names-and-labels),
+//! `iso-8859-1` is decoded as `windows-1252` for instance.
+//!
+//! # Example
+//! ```
+//! use cabot::charset;
+//!
+//! let content_type = "text/html; charset=ISO-8859-1";
+//! assert_eq!(charset::from_content_type(content_type), Some("ISO-8859-1".to_owned()));
+//! assert_eq!(charset::decode(b"caf\xe9", "ISO-8859-1", false).unwrap(), "café");
+//! ```
+
+use std::cmp;
+
+use encoding::DecoderTrap;
+use encoding::label::encoding_from_whatwg_label;
+
+use super::results::{CabotResult, CabotError};
+
+/// Number of bytes of an HTML body read to find a `<meta>` charset.
+const SNIFF_SIZE: usize = 1024;
+
+
+/// Read the charset parameter of a `Content-Type` header value.
+pub fn from_content_type(content_type: &str) -> Option<String> {
+    content_type.split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut split = param.splitn(2, '=');
+            let key = split.next().unwrap().trim();
+            match split.next() {
+                Some(value) if key.eq_ignore_ascii_case("charset") => {
+                    Some(value.trim().trim_matches('"').to_owned())
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+
+/// Detect the charset using the byte order mark of the body.
+///
+/// Return the charset and the length of the byte order mark.
+pub fn from_bom(body: &[u8]) -> Option<(&'static str, usize)> {
+    if body.starts_with(b"\xEF\xBB\xBF") {
+        Some(("utf-8", 3))
+    } else if body.starts_with(b"\xFF\xFE") {
+        Some(("utf-16le", 2))
+    } else if body.starts_with(b"\xFE\xFF") {
+        Some(("utf-16be", 2))
+    } else {
+        None
+    }
+}
+
+
+/// Find the charset declared by a `<meta charset>` or a
+/// `<meta http-equiv="Content-Type">` tag of an HTML document.
+pub fn sniff_html(body: &[u8]) -> Option<String> {
+    let head = &body[..cmp::min(body.len(), SNIFF_SIZE)];
+    let head = String::from_utf8_lossy(head).to_lowercase();
+    let mut rest = head.as_str();
+    while let Some(pos) = rest.find("<meta") {
+        rest = &rest[pos + 5..];
+        let tag = match rest.find('>') {
+            Some(end) => &rest[..end],
+            None => rest,
+        };
+        let pos = match tag.find("charset") {
+            Some(pos) => pos,
+            None => continue,
+        };
+        let value = tag[pos + 7..].trim_left();
+        if !value.starts_with('=') {
+            continue;
+        }
+        let value = value[1..].trim_left().trim_left_matches(|c| c == '"' || c == '\'');
+        let end = value.find(|c: char| c == '"' || c == '\'' || c == ';' || c == '/' ||
+                                 c.is_whitespace())
+            .unwrap_or(value.len());
+        if end > 0 {
+            return Some(value[..end].to_owned());
+        }
+    }
+    None
+}
+
+
+/// Decode a body using the given charset label.
+///
+/// If `lossy` is true, invalid sequences are replaced by `U+FFFD`.
+///
+/// Errors:
+///
+///  - CabotError::EncodingError in case the charset is unknown or the body
+///    is not valid in this charset.
+pub fn decode(body: &[u8], charset: &str, lossy: bool) -> CabotResult<String> {
+    let encoding = match encoding_from_whatwg_label(charset.trim()) {
+        Some(encoding) => encoding,
+        None => return Err(CabotError::EncodingError(format!("Unknown charset: {}", charset))),
+    };
+    let trap = if lossy {
+        DecoderTrap::Replace
+    } else {
+        DecoderTrap::Strict
+    };
+    encoding.decode(body, trap)
+        .map_err(|err| {
+            CabotError::EncodingError(format!("Cannot decode {}: {}", encoding.name(), err))
+        })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_content_type() {
+        assert_eq!(from_content_type("text/plain; charset=utf-8"),
+                   Some("utf-8".to_owned()));
+        assert_eq!(from_content_type("text/html;Charset=\"Shift_JIS\""),
+                   Some("Shift_JIS".to_owned()));
+        assert_eq!(from_content_type("application/json"), None);
+    }
+
+    #[test]
+    fn test_from_bom() {
+        assert_eq!(from_bom(b"\xEF\xBB\xBFabc"), Some(("utf-8", 3)));
+        assert_eq!(from_bom(b"\xFF\xFEa\x00"), Some(("utf-16le", 2)));
+        assert_eq!(from_bom(b"\xFE\xFF\x00a"), Some(("utf-16be", 2)));
+        assert_eq!(from_bom(b"abc"), None);
+    }
+
+    #[test]
+    fn test_sniff_html() {
+        assert_eq!(sniff_html(b"<html><head><META CHARSET=\"windows-1252\"></head>"),
+                   Some("windows-1252".to_owned()));
+        assert_eq!(sniff_html(b"<meta http-equiv=\"Content-Type\" \
+                                content=\"text/html; charset=iso-8859-1\"/>"),
+                   Some("iso-8859-1".to_owned()));
+        assert_eq!(sniff_html(b"<meta charset = 'euc-jp'>"), Some("euc-jp".to_owned()));
+        assert_eq!(sniff_html(b"<meta name=\"description\" content=\"charset\">"), None);
+        assert_eq!(sniff_html(b"<html></html>"), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"caf\xe9", "iso-8859-1", false).unwrap(), "café");
+        assert_eq!(decode(b"\x80", "windows-1252", false).unwrap(), "€");
+        assert_eq!(decode(b"c\x00a\x00", "utf-16le", false).unwrap(), "ca");
+        assert_eq!(decode(b"\x00c\x00a", "UTF-16BE", false).unwrap(), "ca");
+        assert_eq!(decode(b"\x93\xfa\x96\x7b", "shift_jis", false).unwrap(), "日本");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode(b"abc", "not-a-charset", false).is_err());
+        assert!(decode(b"caf\xe9", "utf-8", false).is_err());
+        assert_eq!(decode(b"caf\xe9", "utf-8", true).unwrap(), "caf\u{FFFD}");
+    }
+}
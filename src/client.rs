@@ -1,207 +1,633 @@
 //! The HTTP Client that perform query
 
 use std::io::{self, Write};
-use std::fmt::Arguments;
+use std::sync::Arc;
 
 use super::request::Request;
+use super::auth;
+use super::cache::Cache;
+use super::compression::Decoder;
+use super::netrc::Netrc;
 use super::http;
-use super::response::{Response, ResponseBuilder};
-use super::results::CabotResult;
+use super::middleware::{Middleware, Next};
+use super::parser::ResponseParser;
+use super::progress::Monitor;
+use super::response::Response;
+use super::results::{CabotResult, CabotError};
+use super::trace::Tracer;
+use super::transport::{Connector, ResponseStream, TcpConnector, Upgraded};
 use super::constants;
 
 /// Perform the http query
 pub struct Client {
     verbose: bool,
+    netrc: Option<Netrc>,
+    compressed: bool,
+    cache: Option<Cache>,
+    monitor: Monitor,
+    middlewares: Vec<Box<Middleware>>,
+    connector: Box<Connector>,
+    interim_handler: Option<Arc<Fn(&Response) + Send + Sync>>,
+    tracer: Option<Tracer>,
 }
 
 
 impl Client {
     /// Construct a new `Client`
     pub fn new() -> Self {
-        Client { verbose: false }
+        Client {
+            verbose: false,
+            netrc: None,
+            compressed: false,
+            cache: None,
+            monitor: Monitor::new(),
+            middlewares: Vec::new(),
+            connector: Box::new(TcpConnector::new()),
+            interim_handler: None,
+            tracer: None,
+        }
+    }
+
+    /// Display the request and the response headers on stderr.
+    pub fn set_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Authenticate requests that have no credentials using a
+    /// [netrc](../netrc/struct.Netrc.html) file.
+    pub fn set_netrc(mut self, netrc: Netrc) -> Self {
+        self.netrc = Some(netrc);
+        self
+    }
+
+    /// Request a compressed response, using the `Accept-Encoding` header,
+    /// and decode the response body.
+    ///
+    /// The body as received is available using
+    /// [raw_body](../response/struct.Response.html#method.raw_body).
+    pub fn set_compressed(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    /// Store the responses in an HTTP [cache](../cache/struct.Cache.html),
+    /// fresh responses are served without sending the request.
+    pub fn set_cache(mut self, cache: Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Report the progress of the transfers, limit their rate and abort
+    /// the slow ones using a [Monitor](../progress/struct.Monitor.html).
+    pub fn set_monitor(mut self, monitor: Monitor) -> Self {
+        self.monitor = monitor;
+        self
+    }
+
+    /// Add a [middleware](../middleware/index.html) called for every
+    /// request sent, after the middlewares already added.
+    pub fn add_middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Open the connections using the
+    /// [Connector](../transport/trait.Connector.html), instead of TCP
+    /// connections.
+    pub fn set_connector<C: Connector + 'static>(mut self, connector: C) -> Self {
+        self.connector = Box::new(connector);
+        self
+    }
+
+    /// Call the handler with the interim responses received before the
+    /// responses, such as `103 Early Hints`, as soon as they are received.
+    pub fn set_interim_handler<F>(mut self, handler: F) -> Self
+        where F: Fn(&Response) + Send + Sync + 'static
+    {
+        self.interim_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Report the bytes sent and received on the connections, and the
+    /// connections information, to the [Tracer](../trace/struct.Tracer.html).
+    pub fn set_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
     }
 
     /// Execute the query [Request](../request/struct.Request.html) and
     /// return the associate [Response](../response/struct.Response.html).
+    ///
+    /// If the request use Digest authentication, the request is sent
+    /// again to answer the challenge of the server.
     pub fn execute(&self, request: &Request) -> CabotResult<Response> {
-        let mut out = CabotLibWrite::new();
-        http::http_query(&request, &mut out, self.verbose)?;
-        out.response()
+        let mut request = match self.netrc {
+            Some(ref netrc) => netrc.authenticate(request),
+            None => request.clone(),
+        };
+        if self.compressed && request.header("Accept-Encoding").is_none() {
+            request.add_header(&format!("Accept-Encoding: {}", constants::ACCEPT_ENCODING));
+        }
+        let mut response = match self.cache {
+            Some(ref cache) => {
+                cache.execute(&request, |request| self.execute_authenticated(request))?
+            }
+            None => self.execute_authenticated(&request)?,
+        };
+        if self.compressed {
+            response.decode_content()?;
+        }
+        Ok(response)
+    }
+
+    /// Execute the request asking the server to switch to the protocol of
+    /// its `Upgrade` header, and return the stream of the connection.
+    ///
+    /// The middlewares and the cache are not used.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::UpgradeError if the server did not answer
+    ///    `101 Switching Protocols`
+    pub fn upgrade(&self, request: &Request) -> CabotResult<Upgraded> {
+        let request = match self.netrc {
+            Some(ref netrc) => netrc.authenticate(request),
+            None => request.clone(),
+        };
+        let upgraded = http::http_upgrade(&request,
+                                          self.verbose,
+                                          &*self.connector,
+                                          self.tracer.as_ref())?;
+        if upgraded.response().status_code() != 101 {
+            return Err(CabotError::UpgradeError(format!("The server answered {}",
+                                                        upgraded.response().status_line())));
+        }
+        Ok(upgraded)
+    }
+
+    /// Execute the request and return the response once its head has
+    /// been received, the body is read from the
+    /// [ResponseStream](../transport/struct.ResponseStream.html) as it is
+    /// received.
+    ///
+    /// The middlewares and the cache are not used. The body is decoded
+    /// while it is read if the client requests compressed responses. The
+    /// Digest challenge of the server is answered, and the body read is
+    /// reported to the [Monitor](../progress/struct.Monitor.html).
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::EncodingError in case of an unsupported encoding
+    pub fn stream(&self, request: &Request) -> CabotResult<ResponseStream> {
+        let mut request = match self.netrc {
+            Some(ref netrc) => netrc.authenticate(request),
+            None => request.clone(),
+        };
+        if self.compressed && request.header("Accept-Encoding").is_none() {
+            request.add_header(&format!("Accept-Encoding: {}", constants::ACCEPT_ENCODING));
+        }
+        let stream = self.send_stream(&request)?;
+        let mut stream = match auth::digest_request(&request, stream.response())? {
+            Some(request) => self.send_stream(&request)?,
+            None => stream,
+        };
+        if self.compressed {
+            stream.decode_content()?;
+        }
+        Ok(stream)
+    }
+
+    fn send_stream(&self, request: &Request) -> CabotResult<ResponseStream> {
+        http::http_stream(request,
+                          self.verbose,
+                          &self.monitor,
+                          &*self.connector,
+                          self.tracer.as_ref())
+    }
+
+    fn execute_authenticated(&self, request: &Request) -> CabotResult<Response> {
+        let response = self.send(request)?;
+        match auth::digest_request(request, &response)? {
+            Some(request) => self.send(&request),
+            None => Ok(response),
+        }
+    }
+
+    fn send(&self, request: &Request) -> CabotResult<Response> {
+        let endpoint = |request: &Request| self.execute_once(request);
+        Next::new(&self.middlewares, &endpoint).run(request.clone())
+    }
+
+    fn execute_once(&self, request: &Request) -> CabotResult<Response> {
+        let mut parser = ResponseParser::new().set_http_method(request.http_method());
+        if let Some(ref handler) = self.interim_handler {
+            let handler = handler.clone();
+            parser = parser.set_interim_handler(move |response| handler(response));
+        }
+        let mut body = Vec::new();
+        let (mut response, raw_body) = {
+            let out = if self.compressed { Some(&mut body) } else { None };
+            let mut parser = DecodingParser::new(parser, out);
+            let timings = http::http_query_with_tracer(&request,
+                                                       &mut parser,
+                                                       self.verbose,
+                                                       &self.monitor,
+                                                       &*self.connector,
+                                                       self.tracer.as_ref())?;
+            let (mut response, raw_body) = parser.finish()?;
+            response.set_timings(timings);
+            (response, raw_body)
+        };
+        if let Some(raw_body) = raw_body {
+            response.set_decoded_body(raw_body, body);
+        }
+        Ok(response)
     }
 }
 
 
-struct CabotLibWrite {
-    response_builder: ResponseBuilder,
+/// Parse the response, and decode its body while it is received if there
+/// is an output for the decoded body.
+struct DecodingParser<'a> {
+    parser: ResponseParser,
+    out: Option<&'a mut Vec<u8>>,
+    content_encoding: String,
+    decoder: Option<Decoder<'a>>,
+    raw_body: Vec<u8>,
+    error: Option<io::Error>,
 }
 
 
-impl CabotLibWrite {
-    pub fn new() -> Self {
-        CabotLibWrite { response_builder: ResponseBuilder::new() }
+impl<'a> DecodingParser<'a> {
+    fn new(parser: ResponseParser, out: Option<&'a mut Vec<u8>>) -> Self {
+        DecodingParser {
+            parser: parser,
+            out: out,
+            content_encoding: String::new(),
+            decoder: None,
+            raw_body: Vec::new(),
+            error: None,
+        }
     }
 
-    pub fn response(&self) -> CabotResult<Response> {
-        self.response_builder.build()
+    /// Build the response, and return the body as received if it has been
+    /// decoded, `None` if the response has to be decoded.
+    fn finish(self) -> CabotResult<(Response, Option<Vec<u8>>)> {
+        let DecodingParser { parser, content_encoding, decoder, mut raw_body, error, .. } = self;
+        let response = parser.finish()?;
+        let mut decoder = match decoder {
+            Some(decoder) if response.body().is_some() => decoder,
+            _ => return Ok((response, None)),
+        };
+        // The end of the body has not been taken from the parser
+        let tail = response.body().unwrap();
+        raw_body.extend_from_slice(tail);
+        let decoded = match error {
+            Some(err) => Err(err),
+            None => decoder.write_all(tail).and_then(|_| decoder.finish()),
+        };
+        decoded.map_err(|err| {
+                CabotError::EncodingError(format!("Cannot decode {}: {}", content_encoding, err))
+            })?;
+        Ok((response, Some(raw_body)))
     }
 }
 
-impl Write for CabotLibWrite {
-    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        info!("Parsing http response");
-        let response: Vec<&[u8]> = constants::SPLIT_HEADERS_RE.splitn(buf, 2).collect();
-        let header_len = response.get(0).unwrap().len();
-        let headers_str = String::from_utf8_lossy(response.get(0).unwrap());
-        let mut headers: Vec<&str> = constants::SPLIT_HEADER_RE.split(&headers_str).collect();
-
-        let mut builder = ResponseBuilder::new();
-        let status_line = headers.remove(0);
-        debug!("Adding status line {}", status_line);
-        builder = builder.set_status_line(status_line);
-        let mut iter_header = headers.iter().peekable();
-        let header = iter_header.next();
-        if header.is_some() {
-            let buf = header.unwrap();
-            let mut header = String::with_capacity(buf.len() * 2);
-            header.push_str(buf);
-            loop {
-                {
-                    let buf = iter_header.peek();
-                    if buf.is_none() {
-                        builder = builder.add_header(header.as_str());
-                        break;
-                    }
-                    let buf = buf.unwrap();
-                    if buf.starts_with(" ") || buf.starts_with("\t") {
-                        debug!("Obsolete line folded header reveived in {}", header);
-                        header.push_str(" ");
-                        header.push_str(buf.trim_left());
-                    } else {
-                        debug!("Adding header {}", header);
-                        builder = builder.add_header(header.as_str());
-                        header.clear();
-                        header.push_str(buf);
-                    }
+
+impl<'a> Write for DecodingParser<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.parser.write(buf)?;
+        if self.out.is_some() && self.parser.is_head_complete() {
+            // Responses with an unsupported encoding are decoded once
+            // received, to report the error
+            let out = self.out.take().unwrap();
+            let head = self.parser.head().unwrap();
+            self.content_encoding = head.header_values("Content-Encoding").join(", ");
+            if !self.content_encoding.is_empty() {
+                self.decoder = Decoder::new(self.content_encoding.as_str(), out).ok();
+            }
+        }
+        if let Some(ref mut decoder) = self.decoder {
+            let body = self.parser.take_body();
+            if self.error.is_none() {
+                if let Err(err) = decoder.write_all(&body) {
+                    self.error = Some(err);
                 }
-                let _ = iter_header.next();
             }
+            self.raw_body.extend_from_slice(&body);
         }
-        let body = if (header_len + 4) < buf.len() {
-            &buf[(header_len + 4)..buf.len()]
-        } else {
-            &[]
-        };
-        // debug!("Adding body {:?}", body);
-        builder = builder.set_body(body);
-        self.response_builder = builder;
-        // debug!("Response Builder - {:?}", self.response_builder);
-        Ok(())
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
 
-    // Don't implemented unused method
+    use super::*;
+    use super::super::request::RequestBuilder;
+    use super::super::testing::{MockServer, MockResponse};
+    use super::super::transport::MockConnector;
 
-    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not Implemented"))
+    #[test]
+    fn test_execute_with_middleware() {
+        let connector = MockConnector::new()
+            .add_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOk");
+        let client = Client::new()
+            .set_connector(connector.clone())
+            .add_middleware(|mut request: Request, next: Next| {
+                request.add_header("X-Trace-Id: 42");
+                next.run(request)
+            });
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Ok");
+        let sent = String::from_utf8(connector.requests().remove(0)).unwrap();
+        assert!(sent.contains("\r\nX-Trace-Id: 42\r\n"));
     }
 
+    #[test]
+    fn test_upgrade() {
+        let connector = MockConnector::new()
+            .add_response(b"HTTP/1.1 101 Switching Protocols\r\n\
+                            Upgrade: echo\r\nConnection: Upgrade\r\n\r\nHello")
+            .add_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOk");
+        let client = Client::new().set_connector(connector.clone());
+        let request = RequestBuilder::new("http://localhost/")
+            .add_headers(&["Upgrade: echo", "Connection: Upgrade"])
+            .build()
+            .unwrap();
+        let mut upgraded = client.upgrade(&request).unwrap();
+        assert_eq!(upgraded.response().header("Upgrade"), Some("echo"));
+        let mut buf = String::new();
+        upgraded.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "Hello");
+        let sent = String::from_utf8(connector.requests().remove(0)).unwrap();
+        assert!(sent.contains("\r\nConnection: Upgrade\r\n"));
+        assert!(!sent.contains("Connection: close"));
 
-    fn write_fmt(&mut self, _: Arguments) -> io::Result<()> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not Implemented"))
+        match client.upgrade(&request) {
+            Err(CabotError::UpgradeError(message)) => {
+                assert_eq!(message, "The server answered 200 OK")
+            }
+            _ => panic!("The upgrade should fail"),
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_stream() {
+        let connector = MockConnector::new()
+            .add_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                            5\r\nHello\r\n7\r\n World!\r\n0\r\n\r\n");
+        let client = Client::new().set_connector(connector);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let mut stream = client.stream(&request).unwrap();
+        assert_eq!(stream.response().header("Transfer-Encoding"), Some("chunked"));
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "Hello World!");
+    }
+
+    fn gzip_response() -> Vec<u8> {
+        let raw: &[u8] = include_bytes!("../tests/fixtures/body.txt.gz");
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\
+                                    Content-Length: {}\r\n\r\n",
+                                   raw.len())
+            .into_bytes();
+        response.extend_from_slice(raw);
+        response
+    }
+
+    #[test]
+    fn test_execute_compressed() {
+        let connector = MockConnector::new()
+            .add_response(&gzip_response())
+            .add_response(b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\
+                            Content-Length: 2\r\n\r\nOk")
+            .add_response(b"HTTP/1.1 200 OK\r\nContent-Encoding: zstd\r\n\
+                            Content-Length: 2\r\n\r\nOk");
+        let client = Client::new().set_connector(connector.clone()).set_compressed(true);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).unwrap();
+        let body: &[u8] = include_bytes!("../tests/fixtures/body.txt");
+        let raw: &[u8] = include_bytes!("../tests/fixtures/body.txt.gz");
+        assert_eq!(response.body(), Some(body));
+        assert_eq!(response.raw_body(), Some(raw));
+        let sent = String::from_utf8(connector.requests().remove(0)).unwrap();
+        assert!(sent.contains("\r\nAccept-Encoding: "));
+        match client.execute(&request) {
+            Err(CabotError::EncodingError(_)) => {}
+            _ => panic!("The corrupted body should not be decoded"),
+        }
+        match client.execute(&request) {
+            Err(CabotError::EncodingError(_)) => {}
+            _ => panic!("The encoding should not be supported"),
+        }
+    }
+
+    #[test]
+    fn test_stream_compressed() {
+        let connector = MockConnector::new()
+            .add_response(&gzip_response())
+            .add_response(b"HTTP/1.1 304 Not Modified\r\nContent-Encoding: gzip\r\n\r\n")
+            .add_response(&gzip_response());
+        let client = Client::new().set_connector(connector.clone()).set_compressed(true);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let mut stream = client.stream(&request).unwrap();
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).unwrap();
+        assert_eq!(body.as_slice(), &include_bytes!("../tests/fixtures/body.txt")[..]);
+        let sent = String::from_utf8(connector.requests().remove(0)).unwrap();
+        assert!(sent.contains("\r\nAccept-Encoding: "));
+        let mut stream = client.stream(&request).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+        let client = Client::new().set_connector(connector);
+        let mut stream = client.stream(&request).unwrap();
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).unwrap();
+        assert_eq!(body.as_slice(), &include_bytes!("../tests/fixtures/body.txt.gz")[..]);
+    }
+
+    #[test]
+    fn test_stream_digest_challenge_progress() {
+        let connector = MockConnector::new()
+            .add_response(b"HTTP/1.1 401 Unauthorized\r\n\
+                            WWW-Authenticate: Digest realm=\"cabot\", nonce=\"abc\"\r\n\
+                            Content-Length: 0\r\n\
+                            \r\n")
+            .add_response(b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nHello World!");
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let monitor = {
+            let reports = reports.clone();
+            Monitor::new().set_callback(move |progress| {
+                reports.lock().unwrap().push((progress.downloaded(), progress.is_finished()))
+            })
+        };
+        let client = Client::new().set_connector(connector.clone()).set_monitor(monitor);
+        let request = RequestBuilder::new("http://localhost/")
+            .digest_auth("user", "password")
+            .build()
+            .unwrap();
+        let mut stream = client.stream(&request).unwrap();
+        assert_eq!(stream.response().status_code(), 200);
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "Hello World!");
+        assert_eq!(reports.lock().unwrap().last(), Some(&(12, true)));
+        let sent = String::from_utf8(connector.requests()[1].clone()).unwrap();
+        assert!(sent.contains("\r\nAuthorization: Digest username=\"user\""));
+    }
 
     #[test]
-    fn test_build_http_response_from_string() {
-        let response = vec!["HTTP/1.1 200 Ok",
-                            "Content-Type: text/plain",
-                            "Content-Length: 12",
-                            "",
-                            "Hello World!"];
-        let response = response.join("\r\n");
-
-        let mut out = CabotLibWrite::new();
-        out.write_all(response.as_bytes()).unwrap();
-        let response = out.response().unwrap();
-        assert_eq!(response.http_version(), "HTTP/1.1");
+    fn test_execute_digest_challenge() {
+        let connector = MockConnector::new()
+            .add_response(b"HTTP/1.1 401 Unauthorized\r\n\
+                            WWW-Authenticate: Digest realm=\"cabot\", \
+                            nonce=\"abc\", qop=\"auth\"\r\n\
+                            \r\n")
+            .add_response(b"HTTP/1.1 200 OK\r\n\r\n");
+        let client = Client::new().set_connector(connector.clone());
+        let request = RequestBuilder::new("http://localhost/")
+            .digest_auth("user", "password")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).unwrap();
         assert_eq!(response.status_code(), 200);
-        assert_eq!(response.status_line(), "200 Ok");
-        let headers: &[&str] = &["Content-Type: text/plain", "Content-Length: 12"];
-        assert_eq!(response.headers(), headers);
-        assert_eq!(response.body_as_string().unwrap(),
-                   "Hello World!".to_owned());
+        let requests = connector.requests();
+        assert_eq!(requests.len(), 2);
+        let sent = String::from_utf8(requests[1].clone()).unwrap();
+        assert!(sent.contains("\r\nAuthorization: Digest username=\"user\", realm=\"cabot\""));
+    }
+
+    #[test]
+    fn test_execute_digest_challenge_body_stream() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("401 Unauthorized")
+            .add_header("WWW-Authenticate: Digest realm=\"cabot\", nonce=\"abc\", qop=\"auth\""));
+        let request = RequestBuilder::new(&server.url("/"))
+            .set_http_method("PUT")
+            .set_body_stream(&b"Hello"[..], Some(5))
+            .digest_auth("user", "password")
+            .build()
+            .unwrap();
+        match Client::new().execute(&request) {
+            Err(CabotError::AuthenticationError(_)) => {}
+            other => panic!("Unexpected result {:?}", other.map(|response| response.status_code())),
+        }
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].body(), b"Hello");
+    }
 
+    #[test]
+    fn test_execute_read_timeout() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK")
+            .set_body(b"Ok")
+            .set_delay(Duration::from_secs(2)));
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let client = Client::new().set_connector(server.connector()
+            .set_read_timeout(Some(Duration::from_millis(200))));
+        match client.execute(&request) {
+            Err(CabotError::TimeoutError(_)) => {}
+            other => panic!("Unexpected result {:?}", other.map(|response| response.status_code())),
+        }
     }
 
     #[test]
-    fn test_build_http_header_obsolete_line_folding() {
-        let response = vec!["HTTP/1.1 200 Ok",
-                            "ows: https://tools.ietf.org/html/rfc7230",
-                            "  #section-3.2.4",
-                            "Content-Length: 12",
-                            "",
-                            "Hello World!"];
-        let response = response.join("\r\n");
-
-        let mut out = CabotLibWrite::new();
-        out.write_all(response.as_bytes()).unwrap();
-        let response = out.response().unwrap();
-        assert_eq!(response.http_version(), "HTTP/1.1");
+    fn test_execute_interim_handler() {
+        let connector = MockConnector::new()
+            .add_response(b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+                            HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOk");
+        let hints = Arc::new(Mutex::new(Vec::new()));
+        let handler_hints = hints.clone();
+        let client = Client::new()
+            .set_connector(connector)
+            .set_interim_handler(move |response: &Response| {
+                handler_hints.lock().unwrap().push(response.status_code())
+            });
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).unwrap();
         assert_eq!(response.status_code(), 200);
-        assert_eq!(response.status_line(), "200 Ok");
-        let headers: &[&str] = &["ows: https://tools.ietf.org/html/rfc7230 #section-3.2.4",
-                                 "Content-Length: 12"];
-        assert_eq!(response.headers(), headers);
-        assert_eq!(response.body_as_string().unwrap(),
-                   "Hello World!".to_owned());
+        assert_eq!(response.body_as_string().unwrap(), "Ok");
+        assert_eq!(*hints.lock().unwrap(), vec![103]);
+    }
 
+    #[test]
+    fn test_execute_head() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello World!"));
+        let request = RequestBuilder::new(&server.url("/"))
+            .set_http_method("HEAD")
+            .build()
+            .unwrap();
+        let response = Client::new().execute(&request).unwrap();
+        assert_eq!(response.header("Content-Length"), Some("12"));
+        assert_eq!(response.body(), None);
+        assert_eq!(server.requests()[0].http_method(), "HEAD");
     }
 
     #[test]
-    fn test_build_http_header_obsolete_line_folding_tab() {
-        let response = vec!["HTTP/1.1 200 Ok",
-                            "ows: https://tools.ietf.org/html/rfc7230",
-                            "\t#section-3.2.4",
-                            "Content-Length: 12",
-                            "",
-                            "Hello World!"];
-        let response = response.join("\r\n");
-
-        let mut out = CabotLibWrite::new();
-        out.write_all(response.as_bytes()).unwrap();
-        let response = out.response().unwrap();
-        assert_eq!(response.http_version(), "HTTP/1.1");
+    fn test_execute_expect_continue() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK")
+            .add_interim_response("100 Continue")
+            .set_body(b"Ok"));
+        let request = RequestBuilder::new(&server.url("/"))
+            .set_http_method("PUT")
+            .set_body(b"Hello")
+            .expect_continue(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        let response = Client::new().execute(&request).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
         assert_eq!(response.status_code(), 200);
-        assert_eq!(response.status_line(), "200 Ok");
-        let headers: &[&str] = &["ows: https://tools.ietf.org/html/rfc7230 #section-3.2.4",
-                                 "Content-Length: 12"];
-        assert_eq!(response.headers(), headers);
-        assert_eq!(response.body_as_string().unwrap(),
-                   "Hello World!".to_owned());
+        assert_eq!(response.body_as_string().unwrap(), "Ok");
+        let requests = server.requests();
+        assert_eq!(requests[0].header("Expect"), Some("100-continue"));
+        assert_eq!(requests[0].body(), b"Hello");
+    }
 
+    #[test]
+    fn test_execute_expect_continue_final_response() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("413 Payload Too Large").set_read_body(false));
+        let request = RequestBuilder::new(&server.url("/"))
+            .set_http_method("PUT")
+            .set_body_stream(&b"Hello"[..], Some(5))
+            .expect_continue(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let response = Client::new().execute(&request).unwrap();
+        assert_eq!(response.status_code(), 413);
+        assert_eq!(server.requests()[0].header("Expect"), Some("100-continue"));
+        // The body stream is not sent
+        assert!(!request.is_body_stream_sent());
     }
 
     #[test]
-    fn test_build_http_no_response_body() {
-        let response = vec!["HTTP/1.1 302 Moved",
-                            "Location: https://tools.ietf.org/html/rfc7230#section-3.3"];
-        let response = response.join("\r\n");
-
-        let mut out = CabotLibWrite::new();
-        out.write_all(response.as_bytes()).unwrap();
-        let response = out.response().unwrap();
-        assert_eq!(response.http_version(), "HTTP/1.1");
-        assert_eq!(response.status_code(), 302);
-        assert_eq!(response.status_line(), "302 Moved");
-        let headers: &[&str] = &["Location: https://tools.ietf.org/html/rfc7230#section-3.3"];
-        assert_eq!(response.headers(), headers);
-        assert_eq!(response.body_as_string().unwrap(), "");
+    fn test_execute_expect_continue_timeout() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("204 No Content"));
+        let request = RequestBuilder::new(&server.url("/"))
+            .set_http_method("PUT")
+            .set_body(b"Hello")
+            .expect_continue(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        let response = Client::new().execute(&request).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(response.status_code(), 204);
+        assert_eq!(server.requests()[0].body(), b"Hello");
     }
 }
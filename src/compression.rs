@@ -0,0 +1,411 @@
+//! Content codings of HTTP bodies.
+//!
+//! Response bodies encoded using `gzip`, `deflate` or `br`
+//! ([rfc7231](https://tools.ietf.org/html/rfc7231#section-3.1.2.2)) are
+//! decoded while they are written, or while they are read, stacked
+//! encodings are decoded in the reverse order of the `Content-Encoding`
+//! header.
+//!
+//! Request bodies can be compressed using `gzip` or `deflate`.
+//!
+//! # Example
+//! ```
+//! use cabot::compression;
+//!
+//! let body = compression::decode("identity", b"Hello World!").unwrap();
+//! assert_eq!(body, b"Hello World!");
+//! ```
+
+use std::io::{self, Read, Write};
+use std::mem;
+
+use brotli::{Decompressor, DecompressorWriter};
+use flate2::Compression;
+use flate2::read::{self, GzEncoder, ZlibEncoder};
+use flate2::write::{GzDecoder, ZlibDecoder, DeflateDecoder};
+
+use super::results::{CabotResult, CabotError};
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+
+/// Content codings used to compress request bodies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    /// The gzip file format.
+    Gzip,
+    /// The zlib data format, named deflate in HTTP.
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// The name of the coding, used in the `Content-Encoding` header.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    /// Compress a stream while it is read.
+    pub fn encoder<'a, R: Read + Send + 'a>(&self, reader: R) -> Box<Read + Send + 'a> {
+        match *self {
+            ContentEncoding::Gzip => Box::new(GzEncoder::new(reader, Compression::default())),
+            ContentEncoding::Deflate => Box::new(ZlibEncoder::new(reader, Compression::default())),
+        }
+    }
+
+    /// Compress a complete body.
+    pub fn encode(&self, body: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(body.len() / 2);
+        // Reading from a slice never fails
+        self.encoder(body).read_to_end(&mut encoded).unwrap();
+        encoded
+    }
+}
+
+
+enum Codec<'a> {
+    Output(&'a mut Write),
+    Gzip(GzDecoder<Box<Codec<'a>>>),
+    Zlib(ZlibDecoder<Box<Codec<'a>>>),
+    Deflate(DeflateDecoder<Box<Codec<'a>>>),
+    Brotli(DecompressorWriter<Box<Codec<'a>>>),
+    // The deflate coding is a zlib stream, but some servers send a raw
+    // deflate stream, the first bytes are buffered to detect it.
+    DeflateDetect(Vec<u8>, Option<Box<Codec<'a>>>),
+}
+
+
+impl<'a> Codec<'a> {
+    fn detect(&mut self, buf: &[u8]) -> io::Result<()> {
+        let (codec, pending) = match *self {
+            Codec::DeflateDetect(ref mut pending, ref mut out) => {
+                pending.extend_from_slice(buf);
+                if pending.len() < 2 {
+                    return Ok(());
+                }
+                let out = out.take().unwrap();
+                let header = (pending[0] as u16) << 8 | pending[1] as u16;
+                let codec = if pending[0] & 0x0f == 8 && header % 31 == 0 {
+                    debug!("Decoding deflate content as a zlib stream");
+                    Codec::Zlib(ZlibDecoder::new(out))
+                } else {
+                    debug!("Decoding deflate content as a raw deflate stream");
+                    Codec::Deflate(DeflateDecoder::new(out))
+                };
+                (codec, mem::replace(pending, Vec::new()))
+            }
+            _ => unreachable!(),
+        };
+        *self = codec;
+        self.write_all(pending.as_slice())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Codec::Output(out) => out.flush(),
+            Codec::Gzip(decoder) => (*decoder.finish()?).finish(),
+            Codec::Zlib(decoder) => (*decoder.finish()?).finish(),
+            Codec::Deflate(decoder) => (*decoder.finish()?).finish(),
+            Codec::Brotli(mut decoder) => {
+                decoder.close()?;
+                match decoder.into_inner() {
+                    Ok(out) => (*out).finish(),
+                    Err(_) => {
+                        Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated brotli stream"))
+                    }
+                }
+            }
+            Codec::DeflateDetect(pending, out) => {
+                if pending.is_empty() {
+                    (*out.unwrap()).finish()
+                } else {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated deflate stream"))
+                }
+            }
+        }
+    }
+}
+
+
+impl<'a> Write for Codec<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Codec::Output(ref mut out) => out.write(buf),
+            Codec::Gzip(ref mut decoder) => decoder.write(buf),
+            Codec::Zlib(ref mut decoder) => decoder.write(buf),
+            Codec::Deflate(ref mut decoder) => decoder.write(buf),
+            Codec::Brotli(ref mut decoder) => decoder.write(buf),
+            Codec::DeflateDetect(_, _) => {
+                self.detect(buf)?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Codec::Output(ref mut out) => out.flush(),
+            Codec::Gzip(ref mut decoder) => decoder.flush(),
+            Codec::Zlib(ref mut decoder) => decoder.flush(),
+            Codec::Deflate(ref mut decoder) => decoder.flush(),
+            Codec::Brotli(ref mut decoder) => decoder.flush(),
+            Codec::DeflateDetect(_, _) => Ok(()),
+        }
+    }
+}
+
+
+/// Decode a body while it is written, the decoded body is written to the
+/// output.
+///
+/// The decoder must be finished to write the end of the body and to
+/// detect truncated bodies.
+pub struct Decoder<'a> {
+    codec: Codec<'a>,
+}
+
+
+impl<'a> Decoder<'a> {
+    /// Create a decoder for the value of a `Content-Encoding` header.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::EncodingError in case of an unsupported encoding
+    pub fn new(content_encoding: &str, out: &'a mut Write) -> CabotResult<Self> {
+        let mut codec = Codec::Output(out);
+        // The last applied coding is the first to decode
+        for coding in content_encoding.split(',').map(|s| s.trim().to_lowercase()) {
+            codec = match coding.as_str() {
+                "" | "identity" => codec,
+                "gzip" | "x-gzip" => Codec::Gzip(GzDecoder::new(Box::new(codec))),
+                "deflate" => Codec::DeflateDetect(Vec::with_capacity(2), Some(Box::new(codec))),
+                "br" => {
+                    Codec::Brotli(DecompressorWriter::new(Box::new(codec), BROTLI_BUFFER_SIZE))
+                }
+                _ => {
+                    return Err(CabotError::EncodingError(format!("Unsupported Content-Encoding: \
+                                                                  {}",
+                                                                 coding)))
+                }
+            };
+        }
+        Ok(Decoder { codec: codec })
+    }
+
+    /// Write the end of the decoded body.
+    pub fn finish(self) -> io::Result<()> {
+        self.codec.finish()
+    }
+}
+
+
+impl<'a> Write for Decoder<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.codec.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.codec.flush()
+    }
+}
+
+
+/// Decode a body while it is read, for the value of a `Content-Encoding`
+/// header.
+///
+/// Errors:
+///
+///  - CabotError::EncodingError in case of an unsupported encoding
+pub fn decoder<'a, R: Read + Send + 'a>(content_encoding: &str,
+                                         reader: R)
+                                         -> CabotResult<Box<Read + Send + 'a>> {
+    let mut reader: Box<Read + Send + 'a> = Box::new(reader);
+    // The last applied coding is the first to decode, it reads the body
+    for coding in content_encoding.split(',').rev().map(|s| s.trim().to_lowercase()) {
+        reader = match coding.as_str() {
+            "" | "identity" => reader,
+            "gzip" | "x-gzip" => Box::new(read::GzDecoder::new(reader)),
+            "deflate" => Box::new(DeflateReader::Detect(reader)),
+            "br" => Box::new(Decompressor::new(reader, BROTLI_BUFFER_SIZE)),
+            _ => {
+                return Err(CabotError::EncodingError(format!("Unsupported Content-Encoding: {}",
+                                                             coding)))
+            }
+        };
+    }
+    Ok(reader)
+}
+
+
+/// Decode the deflate coding while it is read, a zlib stream or a raw
+/// deflate stream is detected from the first bytes read.
+enum DeflateReader<'a> {
+    Detect(Box<Read + Send + 'a>),
+    Decode(Box<Read + Send + 'a>),
+    // The detection has failed
+    Empty,
+}
+
+
+impl<'a> Read for DeflateReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let DeflateReader::Decode(ref mut decoder) = *self {
+            return decoder.read(buf);
+        }
+        let mut reader = match mem::replace(self, DeflateReader::Empty) {
+            DeflateReader::Detect(reader) => reader,
+            _ => return Ok(0),
+        };
+        let mut pending = Vec::with_capacity(2);
+        (&mut reader).take(2).read_to_end(&mut pending)?;
+        let decoder: Box<Read + Send + 'a> = match pending.len() {
+            0 => return Ok(0),
+            1 => return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated deflate stream")),
+            _ => {
+                let header = (pending[0] as u16) << 8 | pending[1] as u16;
+                let zlib = pending[0] & 0x0f == 8 && header % 31 == 0;
+                let reader = io::Cursor::new(pending).chain(reader);
+                if zlib {
+                    debug!("Decoding deflate content as a zlib stream");
+                    Box::new(read::ZlibDecoder::new(reader))
+                } else {
+                    debug!("Decoding deflate content as a raw deflate stream");
+                    Box::new(read::DeflateDecoder::new(reader))
+                }
+            }
+        };
+        *self = DeflateReader::Decode(decoder);
+        self.read(buf)
+    }
+}
+
+
+/// Decode a complete body.
+///
+/// Errors:
+///
+///  - CabotError::EncodingError in case of an unsupported encoding or a
+///    corrupted body.
+pub fn decode(content_encoding: &str, body: &[u8]) -> CabotResult<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(body.len() * 2);
+    {
+        let mut decoder = Decoder::new(content_encoding, &mut decoded)?;
+        decoder.write_all(body)
+            .and_then(|_| decoder.finish())
+            .map_err(|err| {
+                CabotError::EncodingError(format!("Cannot decode {}: {}", content_encoding, err))
+            })?;
+    }
+    Ok(decoded)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &'static [u8] = include_bytes!("../tests/fixtures/body.txt");
+
+    #[test]
+    fn test_decode_gzip() {
+        let body = decode("gzip", include_bytes!("../tests/fixtures/body.txt.gz")).unwrap();
+        assert_eq!(body.as_slice(), BODY);
+    }
+
+    #[test]
+    fn test_decode_zlib_deflate() {
+        let body = decode("deflate", include_bytes!("../tests/fixtures/body.txt.zz")).unwrap();
+        assert_eq!(body.as_slice(), BODY);
+    }
+
+    #[test]
+    fn test_decode_raw_deflate() {
+        let body = decode("deflate", include_bytes!("../tests/fixtures/body.txt.deflate"))
+            .unwrap();
+        assert_eq!(body.as_slice(), BODY);
+    }
+
+    #[test]
+    fn test_decode_brotli() {
+        let body = decode("br", include_bytes!("../tests/fixtures/body.txt.br")).unwrap();
+        assert_eq!(body.as_slice(), BODY);
+    }
+
+    #[test]
+    fn test_decode_stacked() {
+        // gzip has been applied, then br
+        let body = decode("gzip, br", include_bytes!("../tests/fixtures/body.txt.gz.br"))
+            .unwrap();
+        assert_eq!(body.as_slice(), BODY);
+    }
+
+    #[test]
+    fn test_decode_by_chunks() {
+        let mut decoded = Vec::new();
+        {
+            let mut decoder = Decoder::new("GZIP", &mut decoded).unwrap();
+            for chunk in include_bytes!("../tests/fixtures/body.txt.gz").chunks(7) {
+                decoder.write_all(chunk).unwrap();
+            }
+            decoder.finish().unwrap();
+        }
+        assert_eq!(decoded.as_slice(), BODY);
+    }
+
+    #[test]
+    fn test_decoder() {
+        let cases: [(&str, &[u8]); 5] =
+            [("gzip", include_bytes!("../tests/fixtures/body.txt.gz")),
+             ("deflate", include_bytes!("../tests/fixtures/body.txt.zz")),
+             ("deflate", include_bytes!("../tests/fixtures/body.txt.deflate")),
+             ("br", include_bytes!("../tests/fixtures/body.txt.br")),
+             ("gzip, br", include_bytes!("../tests/fixtures/body.txt.gz.br"))];
+        for &(content_encoding, body) in cases.iter() {
+            let mut decoded = Vec::new();
+            decoder(content_encoding, body).unwrap().read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded.as_slice(), BODY, "{}", content_encoding);
+        }
+        assert!(decoder("compress", &b""[..]).is_err());
+    }
+
+    #[test]
+    fn test_decoder_truncated() {
+        let gzip = include_bytes!("../tests/fixtures/body.txt.gz");
+        let mut decoded = Vec::new();
+        assert!(decoder("gzip", &gzip[..gzip.len() - 10])
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .is_err());
+        let brotli = include_bytes!("../tests/fixtures/body.txt.br");
+        assert!(decoder("br", &brotli[..brotli.len() - 10])
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let gzip = include_bytes!("../tests/fixtures/body.txt.gz");
+        let body = decode("gzip", &gzip[..gzip.len() - 10]);
+        assert!(body.is_err());
+        let brotli = include_bytes!("../tests/fixtures/body.txt.br");
+        let body = decode("br", &brotli[..brotli.len() - 10]);
+        assert!(body.is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let gzip = ContentEncoding::Gzip.encode(BODY);
+        assert!(gzip.len() < BODY.len());
+        assert_eq!(decode("gzip", gzip.as_slice()).unwrap().as_slice(), BODY);
+        let deflate = ContentEncoding::Deflate.encode(BODY);
+        assert_eq!(decode("deflate", deflate.as_slice()).unwrap().as_slice(), BODY);
+    }
+
+    #[test]
+    fn test_decode_unsupported() {
+        assert!(decode("compress", b"").is_err());
+    }
+}
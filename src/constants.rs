@@ -12,6 +12,9 @@ pub const VERSION: &'static str = "0.1.3";
 /// Default user agent `cabot/{cabot-version}`
 pub const USER_AGENT: &'static str = "cabot/0.1.3";
 
+/// Content codings decoded by cabot, sent in the `Accept-Encoding` header
+pub const ACCEPT_ENCODING: &'static str = "gzip, deflate, br";
+
 
 lazy_static! {
     pub static ref SPLIT_HEADERS_RE: BytesRegex = BytesRegex::new("\r?\n\r?\n").unwrap();
@@ -0,0 +1,124 @@
+//! HTTP dates
+//! ([rfc7231](https://tools.ietf.org/html/rfc7231#section-7.1.1.1)).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&'static str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug",
+                                    "Sep", "Oct", "Nov", "Dec"];
+
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+
+// Date of the proleptic Gregorian calendar of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+
+/// Format the time as an IMF-fixdate, `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs() as i64;
+    let days = secs / 86400;
+    let secs = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[(days % 7) as usize],
+            day,
+            MONTHS[(month - 1) as usize],
+            year,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60)
+}
+
+
+/// Parse an HTTP date, the IMF-fixdate format and the obsolete rfc850
+/// and asctime formats are accepted.
+pub fn parse(date: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = date.split(|c| c == ' ' || c == ',' || c == '-')
+        .filter(|part| !part.is_empty())
+        .collect();
+    let (day, month, year, time) = match parts.len() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        6 => (parts[1], parts[2], parts[3], parts[4]),
+        // Sun Nov  6 08:49:37 1994
+        5 => (parts[2], parts[1], parts[4], parts[3]),
+        _ => return None,
+    };
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as i64 + 1;
+    let mut year: i64 = year.parse().ok()?;
+    if year < 100 {
+        // rfc850 two digits years
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+    if year < 1601 || year > 9999 {
+        // The seconds since the epoch of the date can't overflow
+        return None;
+    }
+    let time: Vec<i64> = time.split(':').filter_map(|part| part.parse().ok()).collect();
+    if time.len() != 3 || day < 1 || day > 31 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + time[0] * 3600 + time[1] * 60 +
+               time[2];
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let time = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(format(time), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_parse() {
+        let time = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), time);
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), time);
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), time);
+        assert_eq!(parse("Tue, 29 Feb 2000 00:00:00 GMT"),
+                   Some(UNIX_EPOCH + Duration::from_secs(951782400)));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse("0"), None);
+        assert_eq!(parse("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 99999999999999999 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 10000 08:49:37 GMT"), None);
+    }
+}
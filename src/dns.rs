@@ -4,6 +4,8 @@ use std::io::{Write, stderr};
 use std::net::{ToSocketAddrs, SocketAddr};
 use log::LogLevel::Info;
 
+use super::results::{CabotResult, CabotError};
+
 pub struct Resolver {
     verbose: bool,
 }
@@ -12,11 +14,12 @@ impl Resolver {
     pub fn new(verbose: bool) -> Self {
         Resolver { verbose: verbose }
     }
-    pub fn get_addr(&self, authority: &str) -> SocketAddr {
+    pub fn get_addr(&self, authority: &str) -> CabotResult<SocketAddr> {
         debug!("Resolving TCP Endpoint for authority {}", authority);
         let addr = authority.to_socket_addrs()
-            .unwrap() // unwrap result
-            .next().unwrap(); // get first item from iterator
+            .map_err(|err| CabotError::DnsError(format!("{}: {}", authority, err)))?
+            .next() // get first item from iterator
+            .ok_or_else(|| CabotError::DnsError(format!("{}: No address found", authority)))?;
         if log_enabled!(Info) {
             info!("Authority {} has been resolved to {}", authority, addr);
         } else if self.verbose {
@@ -26,6 +29,6 @@ impl Resolver {
                      addr)
                 .unwrap();
         }
-        addr
+        Ok(addr)
     }
 }
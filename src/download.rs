@@ -0,0 +1,439 @@
+//! Parallel segmented downloads.
+//!
+//! The content is probed using a `HEAD` request, if the server accepts
+//! byte ranges the content is split in segments fetched on separate
+//! connections, from separate threads, and written at their offset in the
+//! output file. Otherwise the content is downloaded using a single request.
+//!
+//! # Example
+//! ```no_run
+//! use cabot::RequestBuilder;
+//! use cabot::download::Downloader;
+//!
+//! let request = RequestBuilder::new("https://static.rust-lang.org/dist/rust-1.19.0.tar.gz")
+//!     .build()
+//!     .unwrap();
+//! let length = Downloader::new()
+//!     .set_segments(4)
+//!     .download(&request, "rust-1.19.0.tar.gz")
+//!     .unwrap();
+//! println!("{} bytes downloaded", length);
+//! ```
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::client::Client;
+use super::request::Request;
+use super::response::Response;
+use super::results::{CabotResult, CabotError};
+use super::transport::{Connector, TcpConnector};
+
+/// The maximum number of segments downloaded in parallel.
+pub const MAX_SEGMENTS: u64 = 64;
+
+
+/// Download a content to a file, using many connections.
+pub struct Downloader {
+    segments: u64,
+    verbose: bool,
+    connector: Arc<Connector>,
+}
+
+
+impl Downloader {
+    /// Construct a new `Downloader`, using a single segment.
+    pub fn new() -> Self {
+        Downloader {
+            segments: 1,
+            verbose: false,
+            connector: Arc::new(TcpConnector::new()),
+        }
+    }
+
+    /// Set the number of segments downloaded in parallel, between 1 and
+    /// [MAX_SEGMENTS](constant.MAX_SEGMENTS.html).
+    pub fn set_segments(mut self, segments: u64) -> Self {
+        self.segments = cmp::max(1, cmp::min(segments, MAX_SEGMENTS));
+        self
+    }
+
+    /// Display the requests and the responses headers on stderr.
+    pub fn set_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Open the connections using the
+    /// [Connector](../transport/trait.Connector.html).
+    pub fn set_connector<C: Connector + 'static>(mut self, connector: C) -> Self {
+        self.connector = Arc::new(connector);
+        self
+    }
+
+    /// Download the content of the `GET` request to the file,
+    /// and return its length.
+    ///
+    /// The bodies are written to the file as they are received, using as
+    /// many connections as segments. A `Range` header of the request is
+    /// ignored. The segments being downloaded are completed before an
+    /// error is returned.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::WriteError in case the file can't be written.
+    ///  - CabotError::IOError in case a segment can't be fetched.
+    ///  - CabotError::TimeoutError in case a response is not received in
+    ///    time.
+    ///  - CabotError::HttpResponseParseError in case a response is invalid.
+    ///  - CabotError::StatusError in case the content is downloaded using a
+    ///    single request, and the response status is not successful.
+    ///  - CabotError::RangeError in case a segment doesn't match its range.
+    pub fn download<P: AsRef<Path>>(&self, request: &Request, path: P) -> CabotResult<u64> {
+        let path = path.as_ref();
+        // The whole content is downloaded
+        let mut request = request.clone();
+        request.remove_header("Range");
+        request.remove_header("If-Range");
+        let length = if self.segments > 1 && request.http_method() == "GET" {
+            let mut head = request.clone();
+            head.set_http_method("HEAD");
+            let response = self.client().execute(&head)?;
+            accept_ranges(&response)
+        } else {
+            None
+        };
+        let (length, etag) = match length {
+            Some((length, etag)) if length > 0 => (length, etag),
+            _ => {
+                debug!("Downloading {} using a single request", path.display());
+                let mut stream = self.client().stream(&request)?;
+                let status_code = stream.response().status_code();
+                if status_code < 200 || status_code >= 300 {
+                    let response = stream.response();
+                    return Err(CabotError::StatusError(format!("Cannot download {}: {} {}",
+                                                               path.display(),
+                                                               response.http_version(),
+                                                               response.status_line())));
+                }
+                let mut file = File::create(path).map_err(|err| write_error(path, err))?;
+                return copy(&mut stream, &mut file, path);
+            }
+        };
+
+        File::create(path)
+            .and_then(|file| file.set_len(length))
+            .map_err(|err| write_error(path, err))?;
+        // The workers download the segments left, until one of them fails
+        let ranges = segments(length, self.segments);
+        let count = ranges.len();
+        let ranges = Arc::new(Mutex::new(ranges));
+        let workers: Vec<_> = (0..count)
+            .map(|_| {
+                let ranges = ranges.clone();
+                let client = self.client();
+                let request = request.clone();
+                let etag = etag.clone();
+                let path = path.to_path_buf();
+                thread::spawn(move || loop {
+                    let range = ranges.lock().unwrap().pop();
+                    let (first, last) = match range {
+                        Some(range) => range,
+                        None => return Ok(()),
+                    };
+                    let mut request = request.clone();
+                    request.add_header(&format!("Range: bytes={}-{}", first, last));
+                    if let Some(ref etag) = etag {
+                        // Segments of a modified content are not mixed
+                        request.add_header(&format!("If-Range: {}", etag));
+                    }
+                    if let Err(err) = download_segment(&client, &request, &path, first, last) {
+                        ranges.lock().unwrap().clear();
+                        return Err(err);
+                    }
+                })
+            })
+            .collect();
+        // The segments cover the content, once they have all been written
+        let results: Vec<_> = workers.into_iter()
+            .map(|worker| {
+                worker.join()
+                    .unwrap_or(Err(CabotError::IOError("Segment download panicked".to_owned())))
+            })
+            .collect();
+        for result in results {
+            result?;
+        }
+        Ok(length)
+    }
+
+    fn client(&self) -> Client {
+        Client::new()
+            .set_verbose(self.verbose)
+            .set_connector(self.connector.clone())
+    }
+}
+
+
+/// The content length and the strong `ETag` of a response, if the server
+/// accepts byte ranges.
+fn accept_ranges(response: &Response) -> Option<(u64, Option<String>)> {
+    if response.status_code() < 200 || response.status_code() >= 300 {
+        return None;
+    }
+    let units = response.header_values("Accept-Ranges");
+    if !units.iter().any(|unit| unit.eq_ignore_ascii_case("bytes")) {
+        return None;
+    }
+    let length = response.header("Content-Length").and_then(|length| length.parse().ok())?;
+    let etag = match response.header("ETag") {
+        Some(etag) if !etag.starts_with("W/") => Some(etag.to_owned()),
+        _ => None,
+    };
+    Some((length, etag))
+}
+
+
+/// Split the content in ranges of bytes, the last byte is included. A
+/// range has one byte at least.
+fn segments(length: u64, count: u64) -> Vec<(u64, u64)> {
+    let count = cmp::min(count, length);
+    if count == 0 {
+        return Vec::new();
+    }
+    let size = length / count + if length % count > 0 { 1 } else { 0 };
+    (0..count)
+        .map(|i| i.saturating_mul(size))
+        .filter(|first| *first < length)
+        .map(|first| (first, cmp::min(first.saturating_add(size), length) - 1))
+        .collect()
+}
+
+
+/// Write the bytes of the range to the file, at their offset.
+fn download_segment(client: &Client,
+                    request: &Request,
+                    path: &Path,
+                    first: u64,
+                    last: u64)
+                    -> CabotResult<()> {
+    debug!("Downloading bytes {}-{} of {}", first, last, path.display());
+    let mut stream = client.stream(request)?;
+    {
+        let response = stream.response();
+        let range = response.content_range().and_then(|range| range.range());
+        if response.status_code() != 206 || range != Some((first, last)) {
+            return Err(CabotError::RangeError(format!("Unexpected response for bytes {}-{}: {} {}",
+                                                      first,
+                                                      last,
+                                                      response.status_line(),
+                                                      response.header("Content-Range")
+                                                          .unwrap_or(""))));
+        }
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|mut file| file.seek(SeekFrom::Start(first)).map(|_| file))
+        .map_err(|err| write_error(path, err))?;
+    // The bytes after the range are not written over the next segment
+    let length = last - first + 1;
+    let written = copy(&mut (&mut stream).take(length), &mut file, path)?;
+    let extra = stream.read(&mut [0]).map_err(read_error)?;
+    if written != length || extra > 0 {
+        return Err(CabotError::RangeError(format!("Unexpected body length for bytes {}-{}",
+                                                  first,
+                                                  last)));
+    }
+    Ok(())
+}
+
+
+/// Write the body to the file as it is received, and return its length.
+fn copy<R: Read>(body: &mut R, file: &mut File, path: &Path) -> CabotResult<u64> {
+    let mut buf = [0; 8192];
+    let mut written = 0;
+    loop {
+        let count = body.read(&mut buf).map_err(read_error)?;
+        if count == 0 {
+            return Ok(written);
+        }
+        file.write_all(&buf[..count]).map_err(|err| write_error(path, err))?;
+        written += count as u64;
+    }
+}
+
+
+fn read_error(err: io::Error) -> CabotError {
+    match err.kind() {
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::WouldBlock => CabotError::TimeoutError(format!("{}", err)),
+        io::ErrorKind::InvalidData |
+        io::ErrorKind::UnexpectedEof => CabotError::HttpResponseParseError(format!("{}", err)),
+        _ => CabotError::IOError(format!("{}", err)),
+    }
+}
+
+
+fn write_error(path: &Path, err: io::Error) -> CabotError {
+    CabotError::WriteError(format!("{}: {}", path.display(), err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    use super::*;
+    use super::super::request::RequestBuilder;
+    use super::super::response::ResponseBuilder;
+    use super::super::testing::{MockServer, MockResponse};
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir().join(format!("cabot-download-{}", ::rand::random::<u32>()))
+    }
+
+    fn read(path: &Path) -> Vec<u8> {
+        let mut content = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    fn content(etag: &str) -> MockResponse {
+        MockResponse::new("200 OK")
+            .add_header(&format!("ETag: {}", etag))
+            .set_body(b"Hello World!")
+            .set_accept_ranges(true)
+    }
+
+    #[test]
+    fn test_download_segments() {
+        let server = MockServer::start();
+        for _ in 0..4 {
+            server.add_response(content("\"v1\""));
+        }
+        // The range of the request is replaced by the ranges of the segments
+        let request = RequestBuilder::new(&server.url("/file")).range(0, Some(1)).build().unwrap();
+        let path = temp_path();
+        let length = Downloader::new().set_segments(3).download(&request, &path).unwrap();
+        assert_eq!(length, 12);
+        assert_eq!(read(&path), b"Hello World!");
+        fs::remove_file(&path).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].http_method(), "HEAD");
+        assert_eq!(requests[0].header("Range"), None);
+        let mut ranges: Vec<_> = requests[1..]
+            .iter()
+            .map(|request| {
+                assert_eq!(request.http_method(), "GET");
+                assert_eq!(request.header("If-Range"), Some("\"v1\""));
+                let headers = request.headers();
+                assert_eq!(headers.iter().filter(|header| header.starts_with("Range:")).count(),
+                           1);
+                request.header("Range").unwrap().to_owned()
+            })
+            .collect();
+        ranges.sort();
+        assert_eq!(ranges, vec!["bytes=0-3", "bytes=4-7", "bytes=8-11"]);
+    }
+
+    #[test]
+    fn test_download_modified_segment() {
+        let server = MockServer::start();
+        server.add_response(content("\"v1\""));
+        for _ in 0..2 {
+            server.add_response(content("\"v2\""));
+        }
+        let request = RequestBuilder::new(&server.url("/file")).build().unwrap();
+        let path = temp_path();
+        match Downloader::new().set_segments(2).download(&request, &path) {
+            Err(CabotError::RangeError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_download_single_request() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello World!"));
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello World!"));
+        let request = RequestBuilder::new(&server.url("/file")).build().unwrap();
+        let path = temp_path();
+        let length = Downloader::new().set_segments(3).download(&request, &path).unwrap();
+        assert_eq!(length, 12);
+        assert_eq!(read(&path), b"Hello World!");
+        fs::remove_file(&path).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].http_method(), "HEAD");
+        assert_eq!(requests[1].http_method(), "GET");
+        assert_eq!(requests[1].header("Range"), None);
+    }
+
+    #[test]
+    fn test_download_single_request_error() {
+        let server = MockServer::start();
+        let request = RequestBuilder::new(&server.url("/file")).build().unwrap();
+        let path = temp_path();
+        match Downloader::new().download(&request, &path) {
+            Err(CabotError::StatusError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(!path.exists());
+        assert_eq!(server.requests()[0].http_method(), "GET");
+    }
+
+    #[test]
+    fn test_segments() {
+        assert_eq!(segments(10, 1), vec![(0, 9)]);
+        assert_eq!(segments(10, 3), vec![(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(segments(10, 5), vec![(0, 1), (2, 3), (4, 5), (6, 7), (8, 9)]);
+        assert_eq!(segments(2, 4), vec![(0, 0), (1, 1)]);
+        assert_eq!(segments(3, u64::max_value()), vec![(0, 0), (1, 1), (2, 2)]);
+        let half = u64::max_value() / 2;
+        assert_eq!(segments(u64::max_value(), 2),
+                   vec![(0, half), (half + 1, u64::max_value() - 1)]);
+        assert_eq!(segments(0, 4), vec![]);
+    }
+
+    #[test]
+    fn test_accept_ranges() {
+        let response = ResponseBuilder::new()
+            .set_status_line("HTTP/1.1 200 OK")
+            .add_header("Accept-Ranges: bytes")
+            .add_header("Content-Length: 1234")
+            .add_header("ETag: \"v1\"")
+            .build()
+            .unwrap();
+        assert_eq!(accept_ranges(&response), Some((1234, Some("\"v1\"".to_owned()))));
+
+        let response = ResponseBuilder::new()
+            .set_status_line("HTTP/1.1 200 OK")
+            .add_header("Accept-Ranges: bytes")
+            .add_header("Content-Length: 1234")
+            .add_header("ETag: W/\"v1\"")
+            .build()
+            .unwrap();
+        assert_eq!(accept_ranges(&response), Some((1234, None)));
+
+        let response = ResponseBuilder::new()
+            .set_status_line("HTTP/1.1 200 OK")
+            .add_header("Accept-Ranges: none")
+            .add_header("Content-Length: 1234")
+            .build()
+            .unwrap();
+        assert_eq!(accept_ranges(&response), None);
+    }
+}
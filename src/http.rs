@@ -1,24 +1,27 @@
 //! Low level and internal http and https implementation.
 
 use std::sync::Arc;
-use std::time::Duration;
-use std::io::{Read, Write, stderr};
-use std::net::TcpStream;
+use std::io::{self, Read, Write, stderr};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use rustls::{Session, ClientConfig, ClientSession, ProtocolVersion};
+use rustls::{Session, Certificate, ClientConfig, ClientSession, ProtocolVersion};
 use webpki_roots;
 use log::LogLevel::Info;
 
+use super::parser::ResponseParser;
 use super::request::Request;
 use super::results::{CabotResult, CabotError};
-use super::dns::Resolver;
+use super::progress::{Monitor, Transfer};
+use super::timings::{Timer, Timings};
+use super::trace::{TracedStream, Tracer};
+use super::transport::{Connector, ResponseStream, Stream, TcpConnector, Upgraded};
 use super::constants;
 
 const BUFFER_PAGE_SIZE: usize = 1024;
-const RESPONSE_BUFFER_SIZE: usize = 1024;
 
 
-fn log_request(request: &[u8], verbose: bool) {
+pub(crate) fn log_request(request: &[u8], verbose: bool) {
     if !log_enabled!(Info) && !verbose {
         return;
     }
@@ -51,152 +54,500 @@ fn log_request(request: &[u8], verbose: bool) {
 }
 
 
-fn read_buf<T>(mut client: &mut T, mut buf: &mut [u8]) -> Vec<u8>
-    where T: Read + Sized
-{
-    let mut response: Vec<u8> = Vec::with_capacity(RESPONSE_BUFFER_SIZE);
-    loop {
-        match client.read(&mut buf[..]) {
-            Ok(count) => {
-                if count > 0 {
-                    response.extend_from_slice(&buf[0..count]);
-                } else {
-                    break;
+/// A TLS session over a stream.
+pub(crate) struct TlsStream {
+    session: ClientSession,
+    sock: Box<Stream>,
+}
+
+impl TlsStream {
+    /// Establish the TLS session with the host of the request.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::CertificateError in case the handshake fails
+    ///  - CabotError::IOError in case the connection is closed
+    pub(crate) fn connect(request: &Request,
+                          sock: Box<Stream>,
+                          root_certificates: &[Vec<u8>],
+                          timer: &mut Timer)
+                          -> CabotResult<Self> {
+        let mut stream = TlsStream {
+            session: tls_session(request, root_certificates)?,
+            sock: sock,
+        };
+        while stream.session.is_handshaking() {
+            while stream.session.wants_write() {
+                let count = stream.session.write_tls(&mut stream.sock).map_err(stream_error)?;
+                debug!("Write {} TLS bytes", count);
+            }
+            if stream.session.is_handshaking() && stream.session.wants_read() {
+                let count = stream.session.read_tls(&mut stream.sock).map_err(stream_error)?;
+                debug!("Read {} TLS bytes", count);
+                if count == 0 {
+                    return Err(CabotError::IOError("Connection closed during the TLS handshake"
+                        .to_owned()));
                 }
+                stream.session
+                    .process_new_packets()
+                    .map_err(|err| CabotError::CertificateError(format!("{}", err)))?;
+            }
+        }
+        info!("Handshake complete");
+        timer.tls_handshake();
+        info!("Protocol {} negociated", stream.protocol());
+        Ok(stream)
+    }
+
+    /// The name of the protocol negociated.
+    fn protocol(&self) -> String {
+        match self.session.get_protocol_version() {
+            Some(ProtocolVersion::SSLv2) => "SSL v2".to_owned(),
+            Some(ProtocolVersion::SSLv3) => "SSL v3".to_owned(),
+            Some(ProtocolVersion::TLSv1_0) => "TLS v1.0".to_owned(),
+            Some(ProtocolVersion::TLSv1_1) => "TLS v1.1".to_owned(),
+            Some(ProtocolVersion::TLSv1_2) => "TLS v1.2".to_owned(),
+            Some(ProtocolVersion::TLSv1_3) => "TLS v1.3".to_owned(),
+            Some(ProtocolVersion::Unknown(num)) => format!("unknown ({})", num),
+            None => "none".to_owned(),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = self.session.read(buf)?;
+            if count > 0 || !self.session.wants_read() {
+                return Ok(count);
+            }
+            let count = self.session.read_tls(&mut self.sock)?;
+            debug!("Read {} TLS bytes", count);
+            if count == 0 {
+                return Ok(0);
             }
-            Err(_) => break, // connection is closed by client
+            self.session
+                .process_new_packets()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}", err)))?;
         }
     }
-    response
 }
 
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.session.write(buf)?;
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.sock)?;
+        }
+        Ok(count)
+    }
 
-fn from_http(request: &Request,
-             mut client: &mut TcpStream,
-             mut out: &mut Write,
-             verbose: bool)
-             -> CabotResult<()> {
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.flush()?;
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.sock)?;
+        }
+        self.sock.flush()
+    }
+}
 
-    let request_bytes = request.to_bytes();
-    let raw_request = request_bytes.as_slice();
-    log_request(&raw_request, verbose);
+impl Stream for TlsStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.sock.remote_addr()
+    }
 
-    debug!("Sending request...");
-    client.write(&raw_request).unwrap();
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.sock.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+
+/// Report the bytes of the request body written to the transfer.
+struct MonitoredWriter<'a> {
+    out: &'a mut Write,
+    transfer: &'a mut Transfer,
+}
+
+impl<'a> Write for MonitoredWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.out.write(buf)?;
+        self.transfer
+            .sent(count)
+            .map_err(|err| match err {
+                CabotError::TimeoutError(msg) => io::Error::new(io::ErrorKind::TimedOut, msg),
+                err => io::Error::new(io::ErrorKind::Other, format!("{:?}", err)),
+            })?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+
+fn stream_error(err: io::Error) -> CabotError {
+    match err.kind() {
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::WouldBlock => CabotError::TimeoutError(format!("{}", err)),
+        _ => CabotError::IOError(format!("{}", err)),
+    }
+}
+
+
+/// Write the bytes of the response to `out`, and report the bytes of the
+/// body delimited by the parser to the transfer.
+///
+/// Return false if the response is invalid, the parse errors are
+/// reported by the parser of `out`.
+fn received(buf: &[u8],
+            out: &mut Write,
+            parser: &mut ResponseParser,
+            transfer: &mut Transfer,
+            timer: &mut Timer)
+            -> CabotResult<bool> {
+    timer.first_byte();
+    out.write_all(buf).map_err(stream_error)?;
+    let head_complete = parser.is_head_complete();
+    let valid = parser.feed(buf).is_ok();
+    if !head_complete && parser.is_head_complete() {
+        transfer.set_download_total(parser.body_length());
+    }
+    transfer.received(parser.take_body().len())?;
+    Ok(valid)
+}
+
+
+/// Read the response until the parser delimits its end, or until the
+/// connection is closed.
+///
+/// The parse errors are reported by the parser of `out`, the body is
+/// dropped from the parser.
+fn read_response(stream: &mut Stream,
+                 out: &mut Write,
+                 parser: &mut ResponseParser,
+                 transfer: &mut Transfer,
+                 timer: &mut Timer)
+                 -> CabotResult<()> {
     let mut buf = [0; BUFFER_PAGE_SIZE];
-    let response = read_buf(client, &mut buf);
-    out.write_all(response.as_slice()).unwrap();
+    while !parser.is_complete() {
+        match stream.read(&mut buf) {
+            Ok(0) => break, // connection is closed by the server
+            Ok(count) => {
+                if !received(&buf[..count], out, parser, transfer, timer)? {
+                    break;
+                }
+            }
+            Err(err) => return Err(stream_error(err)),
+        }
+    }
     Ok(())
 }
 
-fn from_https(request: &Request,
-              mut client: &mut TcpStream,
-              mut out: &mut Write,
-              verbose: bool)
-              -> CabotResult<()> {
 
-    let request_bytes = request.to_bytes();
-    let raw_request = request_bytes.as_slice();
-    let mut response: Vec<u8> = Vec::with_capacity(RESPONSE_BUFFER_SIZE);
+/// Wait for the answer to the `Expect: 100-continue` header, return true
+/// if the body has to be sent, when the server answered `100 Continue` or
+/// did not answer before the timeout.
+fn wait_continue(stream: &mut Stream,
+                 timeout: Duration,
+                 out: &mut Write,
+                 parser: &mut ResponseParser,
+                 transfer: &mut Transfer,
+                 timer: &mut Timer)
+                 -> CabotResult<bool> {
+    let read_timeout = stream.read_timeout().map_err(stream_error)?;
+    let deadline = Instant::now() + timeout;
     let mut buf = [0; BUFFER_PAGE_SIZE];
+    let send_body = loop {
+        let now = Instant::now();
+        if now >= deadline {
+            info!("No answer to the Expect header, sending the body");
+            break true;
+        }
+        stream.set_read_timeout(Some(deadline - now)).map_err(stream_error)?;
+        let count = match stream.read(&mut buf) {
+            Ok(count) => count,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::TimedOut => continue,
+            Err(err) => return Err(stream_error(err)),
+        };
+        if count == 0 {
+            break false;
+        }
+        // The parse errors are reported once the response is read
+        received(&buf[..count], out, parser, transfer, timer)?;
+        if parser.interim_responses().iter().any(|response| response.status_code() == 100) {
+            break true;
+        }
+        if parser.is_head_complete() {
+            info!("The server answered the Expect header, the body is not sent");
+            break false;
+        }
+    };
+    stream.set_read_timeout(read_timeout).map_err(stream_error)?;
+    Ok(send_body)
+}
+
 
+/// Start a TLS session with the host of the request, authenticated using
+/// the Mozilla root certificates and the additional DER encoded root
+/// certificates.
+///
+/// Errors:
+///
+///  - CabotError::CertificateError in case a root certificate is invalid
+pub(crate) fn tls_session(request: &Request,
+                          root_certificates: &[Vec<u8>])
+                          -> CabotResult<ClientSession> {
     let mut config = ClientConfig::new();
     config.root_store.add_trust_anchors(&webpki_roots::ROOTS);
+    for certificate in root_certificates {
+        config.root_store
+            .add(&Certificate(certificate.clone()))
+            .map_err(|err| CabotError::CertificateError(format!("{:?}", err)))?;
+    }
     let rc_config = Arc::new(config);
-    let mut tlsclient = ClientSession::new(&rc_config, request.host());
-    let mut is_handshaking = true;
-    loop {
-        while tlsclient.wants_write() {
-            let count = tlsclient.write_tls(&mut client).unwrap();
-            debug!("Write {} TLS bytes", count);
-        }
-        if is_handshaking && !tlsclient.is_handshaking() {
-            info!("Handshake complete");
-            is_handshaking = false;
-            let protocol = tlsclient.get_protocol_version();
-            match protocol {
-                Some(ProtocolVersion::SSLv2) => {
-                    info!("Protocol SSL v2 negociated");
-                }
-                Some(ProtocolVersion::SSLv3) => {
-                    info!("Protocol SSL v3 negociated");
-                }
-                Some(ProtocolVersion::TLSv1_0) => {
-                    info!("Protocol TLS v1.0 negociated");
-                }
-                Some(ProtocolVersion::TLSv1_1) => {
-                    info!("Protocol TLS v1.1 negociated");
-                }
-                Some(ProtocolVersion::TLSv1_2) => {
-                    info!("Protocol TLS v1.2 negociated");
-                }
-                Some(ProtocolVersion::TLSv1_3) => {
-                    info!("Protocol TLS v1.3 negociated");
-                }
-                Some(ProtocolVersion::Unknown(num)) => {
-                    info!("Unknown TLS Protocol negociated: {}", num);
-                }
-                None => {
-                    info!("No TLS Protocol negociated");
-                }
-            }
-            log_request(&raw_request, verbose);
-            tlsclient.write_all(&raw_request).unwrap();
-            let count = tlsclient.write_tls(&mut client).unwrap();
-            debug!("Write {} TLS bytes", count);
-        }
-
-        if tlsclient.wants_read() {
-            let count = tlsclient.read_tls(&mut client);
-            if let Err(err) = count {
-                error!("{:?}", err);
-                return Err(CabotError::IOError(format!("{}", err)));
-            }
+    Ok(ClientSession::new(&rc_config, request.host()))
+}
 
-            let count = count.unwrap();
-            debug!("Read {} TLS bytes", count);
-            if count == 0 {
-                break;
-            }
 
-            if let Err(err) = tlsclient.process_new_packets() {
-                return Err(CabotError::CertificateError(format!("{}", err)));
-            }
+/// Send the request, the response is written to `out` and consumed by the
+/// parser if the server answers before the body has been sent.
+fn send_request(request: &Request,
+                stream: &mut Stream,
+                out: &mut Write,
+                parser: &mut ResponseParser,
+                verbose: bool,
+                transfer: &mut Transfer,
+                timer: &mut Timer)
+                -> CabotResult<()> {
+    let request_bytes = request.to_bytes();
+    let body_length = request.body().map(|body| body.len()).unwrap_or(0);
+    let (head, body) = request_bytes.split_at(request_bytes.len() - body_length);
+    log_request(&request_bytes, verbose);
 
-            let mut part: Vec<u8> = read_buf(&mut tlsclient, &mut buf);
-            response.append(&mut part);
-        } else {
-            break;
+    debug!("Sending request...");
+    stream.write_all(head).map_err(stream_error)?;
+    stream.flush().map_err(stream_error)?;
+    let send_body = match request.expect_continue() {
+        Some(timeout) => wait_continue(stream, timeout, out, parser, transfer, timer)?,
+        None => true,
+    };
+    if send_body {
+        stream.write_all(body).map_err(stream_error)?;
+        transfer.sent(body_length)?;
+        {
+            let mut writer = MonitoredWriter {
+                out: &mut *stream,
+                transfer: transfer,
+            };
+            request.write_body_stream(&mut writer).map_err(stream_error)?;
         }
+        stream.flush().map_err(stream_error)?;
     }
-    out.write_all(response.as_slice()).unwrap();
     Ok(())
 }
 
 
-pub fn http_query(request: &Request, mut out: &mut Write, verbose: bool) -> CabotResult<()> {
+/// Open the stream to the server, and establish the TLS session of the
+/// https and wss requests.
+fn connect(request: &Request,
+           connector: &Connector,
+           verbose: bool,
+           timer: &mut Timer,
+           tracer: Option<&Tracer>)
+           -> CabotResult<Box<Stream>> {
+    let tls = match request.scheme() {
+        "http" | "ws" => false,
+        "https" | "wss" => true,
+        _ => {
+            return Err(CabotError::SchemeError(format!("Unrecognized scheme {}",
+                                                       request.scheme())))
+        }
+    };
+    let stream = connector.connect(request, timer, verbose)?;
+    if let Some(tracer) = tracer {
+        match stream.remote_addr() {
+            Some(addr) => tracer.info(&format!("Connected to {}", addr)),
+            None => tracer.info(&format!("Connected to {}", request.authority())),
+        }
+    }
+    let stream: Box<Stream> = if tls {
+        let root_certificates = connector.root_certificates();
+        let stream = TlsStream::connect(request, stream, &root_certificates, timer)?;
+        if let Some(tracer) = tracer {
+            tracer.info(&format!("TLS handshake with {} complete, protocol {}",
+                                 request.host(),
+                                 stream.protocol()));
+        }
+        Box::new(stream)
+    } else {
+        stream
+    };
+    Ok(match tracer {
+        Some(tracer) => Box::new(TracedStream::new(stream, tracer.clone())),
+        None => stream,
+    })
+}
+
+
+pub fn http_query(request: &Request, out: &mut Write, verbose: bool) -> CabotResult<()> {
+    http_query_with_monitor(request, out, verbose, &Monitor::new()).map(|_| ())
+}
+
+
+/// Perform the query, the progress of the transfer is reported to the
+/// [Monitor](../progress/struct.Monitor.html).
+///
+/// Return the [Timings](../timings/struct.Timings.html) of the query.
+pub fn http_query_with_monitor(request: &Request,
+                               out: &mut Write,
+                               verbose: bool,
+                               monitor: &Monitor)
+                               -> CabotResult<Timings> {
+    http_query_with_connector(request, out, verbose, monitor, &TcpConnector::new())
+}
+
+
+/// Perform the query over a stream opened by the
+/// [Connector](../transport/trait.Connector.html).
+///
+/// Return the [Timings](../timings/struct.Timings.html) of the query.
+pub fn http_query_with_connector(request: &Request,
+                                 out: &mut Write,
+                                 verbose: bool,
+                                 monitor: &Monitor,
+                                 connector: &Connector)
+                                 -> CabotResult<Timings> {
+    http_query_with_tracer(request, out, verbose, monitor, connector, None)
+}
+
+
+/// Perform the query, the events of the connection are reported to the
+/// [Tracer](../trace/struct.Tracer.html) if any.
+///
+/// Return the [Timings](../timings/struct.Timings.html) of the query.
+pub fn http_query_with_tracer(request: &Request,
+                              mut out: &mut Write,
+                              verbose: bool,
+                              monitor: &Monitor,
+                              connector: &Connector,
+                              tracer: Option<&Tracer>)
+                              -> CabotResult<Timings> {
+    let mut timer = Timer::new();
     debug!("HTTP Query {} {}",
            request.http_method(),
            request.request_uri());
 
-    let resolver = Resolver::new(verbose);
-    let authority = request.authority();
-    let addr = resolver.get_addr(authority);
+    let mut stream = connect(request, connector, verbose, &mut timer, tracer)?;
+    let mut transfer = monitor.start(request.content_length());
+    // Delimit the response, to stop reading once it is complete
+    let mut parser = ResponseParser::new().set_http_method(request.http_method());
+    send_request(request,
+                 &mut *stream,
+                 &mut out,
+                 &mut parser,
+                 verbose,
+                 &mut transfer,
+                 &mut timer)?;
+    read_response(&mut *stream, &mut out, &mut parser, &mut transfer, &mut timer)?;
+    transfer.finish();
 
-    info!("Connecting to {}", addr);
-    let mut client = TcpStream::connect(addr).unwrap();
-    client.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+    out.flush().unwrap();
 
-    match request.scheme() {
-        "http" => from_http(request, &mut client, &mut out, verbose)?,
-        "https" => from_https(request, &mut client, &mut out, verbose)?,
-        _ => {
-            return Err(CabotError::SchemeError(format!("Unrecognized scheme {}", request.scheme())))
+    Ok(timer.finish())
+
+}
+
+
+/// Send the request, and return the stream once the head of the response
+/// has been received, to switch to the protocol of the `Upgrade` header
+/// if the server answered `101 Switching Protocols`.
+///
+/// The complete response is read if the server answered another status.
+pub fn http_upgrade(request: &Request,
+                    verbose: bool,
+                    connector: &Connector,
+                    tracer: Option<&Tracer>)
+                    -> CabotResult<Upgraded> {
+    let mut timer = Timer::new();
+    debug!("HTTP Upgrade {} {}",
+           request.http_method(),
+           request.request_uri());
+
+    let mut stream = connect(request, connector, verbose, &mut timer, tracer)?;
+    let monitor = Monitor::new();
+    let mut transfer = monitor.start(request.content_length());
+    let mut parser = ResponseParser::new().set_http_method(request.http_method());
+    send_request(request,
+                 &mut *stream,
+                 &mut io::sink(),
+                 &mut parser,
+                 verbose,
+                 &mut transfer,
+                 &mut timer)?;
+
+    let mut buf = [0; BUFFER_PAGE_SIZE];
+    let mut buffered = Vec::new();
+    while !parser.is_complete() {
+        let count = stream.read(&mut buf).map_err(stream_error)?;
+        if count == 0 {
+            break;
         }
-    };
+        timer.first_byte();
+        let consumed = parser.feed(&buf[..count])?;
+        // The bytes following the head belong to the new protocol
+        buffered.extend_from_slice(&buf[consumed..count]);
+    }
+    let mut response = parser.finish()?;
+    response.set_timings(timer.finish());
+    Ok(Upgraded::new(response, stream, buffered))
+}
 
-    out.flush().unwrap();
 
-    Ok(())
+/// Send the request, and return the response once its head has been
+/// received, its body is read from the stream and reported to the monitor.
+pub fn http_stream(request: &Request,
+                   verbose: bool,
+                   monitor: &Monitor,
+                   connector: &Connector,
+                   tracer: Option<&Tracer>)
+                   -> CabotResult<ResponseStream> {
+    let mut timer = Timer::new();
+    debug!("HTTP Stream {} {}",
+           request.http_method(),
+           request.request_uri());
+
+    let mut stream = connect(request, connector, verbose, &mut timer, tracer)?;
+    let mut transfer = monitor.start(request.content_length());
+    let mut parser = ResponseParser::new().set_http_method(request.http_method());
+    send_request(request,
+                 &mut *stream,
+                 &mut io::sink(),
+                 &mut parser,
+                 verbose,
+                 &mut transfer,
+                 &mut timer)?;
 
+    let mut buf = [0; BUFFER_PAGE_SIZE];
+    while !parser.is_head_complete() {
+        let count = stream.read(&mut buf).map_err(stream_error)?;
+        if count == 0 {
+            // The head is terminated by the end of the connection
+            transfer.finish();
+            let mut response = parser.finish()?;
+            response.set_timings(timer.finish());
+            return Ok(ResponseStream::new(response, stream, None, None));
+        }
+        timer.first_byte();
+        parser.feed(&buf[..count])?;
+    }
+    transfer.set_download_total(parser.body_length());
+    let mut response = parser.head().unwrap();
+    response.set_timings(timer.finish());
+    Ok(ResponseStream::new(response, stream, Some(parser), Some(transfer)))
 }
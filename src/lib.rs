@@ -32,6 +32,15 @@
 //!
 //! ```
 //!
+//! # Features
+//!
+//!  - `json`: serialize request bodies and deserialize response bodies
+//!    using [serde](https://serde.rs/).
+//!  - `async`: the [AsyncClient](async_client/struct.AsyncClient.html),
+//!    executing requests on non-blocking sockets.
+//!  - `testing`: the [MockServer](testing/struct.MockServer.html), an
+//!    http and https server answering scripted responses, for tests.
+//!
 //! # Why cabot ?
 //!
 //! To get a simple rust native https client. No binding to OpenSSL.
@@ -50,15 +59,51 @@ extern crate webpki_roots;
 #[macro_use]
 extern crate lazy_static;
 extern crate regex;
+extern crate base64;
+extern crate md5;
+extern crate rand;
+extern crate sha2;
+extern crate sha1;
+extern crate flate2;
+extern crate brotli;
+extern crate encoding;
+#[cfg(feature = "json")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "async")]
+extern crate async_io;
+#[cfg(feature = "async")]
+extern crate futures_lite;
 
+mod date;
 mod dns;
 
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod auth;
+pub mod cache;
+pub mod charset;
+pub mod compression;
 pub mod constants;
+pub mod download;
 pub mod http;
+pub mod middleware;
 pub mod results;
 pub mod request;
 pub mod client;
+pub mod multipart;
+pub mod netrc;
+pub mod parser;
+pub mod progress;
 pub mod response;
+pub mod sse;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timings;
+pub mod trace;
+pub mod transport;
+pub mod websocket;
 
 // Rexport
 pub use client::Client;
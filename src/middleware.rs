@@ -0,0 +1,145 @@
+//! Middlewares of the [Client](../client/struct.Client.html).
+//!
+//! The middlewares are called in the order they have been added to the
+//! client, for every request sent to the server. A middleware can modify
+//! the request before calling the next one, answer without calling it, or
+//! inspect and modify the response returned by the next one.
+//!
+//! # Example
+//! ```no_run
+//! use cabot::{Client, RequestBuilder};
+//! use cabot::middleware::Next;
+//! use cabot::request::Request;
+//!
+//! let client = Client::new()
+//!     .add_middleware(|mut request: Request, next: Next| {
+//!         request.add_header("X-Request-Id: 42");
+//!         next.run(request)
+//!     })
+//!     .add_middleware(|request: Request, next: Next| {
+//!         let response = next.run(request)?;
+//!         println!("{} {}", response.http_version(), response.status_line());
+//!         Ok(response)
+//!     });
+//! let request = RequestBuilder::new("https://www.rust-lang.org/en-US/").build().unwrap();
+//! let response = client.execute(&request).unwrap();
+//! ```
+
+use super::request::Request;
+use super::response::Response;
+use super::results::CabotResult;
+
+
+/// Handle the requests sent by the client.
+pub trait Middleware: Send + Sync {
+    /// Handle the request, `next.run()` calls the next middleware, the last
+    /// one sends the request to the server.
+    fn handle(&self, request: Request, next: Next) -> CabotResult<Response>;
+}
+
+
+impl<F> Middleware for F
+    where F: Fn(Request, Next) -> CabotResult<Response> + Send + Sync
+{
+    fn handle(&self, request: Request, next: Next) -> CabotResult<Response> {
+        self(request, next)
+    }
+}
+
+
+/// The rest of the middlewares chain.
+pub struct Next<'a> {
+    middlewares: &'a [Box<Middleware>],
+    endpoint: &'a Fn(&Request) -> CabotResult<Response>,
+}
+
+
+impl<'a> Next<'a> {
+    /// Chain the middlewares, the endpoint sends the request.
+    pub(crate) fn new(middlewares: &'a [Box<Middleware>],
+                      endpoint: &'a Fn(&Request) -> CabotResult<Response>)
+                      -> Self {
+        Next {
+            middlewares: middlewares,
+            endpoint: endpoint,
+        }
+    }
+
+    /// Call the next middleware, or send the request if this is the last
+    /// one.
+    pub fn run(self, request: Request) -> CabotResult<Response> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                middleware.handle(request, Next::new(middlewares, self.endpoint))
+            }
+            None => (self.endpoint)(&request),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use super::super::request::RequestBuilder;
+    use super::super::response::ResponseBuilder;
+
+    fn custom_headers(request: &Request) -> Vec<&str> {
+        request.headers().into_iter().filter(|header| header.starts_with("X-")).collect()
+    }
+
+    fn respond(request: &Request) -> CabotResult<Response> {
+        let mut builder = ResponseBuilder::new().set_status_line("HTTP/1.1 200 OK");
+        for header in custom_headers(request) {
+            builder = builder.add_header(&header.replace("X-", "X-Echo-"));
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let calls = Mutex::new(Vec::new());
+        let middlewares: Vec<Box<Middleware>> =
+            vec![Box::new(|mut request: Request, next: Next| {
+                     request.add_header("X-First: 1");
+                     let mut response = next.run(request)?;
+                     response.add_header("X-Seen-By: first");
+                     Ok(response)
+                 }),
+                 Box::new(|mut request: Request, next: Next| {
+                     request.add_header("X-Second: 2");
+                     let mut response = next.run(request)?;
+                     response.add_header("X-Seen-By: second");
+                     Ok(response)
+                 })];
+        let endpoint = |request: &Request| {
+            calls.lock().unwrap().push(custom_headers(request).join(", "));
+            respond(request)
+        };
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = Next::new(&middlewares, &endpoint).run(request).unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["X-First: 1, X-Second: 2".to_owned()]);
+        assert_eq!(response.headers(),
+                   &["X-Echo-First: 1",
+                     "X-Echo-Second: 2",
+                     "X-Seen-By: second",
+                     "X-Seen-By: first"]);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let middlewares: Vec<Box<Middleware>> =
+            vec![Box::new(|_: Request, _: Next| {
+                     ResponseBuilder::new().set_status_line("HTTP/1.1 403 Forbidden").build()
+                 }),
+                 Box::new(|_: Request, _: Next| panic!("The chain has been interrupted"))];
+        let endpoint = |_: &Request| -> CabotResult<Response> {
+            panic!("The request has been sent")
+        };
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = Next::new(&middlewares, &endpoint).run(request).unwrap();
+        assert_eq!(response.status_code(), 403);
+    }
+}
@@ -0,0 +1,317 @@
+//! `multipart/form-data` bodies.
+//!
+//! Build HTML form uploads as described in
+//! [rfc7578](https://tools.ietf.org/html/rfc7578). The body is streamed
+//! while the request is sent, files are not loaded in memory.
+//!
+//! # Example
+//! ```
+//! use cabot::RequestBuilder;
+//! use cabot::multipart::{Multipart, Part};
+//!
+//! let form = Multipart::new()
+//!     .add_text("name", "cabot")
+//!     .add_part(Part::bytes("data", b"{}".to_vec())
+//!         .set_filename("data.json")
+//!         .set_content_type("application/json"));
+//!
+//! let request = RequestBuilder::new("http://localhost/upload")
+//!     .set_http_method("POST")
+//!     .multipart(form)
+//!     .build()
+//!     .unwrap();
+//! assert!(request.header("Content-Type").unwrap().starts_with("multipart/form-data"));
+//! ```
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use rand;
+
+use super::results::{CabotResult, CabotError};
+
+const DEFAULT_FILE_CONTENT_TYPE: &'static str = "application/octet-stream";
+
+
+enum Content {
+    Bytes(Vec<u8>),
+    Reader(Box<Read + Send>, Option<u64>),
+}
+
+
+/// A part of a multipart body, a form field or a file.
+pub struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    content: Content,
+}
+
+
+impl Part {
+    /// A text field.
+    pub fn text(name: &str, value: &str) -> Self {
+        Part::bytes(name, value.as_bytes().to_vec())
+    }
+
+    /// A field with a binary content.
+    pub fn bytes(name: &str, content: Vec<u8>) -> Self {
+        Part {
+            name: name.to_owned(),
+            filename: None,
+            content_type: None,
+            content: Content::Bytes(content),
+        }
+    }
+
+    /// A field read from a stream while the request is sent.
+    ///
+    /// The `length` of the stream is required to send the `Content-Length`
+    /// of the body, otherwise it is sent using the chunked transfer coding.
+    pub fn reader<R: Read + Send + 'static>(name: &str, reader: R, length: Option<u64>) -> Self {
+        Part {
+            name: name.to_owned(),
+            filename: None,
+            content_type: Some(DEFAULT_FILE_CONTENT_TYPE.to_owned()),
+            content: Content::Reader(Box::new(reader), length),
+        }
+    }
+
+    /// A file upload, the filename is the name of the file on the disk.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::IOError in case the file can't be opened
+    pub fn file<P: AsRef<Path>>(name: &str, path: P) -> CabotResult<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|err| CabotError::IOError(format!("{}: {}", path.display(), err)))?;
+        let length = file.metadata().ok().map(|metadata| metadata.len());
+        let part = Part::reader(name, file, length);
+        Ok(match path.file_name() {
+            Some(filename) => part.set_filename(filename.to_string_lossy().as_ref()),
+            None => part,
+        })
+    }
+
+    /// Set the filename sent in the `Content-Disposition` header of the part.
+    pub fn set_filename(mut self, filename: &str) -> Self {
+        self.filename = Some(filename.to_owned());
+        self
+    }
+
+    /// Set the `Content-Type` header of the part.
+    /// Default is `application/octet-stream` for streams and files,
+    /// and none for fields.
+    pub fn set_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_owned());
+        self
+    }
+
+    fn headers(&self, boundary: &str) -> Vec<u8> {
+        let mut headers = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+                                  boundary,
+                                  escape(&self.name));
+        if let Some(ref filename) = self.filename {
+            headers.push_str(&format!("; filename=\"{}\"", escape(filename)));
+        }
+        headers.push_str("\r\n");
+        if let Some(ref content_type) = self.content_type {
+            headers.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        headers.push_str("\r\n");
+        headers.into_bytes()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match self.content {
+            Content::Bytes(ref bytes) => Some(bytes.len() as u64),
+            Content::Reader(_, length) => length,
+        }
+    }
+}
+
+
+/// A `multipart/form-data` body.
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<Part>,
+}
+
+
+impl Multipart {
+    /// Create an empty multipart body with a random boundary.
+    pub fn new() -> Self {
+        Multipart {
+            boundary: format!("------------------------{:016x}", rand::random::<u64>()),
+            parts: Vec::new(),
+        }
+    }
+
+    /// The boundary delimiting the parts.
+    pub fn boundary(&self) -> &str {
+        self.boundary.as_str()
+    }
+
+    /// Add a text field.
+    pub fn add_text(self, name: &str, value: &str) -> Self {
+        self.add_part(Part::text(name, value))
+    }
+
+    /// Add a file upload.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::IOError in case the file can't be opened
+    pub fn add_file<P: AsRef<Path>>(self, name: &str, path: P) -> CabotResult<Self> {
+        let part = Part::file(name, path)?;
+        Ok(self.add_part(part))
+    }
+
+    /// Add a part.
+    pub fn add_part(mut self, part: Part) -> Self {
+        self.parts.push(part);
+        self
+    }
+
+    /// The `Content-Type` header value of the body, with the boundary.
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    fn closing(&self) -> Vec<u8> {
+        format!("--{}--\r\n", self.boundary).into_bytes()
+    }
+
+    /// The length of the body, if the length of every stream is known.
+    pub fn content_length(&self) -> Option<u64> {
+        let mut length = self.closing().len() as u64;
+        for part in self.parts.iter() {
+            length += part.headers(&self.boundary).len() as u64 + part.content_length()? + 2;
+        }
+        Some(length)
+    }
+
+    /// Stream the body.
+    pub fn into_reader(self) -> MultipartReader {
+        let mut readers: Vec<Box<Read + Send>> = Vec::with_capacity(self.parts.len() * 3 + 1);
+        let closing = self.closing();
+        for part in self.parts.into_iter() {
+            readers.push(Box::new(Cursor::new(part.headers(&self.boundary))));
+            readers.push(match part.content {
+                Content::Bytes(bytes) => Box::new(Cursor::new(bytes)),
+                Content::Reader(reader, _) => reader,
+            });
+            readers.push(Box::new(Cursor::new(b"\r\n")));
+        }
+        readers.push(Box::new(Cursor::new(closing)));
+        readers.reverse();
+        MultipartReader { readers: readers }
+    }
+}
+
+
+/// Read the multipart body, part by part.
+pub struct MultipartReader {
+    // Remaining readers, in reverse order
+    readers: Vec<Box<Read + Send>>,
+}
+
+
+impl Read for MultipartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(mut reader) = self.readers.pop() {
+            let count = reader.read(buf)?;
+            if count > 0 || buf.is_empty() {
+                self.readers.push(reader);
+                return Ok(count);
+            }
+        }
+        Ok(0)
+    }
+}
+
+
+/// Escape quoted values of the `Content-Disposition` header like HTML
+/// forms do.
+fn escape(value: &str) -> String {
+    value.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(multipart: Multipart) -> String {
+        let mut body = String::new();
+        multipart.into_reader().read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn test_multipart() {
+        let mut multipart = Multipart::new()
+            .add_text("name", "cabot")
+            .add_part(Part::bytes("data", b"{}".to_vec())
+                .set_filename("data \"1\".json")
+                .set_content_type("application/json"));
+        multipart.boundary = "boundary".to_owned();
+        assert_eq!(multipart.content_type(), "multipart/form-data; boundary=boundary");
+        let length = multipart.content_length();
+        let body = read_all(multipart);
+        assert_eq!(body,
+                   "--boundary\r\n\
+                    Content-Disposition: form-data; name=\"name\"\r\n\
+                    \r\n\
+                    cabot\r\n\
+                    --boundary\r\n\
+                    Content-Disposition: form-data; name=\"data\"; \
+                    filename=\"data %221%22.json\"\r\n\
+                    Content-Type: application/json\r\n\
+                    \r\n\
+                    {}\r\n\
+                    --boundary--\r\n");
+        assert_eq!(length, Some(body.len() as u64));
+    }
+
+    #[test]
+    fn test_multipart_reader() {
+        let mut multipart = Multipart::new()
+            .add_part(Part::reader("stream", Cursor::new(b"streamed".to_vec()), None));
+        multipart.boundary = "b".to_owned();
+        assert_eq!(multipart.content_length(), None);
+        assert_eq!(read_all(multipart),
+                   "--b\r\n\
+                    Content-Disposition: form-data; name=\"stream\"\r\n\
+                    Content-Type: application/octet-stream\r\n\
+                    \r\n\
+                    streamed\r\n\
+                    --b--\r\n");
+    }
+
+    #[test]
+    fn test_multipart_file() {
+        let mut multipart = Multipart::new()
+            .add_file("upload", "tests/fixtures/body.txt")
+            .unwrap();
+        multipart.boundary = "b".to_owned();
+        let length = multipart.content_length();
+        let body = read_all(multipart);
+        assert!(body.starts_with("--b\r\n\
+                                  Content-Disposition: form-data; name=\"upload\"; \
+                                  filename=\"body.txt\"\r\n\
+                                  Content-Type: application/octet-stream\r\n\
+                                  \r\n\
+                                  cabot is a command line tool"));
+        assert_eq!(length, Some(body.len() as u64));
+
+        assert!(Multipart::new().add_file("upload", "tests/fixtures/missing").is_err());
+    }
+
+    #[test]
+    fn test_multipart_random_boundary() {
+        assert!(Multipart::new().boundary() != Multipart::new().boundary());
+    }
+}
@@ -0,0 +1,360 @@
+//! Credentials lookup in `.netrc` files.
+//!
+//! The file format is described in the
+//! [ftp manual](https://www.gnu.org/software/inetutils/manual/html_node/The-_002enetrc-file.html).
+//! Macros are parsed and ignored.
+//!
+//! # Example
+//! ```
+//! use cabot::netrc::Netrc;
+//!
+//! let netrc = Netrc::parse("machine localhost login user password secret").unwrap();
+//! let machine = netrc.find("localhost").unwrap();
+//! assert_eq!(machine.login(), Some("user"));
+//! assert_eq!(machine.password(), Some("secret"));
+//! ```
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::auth::Authentication;
+use super::request::Request;
+use super::results::{CabotResult, CabotError};
+
+
+/// Credentials of a machine entry.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Machine {
+    login: Option<String>,
+    password: Option<String>,
+    account: Option<String>,
+}
+
+impl Machine {
+    /// The user to authenticate.
+    pub fn login(&self) -> Option<&str> {
+        self.login.as_ref().map(|s| s.as_str())
+    }
+
+    /// The password of the user.
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_ref().map(|s| s.as_str())
+    }
+
+    /// An additional account password, unused by http.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_ref().map(|s| s.as_str())
+    }
+}
+
+
+/// A parsed `.netrc` file.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Netrc {
+    machines: Vec<(String, Machine)>,
+    default: Option<Machine>,
+}
+
+
+impl Netrc {
+    /// The default netrc file, `$NETRC` or `~/.netrc`.
+    pub fn default_path() -> Option<PathBuf> {
+        match env::var_os("NETRC") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => env::home_dir().map(|home| home.join(".netrc")),
+        }
+    }
+
+    /// Read and parse a netrc file.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::NetrcError in case the file can't be read or is
+    ///    malformed
+    pub fn from_file<P: AsRef<Path>>(path: P) -> CabotResult<Self> {
+        let path = path.as_ref();
+        debug!("Reading netrc file {}", path.display());
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|err| CabotError::NetrcError(format!("{}: {}", path.display(), err)))?;
+        Netrc::parse(content.as_str())
+    }
+
+    /// Parse the content of a netrc file.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::NetrcError in case of unexpected or missing token
+    pub fn parse(content: &str) -> CabotResult<Self> {
+        let mut netrc = Netrc::default();
+        let mut tokens = Tokenizer::new(content);
+        // The entry currently parsed, None for the default entry
+        let mut current: Option<(Option<String>, Machine)> = None;
+        while let Some(token) = tokens.next() {
+            match token.as_str() {
+                "machine" => {
+                    netrc.push(current.take());
+                    let name = tokens.value("machine")?;
+                    current = Some((Some(name), Machine::default()));
+                }
+                "default" => {
+                    netrc.push(current.take());
+                    current = Some((None, Machine::default()));
+                }
+                "macdef" => {
+                    let _ = tokens.value("macdef")?;
+                    tokens.skip_macro();
+                }
+                "login" | "password" | "account" => {
+                    let value = tokens.value(token.as_str())?;
+                    let machine = match current {
+                        Some((_, ref mut machine)) => machine,
+                        None => {
+                            return Err(CabotError::NetrcError(format!("Token {} outside a \
+                                                                       machine entry",
+                                                                      token)))
+                        }
+                    };
+                    match token.as_str() {
+                        "login" => machine.login = Some(value),
+                        "password" => machine.password = Some(value),
+                        _ => machine.account = Some(value),
+                    }
+                }
+                _ => return Err(CabotError::NetrcError(format!("Unexpected token {}", token))),
+            }
+        }
+        netrc.push(current);
+        Ok(netrc)
+    }
+
+    fn push(&mut self, entry: Option<(Option<String>, Machine)>) {
+        match entry {
+            Some((Some(name), machine)) => self.machines.push((name, machine)),
+            Some((None, machine)) => self.default = Some(machine),
+            None => {}
+        }
+    }
+
+    /// Find the credentials of the given host,
+    /// fallback to the `default` entry if present.
+    pub fn find(&self, host: &str) -> Option<&Machine> {
+        self.machines
+            .iter()
+            .find(|&&(ref name, _)| name.eq_ignore_ascii_case(host))
+            .map(|&(_, ref machine)| machine)
+            .or(self.default.as_ref())
+    }
+
+    /// Authenticate the request with the credentials of its host using
+    /// Basic authentication.
+    ///
+    /// The request is returned unchanged if it is already authenticated,
+    /// has an `Authorization` header, or if the netrc file has no login for
+    /// its host.
+    pub fn authenticate(&self, request: &Request) -> Request {
+        let mut request = request.clone();
+        if request.authentication().is_some() || request.header("Authorization").is_some() {
+            return request;
+        }
+        if let Some(machine) = self.find(request.host()) {
+            if let Some(login) = machine.login() {
+                info!("Using netrc credentials of {} for {}", login, request.host());
+                request.set_authentication(Authentication::Basic(login.to_owned(),
+                                                                 machine.password()
+                                                                     .unwrap_or("")
+                                                                     .to_owned()));
+            }
+        }
+        request
+    }
+}
+
+
+struct Tokenizer<'a> {
+    content: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(content: &'a str) -> Self {
+        Tokenizer {
+            content: content,
+            pos: 0,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.content[self.pos..]
+    }
+
+    fn skip_whitespaces_and_comments(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_left();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with('#') {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Read the value of the given keyword.
+    fn value(&mut self, keyword: &str) -> CabotResult<String> {
+        match self.next() {
+            Some(value) => Ok(value),
+            None => Err(CabotError::NetrcError(format!("Missing value for {}", keyword))),
+        }
+    }
+
+    /// Skip the macro definition, that ends with an empty line.
+    fn skip_macro(&mut self) {
+        let rest = self.rest();
+        let mut end = rest.len();
+        let mut lines = rest.split('\n');
+        // the end of the macdef line
+        let mut offset = lines.next().map_or(0, |line| line.len() + 1);
+        for line in lines {
+            if line.trim().is_empty() {
+                end = offset;
+                break;
+            }
+            offset += line.len() + 1;
+        }
+        self.pos += end.min(rest.len());
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.skip_whitespaces_and_comments();
+        let rest = self.rest();
+        if rest.is_empty() {
+            return None;
+        }
+        let mut token = String::new();
+        if rest.starts_with('"') {
+            let mut chars = rest.char_indices().skip(1);
+            let mut consumed = rest.len();
+            while let Some((idx, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, c)) = chars.next() {
+                            token.push(c);
+                        }
+                    }
+                    '"' => {
+                        consumed = idx + 1;
+                        break;
+                    }
+                    _ => token.push(c),
+                }
+            }
+            self.pos += consumed;
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            token.push_str(&rest[..end]);
+            self.pos += end;
+        }
+        Some(token)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::request::RequestBuilder;
+
+    #[test]
+    fn test_parse_netrc() {
+        let netrc = Netrc::parse("# my servers\n\
+                                  machine example.org\n\
+                                  \tlogin alice\n\
+                                  \tpassword \"s3cr3t pass\"\n\
+                                  machine api.example.org login bob password hunter2 \
+                                  account acme\n\
+                                  default login anonymous password guest\n")
+            .unwrap();
+        let machine = netrc.find("example.org").unwrap();
+        assert_eq!(machine.login(), Some("alice"));
+        assert_eq!(machine.password(), Some("s3cr3t pass"));
+        assert_eq!(machine.account(), None);
+
+        let machine = netrc.find("API.example.org").unwrap();
+        assert_eq!(machine.login(), Some("bob"));
+        assert_eq!(machine.password(), Some("hunter2"));
+        assert_eq!(machine.account(), Some("acme"));
+
+        let machine = netrc.find("localhost").unwrap();
+        assert_eq!(machine.login(), Some("anonymous"));
+    }
+
+    #[test]
+    fn test_parse_netrc_macdef() {
+        let netrc = Netrc::parse("macdef init\n\
+                                  cd /pub\n\
+                                  login ignored\n\
+                                  \n\
+                                  machine example.org login alice password secret\n")
+            .unwrap();
+        let machine = netrc.find("example.org").unwrap();
+        assert_eq!(machine.login(), Some("alice"));
+        assert_eq!(netrc.find("localhost"), None);
+    }
+
+    #[test]
+    fn test_parse_netrc_errors() {
+        assert!(Netrc::parse("login alice").is_err());
+        assert!(Netrc::parse("machine example.org login").is_err());
+        assert!(Netrc::parse("machine example.org user alice").is_err());
+    }
+
+    #[test]
+    fn test_netrc_file_not_found() {
+        match Netrc::from_file("/nonexistent/.netrc") {
+            Err(CabotError::NetrcError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_authenticate_request() {
+        let netrc = Netrc::parse("machine localhost login alice password secret").unwrap();
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let request = netrc.authenticate(&request);
+        assert_eq!(request.authentication(),
+                   Some(&Authentication::Basic("alice".to_owned(), "secret".to_owned())));
+
+        let request = RequestBuilder::new("http://localhost/")
+            .bearer_auth("token")
+            .build()
+            .unwrap();
+        let request = netrc.authenticate(&request);
+        assert_eq!(request.authentication(),
+                   Some(&Authentication::Bearer("token".to_owned())));
+
+        let request = RequestBuilder::new("http://127.0.0.1/").build().unwrap();
+        let request = netrc.authenticate(&request);
+        assert_eq!(request.authentication(), None);
+
+        let request = RequestBuilder::new("http://localhost/")
+            .add_header("Authorization: Token abc")
+            .build()
+            .unwrap();
+        let request = netrc.authenticate(&request);
+        assert_eq!(request.authentication(), None);
+        assert_eq!(request.headers()
+                       .iter()
+                       .filter(|header| header.starts_with("Authorization:"))
+                       .count(),
+                   1);
+    }
+}
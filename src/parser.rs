@@ -0,0 +1,969 @@
+//! Incremental parser of the HTTP/1.1 responses.
+//!
+//! The [ResponseParser](struct.ResponseParser.html) consumes the bytes of
+//! a response as they are received. The lines of the head may end with
+//! `\r\n` or with `\n`. The body is delimited by the `Content-Length`
+//! header, by the chunked transfer coding, which is decoded, or by the end
+//! of the connection.
+//!
+//! The responses to `HEAD` requests, and the `1xx`, `204 No Content` and
+//! `304 Not Modified` responses have no body.
+//!
+//! The interim responses, such as `100 Continue` or `103 Early Hints`,
+//! are skipped, they are available using
+//! [interim_responses](struct.ResponseParser.html#method.interim_responses)
+//! or passed to a handler as soon as they are parsed.
+//!
+//! Invalid responses are rejected with an `HttpResponseParseError`
+//! reporting the offset of the invalid bytes in the response.
+//!
+//! # Example
+//! ```
+//! use cabot::parser::ResponseParser;
+//!
+//! let mut parser = ResponseParser::new();
+//! parser.feed(b"HTTP/1.1 200 OK\nTransfer-Encoding: chunked\n\n5\r\nHel").unwrap();
+//! parser.feed(b"lo\r\n0\r\n\r\n").unwrap();
+//! assert!(parser.is_complete());
+//! let response = parser.finish().unwrap();
+//! assert_eq!(response.body_as_string().unwrap(), "Hello");
+//! ```
+
+use std::io::{self, Write};
+use std::mem;
+
+use super::response::{Response, ResponseBuilder};
+use super::results::{CabotResult, CabotError};
+
+/// Default maximum number of headers of a response.
+pub const MAX_HEADERS: usize = 100;
+/// Default maximum size of the status line and the headers of a response.
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_SIZE_LINE: usize = 1024;
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum State {
+    StatusLine,
+    Headers,
+    Length(u64),
+    UntilClose,
+    ChunkSize,
+    ChunkData(u64),
+    ChunkDataEnd,
+    Trailers,
+    Complete,
+}
+
+
+/// Parse a response from its bytes.
+pub struct ResponseParser {
+    head_request: bool,
+    max_headers: usize,
+    max_head_size: usize,
+    state: State,
+    // Count of bytes consumed
+    position: usize,
+    // Size of the complete lines of the head
+    head_size: usize,
+    // The pending line, without its line ending
+    line: Vec<u8>,
+    status_line: Option<String>,
+    // The headers and the position of their first line
+    headers: Vec<(String, usize)>,
+    body: Vec<u8>,
+    interim_responses: Vec<Response>,
+    interim_handler: Option<Box<FnMut(&Response) + Send>>,
+    error: Option<CabotError>,
+}
+
+
+impl ResponseParser {
+    /// Construct a new `ResponseParser`.
+    pub fn new() -> Self {
+        ResponseParser {
+            head_request: false,
+            max_headers: MAX_HEADERS,
+            max_head_size: MAX_HEAD_SIZE,
+            state: State::StatusLine,
+            position: 0,
+            head_size: 0,
+            line: Vec::new(),
+            status_line: None,
+            headers: Vec::new(),
+            body: Vec::new(),
+            interim_responses: Vec::new(),
+            interim_handler: None,
+            error: None,
+        }
+    }
+
+    /// Call the handler with every interim response, such as the
+    /// `103 Early Hints` responses, as soon as it is parsed.
+    pub fn set_interim_handler<F>(mut self, handler: F) -> Self
+        where F: FnMut(&Response) + Send + 'static
+    {
+        self.interim_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Set the method of the request, the responses to `HEAD` requests
+    /// have no body.
+    pub fn set_http_method(mut self, http_method: &str) -> Self {
+        self.head_request = http_method.eq_ignore_ascii_case("HEAD");
+        self
+    }
+
+    /// Set the maximum number of headers, `MAX_HEADERS` by default.
+    pub fn set_max_headers(mut self, max_headers: usize) -> Self {
+        self.max_headers = max_headers;
+        self
+    }
+
+    /// Set the maximum size of the status line and the headers, line
+    /// endings included, `MAX_HEAD_SIZE` by default.
+    pub fn set_max_head_size(mut self, max_head_size: usize) -> Self {
+        self.max_head_size = max_head_size;
+        self
+    }
+
+    /// True once the end of the response has been consumed, a response
+    /// delimited by the end of the connection is never complete.
+    pub fn is_complete(&self) -> bool {
+        self.state == State::Complete
+    }
+
+    /// True once the head of the final response has been consumed.
+    pub fn is_head_complete(&self) -> bool {
+        self.state != State::StatusLine && self.state != State::Headers
+    }
+
+    /// The interim responses received before the final response, the
+    /// `1xx` responses except `101 Switching Protocols`.
+    pub fn interim_responses(&self) -> &[Response] {
+        self.interim_responses.as_slice()
+    }
+
+    /// The head of the final response, without its body, once it has
+    /// been consumed.
+    pub fn head(&self) -> Option<Response> {
+        if self.is_head_complete() {
+            self.build_response(None).ok()
+        } else {
+            None
+        }
+    }
+
+    /// The length of the body of the final response, once its head has
+    /// been consumed, `None` if the body is chunked or delimited by the
+    /// end of the connection.
+    pub fn body_length(&self) -> Option<u64> {
+        if !self.is_head_complete() {
+            return None;
+        }
+        match self.body_framing() {
+            Ok(State::Length(length)) => Some(length),
+            Ok(State::Complete) => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Take the bytes of the body consumed so far, to read the body while
+    /// it is received. The response built by
+    /// [finish](#method.finish) only contains the bytes not taken.
+    pub fn take_body(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.body, Vec::new())
+    }
+
+    /// Consume the bytes of the response, return the count of bytes
+    /// consumed, lower than the size of the buffer if the response is
+    /// complete.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::HttpResponseParseError in case the response is
+    ///    invalid, the following calls return the same error.
+    pub fn feed(&mut self, buf: &[u8]) -> CabotResult<usize> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+        let mut consumed = 0;
+        while consumed < buf.len() && !self.is_complete() {
+            match self.step(&buf[consumed..]) {
+                Ok(count) => {
+                    consumed += count;
+                    self.position += count;
+                }
+                Err(err) => {
+                    self.error = Some(err.clone());
+                    return Err(err);
+                }
+            }
+        }
+        Ok(consumed)
+    }
+
+    /// Build the [Response](../response/struct.Response.html) once the
+    /// connection has been closed.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::HttpResponseParseError in case the response is
+    ///    invalid, empty or truncated.
+    pub fn finish(mut self) -> CabotResult<Response> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if self.position == 0 {
+            return Err(CabotError::HttpResponseParseError("Empty response".to_owned()));
+        }
+        if self.state == State::StatusLine || self.state == State::Headers {
+            // The head is terminated by the end of the connection
+            let mut line = self.line.split_off(0);
+            if !line.is_empty() {
+                let start = self.position - line.len();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                self.head_line(line, start)?;
+            }
+            if self.status_line.is_some() && !self.is_interim() {
+                self.state = self.body_framing()?;
+            }
+        }
+        match self.state {
+            State::UntilClose | State::Complete => {}
+            State::Trailers if self.line.is_empty() => {}
+            State::Length(missing) => {
+                return Err(parse_error(format!("Unexpected end of response, {} bytes of the \
+                                                body are missing",
+                                               missing),
+                                       self.position))
+            }
+            _ => return Err(parse_error("Unexpected end of response".to_owned(), self.position)),
+        }
+        if self.has_body() {
+            self.build_response(Some(self.body.as_slice()))
+        } else {
+            self.build_response(None)
+        }
+    }
+
+    fn build_response(&self, body: Option<&[u8]>) -> CabotResult<Response> {
+        let status_line = match self.status_line {
+            Some(ref status_line) => status_line,
+            None => return Err(CabotError::HttpResponseParseError("No Status Line".to_owned())),
+        };
+        let mut builder = ResponseBuilder::new().set_status_line(status_line);
+        if let Some(body) = body {
+            builder = builder.set_body(body);
+        }
+        for &(ref header, _) in &self.headers {
+            builder = builder.add_header(header);
+        }
+        builder.build()
+    }
+
+    /// Consume the beginning of the buffer, return the count of bytes
+    /// consumed.
+    fn step(&mut self, buf: &[u8]) -> CabotResult<usize> {
+        match self.state {
+            State::StatusLine | State::Headers => {
+                let limit = self.max_head_size.saturating_sub(self.head_size);
+                let (count, line) = self.read_line(buf, limit)?;
+                if let Some((line, start)) = line {
+                    self.head_size = self.position + count;
+                    self.head_line(line, start)?;
+                }
+                Ok(count)
+            }
+            State::Length(remaining) => {
+                let count = self.read_body(buf, remaining);
+                self.state = match remaining - count as u64 {
+                    0 => State::Complete,
+                    remaining => State::Length(remaining),
+                };
+                Ok(count)
+            }
+            State::UntilClose => {
+                self.body.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            State::ChunkSize => {
+                let (count, line) = self.read_line(buf, MAX_CHUNK_SIZE_LINE)?;
+                if let Some((line, start)) = line {
+                    self.state = match chunk_size(&line, start)? {
+                        0 => State::Trailers,
+                        size => State::ChunkData(size),
+                    }
+                }
+                Ok(count)
+            }
+            State::ChunkData(remaining) => {
+                let count = self.read_body(buf, remaining);
+                self.state = match remaining - count as u64 {
+                    0 => State::ChunkDataEnd,
+                    remaining => State::ChunkData(remaining),
+                };
+                Ok(count)
+            }
+            State::ChunkDataEnd => {
+                // Only the line ending may follow the chunk data
+                let (count, line) = self.read_line(buf, MAX_CHUNK_SIZE_LINE)?;
+                if let Some((line, start)) = line {
+                    if !line.is_empty() {
+                        return Err(parse_error("Missing line ending after the chunk data"
+                                                   .to_owned(),
+                                               start));
+                    }
+                    self.state = State::ChunkSize;
+                }
+                Ok(count)
+            }
+            State::Trailers => {
+                let (count, line) = self.read_line(buf, self.max_head_size)?;
+                if let Some((line, _)) = line {
+                    if line.is_empty() {
+                        self.state = State::Complete;
+                    } else {
+                        debug!("Ignoring trailer {}", decode_line(&line));
+                    }
+                }
+                Ok(count)
+            }
+            State::Complete => Ok(0),
+        }
+    }
+
+    /// Accumulate the pending line, once its end has been consumed, the
+    /// line is returned without its line ending, with its position.
+    fn read_line(&mut self,
+                 buf: &[u8],
+                 limit: usize)
+                 -> CabotResult<(usize, Option<(Vec<u8>, usize)>)> {
+        let start = self.position - self.line.len();
+        let end = buf.iter().position(|&byte| byte == b'\n');
+        self.line.extend_from_slice(&buf[..end.unwrap_or(buf.len())]);
+        if self.line.len() > limit {
+            return Err(parse_error("Line too long".to_owned(), start));
+        }
+        match end {
+            Some(end) => {
+                let mut line = self.line.split_off(0);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                Ok((end + 1, Some((line, start))))
+            }
+            None => Ok((buf.len(), None)),
+        }
+    }
+
+    fn read_body(&mut self, buf: &[u8], remaining: u64) -> usize {
+        let count = if (buf.len() as u64) < remaining {
+            buf.len()
+        } else {
+            remaining as usize
+        };
+        self.body.extend_from_slice(&buf[..count]);
+        count
+    }
+
+    /// Handle a line of the head, starting at the position `start`.
+    fn head_line(&mut self, line: Vec<u8>, start: usize) -> CabotResult<()> {
+        if self.status_line.is_none() {
+            let status_line = decode_line(&line);
+            check_status_line(&status_line, start)?;
+            debug!("Adding status line {}", status_line);
+            self.status_line = Some(status_line);
+            self.state = State::Headers;
+            return Ok(());
+        }
+        if line.is_empty() {
+            if self.is_interim() {
+                self.interim_response()?;
+            } else {
+                self.state = self.body_framing()?;
+            }
+            return Ok(());
+        }
+        let header = decode_line(&line);
+        if header.starts_with(' ') || header.starts_with('\t') {
+            match self.headers.last_mut() {
+                Some(&mut (ref mut previous, _)) => {
+                    debug!("Obsolete line folded header reveived in {}", previous);
+                    previous.push(' ');
+                    previous.push_str(header.trim_left());
+                    return Ok(());
+                }
+                None => {
+                    return Err(parse_error("Unexpected folded line before the first header"
+                                               .to_owned(),
+                                           start))
+                }
+            }
+        }
+        let valid = match header.find(':') {
+            Some(colon) => colon > 0 && !header[..colon].contains(char::is_whitespace),
+            None => false,
+        };
+        if !valid {
+            return Err(parse_error(format!("Malformed header {:?}", header), start));
+        }
+        if self.headers.len() >= self.max_headers {
+            return Err(parse_error(format!("More than {} headers", self.max_headers), start));
+        }
+        debug!("Adding header {}", header);
+        self.headers.push((header, start));
+        Ok(())
+    }
+
+    fn status_code(&self) -> Option<&str> {
+        // The status line has been checked
+        self.status_line
+            .as_ref()
+            .map(|status_line| &status_line[status_line.find(' ').unwrap() + 1..][..3])
+    }
+
+    fn is_interim(&self) -> bool {
+        match self.status_code() {
+            Some(code) => code.starts_with('1') && code != "101",
+            None => false,
+        }
+    }
+
+    /// Skip the interim response, the final response follows.
+    fn interim_response(&mut self) -> CabotResult<()> {
+        let mut builder = ResponseBuilder::new().set_status_line(self.status_line
+            .take()
+            .unwrap()
+            .as_str());
+        for (header, _) in self.headers.drain(..) {
+            builder = builder.add_header(header.as_str());
+        }
+        let response = builder.build()?;
+        info!("Interim response {} {}",
+              response.http_version(),
+              response.status_line());
+        if let Some(ref mut handler) = self.interim_handler {
+            handler(&response);
+        }
+        self.interim_responses.push(response);
+        self.state = State::StatusLine;
+        Ok(())
+    }
+
+    /// False if the response cannot have a body, whatever its headers.
+    fn has_body(&self) -> bool {
+        match self.status_code() {
+            Some(code) => {
+                !self.head_request && !code.starts_with('1') && code != "204" && code != "304"
+            }
+            None => true,
+        }
+    }
+
+    /// The state following the head, depending on the headers.
+    fn body_framing(&self) -> CabotResult<State> {
+        if !self.has_body() {
+            // The bytes following the head of a 101 response belong to the
+            // new protocol
+            return Ok(State::Complete);
+        }
+        let mut transfer_coding = None;
+        let mut content_length = None;
+        for &(ref header, start) in &self.headers {
+            let mut split = header.splitn(2, ':');
+            let name = split.next().unwrap();
+            let value = split.next().unwrap();
+            if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                // The last coding applied is the last one listed
+                transfer_coding = value.split(',').last().map(|coding| coding.trim().to_owned());
+            } else if name.eq_ignore_ascii_case("Content-Length") {
+                for length in value.split(',') {
+                    let length = length.trim();
+                    if length.is_empty() || !length.bytes().all(|byte| byte.is_ascii_digit()) {
+                        return Err(parse_error(format!("Invalid Content-Length {:?}", length),
+                                               start));
+                    }
+                    let length = length.parse::<u64>()
+                        .map_err(|_| parse_error(format!("Invalid Content-Length {:?}", length),
+                                                 start))?;
+                    match content_length {
+                        Some(previous) if previous != length => {
+                            return Err(parse_error(format!("Conflicting Content-Length {} \
+                                                            and {}",
+                                                           previous,
+                                                           length),
+                                                   start))
+                        }
+                        _ => content_length = Some(length),
+                    }
+                }
+            }
+        }
+        // The transfer coding overrides the Content-Length
+        let state = match (transfer_coding, content_length) {
+            (Some(coding), _) => {
+                if coding.eq_ignore_ascii_case("chunked") {
+                    State::ChunkSize
+                } else {
+                    State::UntilClose
+                }
+            }
+            (None, Some(0)) => State::Complete,
+            (None, Some(length)) => State::Length(length),
+            (None, None) => State::UntilClose,
+        };
+        Ok(state)
+    }
+}
+
+
+impl Write for ResponseParser {
+    /// Consume the bytes, the parse errors are returned by
+    /// [finish](struct.ResponseParser.html#method.finish).
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Ok(count) = self.feed(buf) {
+            if count < buf.len() {
+                debug!("Ignoring {} bytes after the response", buf.len() - count);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+fn parse_error(message: String, position: usize) -> CabotError {
+    CabotError::HttpResponseParseError(format!("{} at byte {}", message, position))
+}
+
+
+/// Decode a line of the head, as ISO-8859-1 if it is not UTF-8.
+fn decode_line(line: &[u8]) -> String {
+    match String::from_utf8(line.to_vec()) {
+        Ok(line) => line,
+        Err(_) => line.iter().map(|&byte| byte as char).collect(),
+    }
+}
+
+
+fn check_status_line(status_line: &str, start: usize) -> CabotResult<()> {
+    let mut split = status_line.splitn(3, ' ');
+    let http_version = split.next().unwrap();
+    if !http_version.starts_with("HTTP/") {
+        return Err(parse_error(format!("Unknown protocol in status line {:?}", status_line),
+                               start));
+    }
+    match split.next() {
+        Some(code) if code.len() == 3 && code.bytes().all(|byte| byte.is_ascii_digit()) => Ok(()),
+        _ => {
+            Err(parse_error(format!("Malformed status code in status line {:?}", status_line),
+                            start + http_version.len() + 1))
+        }
+    }
+}
+
+
+fn chunk_size(line: &[u8], start: usize) -> CabotResult<u64> {
+    // The chunk extensions are ignored
+    let size = line.split(|&byte| byte == b';').next().unwrap();
+    let size = String::from_utf8_lossy(size);
+    let size = size.trim_matches(|c| c == ' ' || c == '\t');
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(parse_error(format!("Invalid chunk size {:?}", size), start));
+    }
+    u64::from_str_radix(size, 16)
+        .map_err(|_| parse_error(format!("Chunk size {:?} too large", size), start))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use rand;
+
+    use super::*;
+
+    fn parse(response: &[u8]) -> CabotResult<Response> {
+        let mut parser = ResponseParser::new();
+        parser.feed(response)?;
+        parser.finish()
+    }
+
+    fn parse_error_message(response: &[u8]) -> String {
+        match parse(response) {
+            Err(CabotError::HttpResponseParseError(message)) => message,
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_content_length() {
+        let mut parser = ResponseParser::new();
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHelloExtra";
+        assert_eq!(parser.feed(response).unwrap(), response.len() - 5);
+        assert!(parser.is_complete());
+        let response = parser.finish().unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.headers(), &["Content-Length: 5"]);
+        assert_eq!(response.body_as_string().unwrap(), "Hello");
+    }
+
+    #[test]
+    fn test_parse_lf_line_endings() {
+        let response = parse(b"HTTP/1.1 200 OK\nContent-Type: text/plain\n\n\r\nHello\n").unwrap();
+        assert_eq!(response.headers(), &["Content-Type: text/plain"]);
+        assert_eq!(response.body_as_string().unwrap(), "\r\nHello\n");
+    }
+
+    #[test]
+    fn test_parse_until_close() {
+        let mut parser = ResponseParser::new();
+        parser.feed(b"HTTP/1.0 200 OK\r\n\r\nHello").unwrap();
+        parser.feed(b" World!").unwrap();
+        assert!(!parser.is_complete());
+        assert_eq!(parser.finish().unwrap().body_as_string().unwrap(), "Hello World!");
+    }
+
+    #[test]
+    fn test_parse_streaming_body() {
+        let mut parser = ResponseParser::new();
+        parser.feed(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n").unwrap();
+        assert!(parser.head().is_none());
+        parser.feed(b"\r\n5\r\nHello\r\n4\r\n Wo").unwrap();
+        assert_eq!(parser.head().unwrap().header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(parser.take_body(), b"Hello Wo");
+        assert_eq!(parser.take_body(), b"");
+        parser.feed(b"r\r\n3\r\nld!\r\n0\r\n\r\n").unwrap();
+        assert!(parser.is_complete());
+        assert_eq!(parser.finish().unwrap().body_as_string().unwrap(), "rld!");
+    }
+
+    #[test]
+    fn test_parse_chunked() {
+        let response = parse(b"HTTP/1.1 200 OK\r\n\
+                               Transfer-Encoding: gzip, chunked\r\n\
+                               Content-Length: 100\r\n\
+                               \r\n\
+                               5;name=value\r\nHello\r\n\
+                               7 \r\n World!\n\
+                               0\r\n\
+                               Expires: 0\r\n\
+                               \r\n")
+            .unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+        assert_eq!(response.headers().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_header_latin1() {
+        let response = parse(b"HTTP/1.1 200 OK\r\nX-Name: caf\xe9\r\n\r\n").unwrap();
+        assert_eq!(response.header("X-Name"), Some("caf\u{e9}"));
+        let response = parse("HTTP/1.1 200 OK\r\nX-Name: caf\u{e9}\r\n\r\n".as_bytes()).unwrap();
+        assert_eq!(response.header("X-Name"), Some("caf\u{e9}"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_error_message(b""), "Empty response");
+        assert_eq!(parse_error_message(b"ICY 200 OK\r\n\r\n"),
+                   "Unknown protocol in status line \"ICY 200 OK\" at byte 0");
+        assert_eq!(parse_error_message(b"HTTP/1.1 2000 OK\r\n\r\n"),
+                   "Malformed status code in status line \"HTTP/1.1 2000 OK\" at byte 9");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\nX-Bad\r\n\r\n"),
+                   "Malformed header \"X-Bad\" at byte 17");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\nX-Bad : 1\n\n"),
+                   "Malformed header \"X-Bad : 1\" at byte 16");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\n folded\r\n\r\n"),
+                   "Unexpected folded line before the first header at byte 17");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\n\
+                                         Content-Length: 2\r\n\
+                                         Content-Length: 3\r\n\r\nabc"),
+                   "Conflicting Content-Length 2 and 3 at byte 36");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\nContent-Length: -1\r\n\r\n"),
+                   "Invalid Content-Length \"-1\" at byte 17");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nHello"),
+                   "Unexpected end of response, 5 bytes of the body are missing at byte 44");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\n\
+                                         Transfer-Encoding: chunked\r\n\r\n\
+                                         5\r\nHello\r\nx\r\n"),
+                   "Invalid chunk size \"x\" at byte 57");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\n\
+                                         Transfer-Encoding: chunked\r\n\r\n\
+                                         3\r\nHello\r\n0\r\n\r\n"),
+                   "Missing line ending after the chunk data at byte 53");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\n\
+                                         Transfer-Encoding: chunked\r\n\r\n\
+                                         fffffffffffffffff\r\n"),
+                   "Chunk size \"fffffffffffffffff\" too large at byte 47");
+        assert_eq!(parse_error_message(b"HTTP/1.1 200 OK\r\n\
+                                         Transfer-Encoding: chunked\r\n\r\n\
+                                         5\r\nHel"),
+                   "Unexpected end of response at byte 53");
+    }
+
+    #[test]
+    fn test_parse_interim_responses() {
+        let codes = Arc::new(Mutex::new(Vec::new()));
+        let handler_codes = codes.clone();
+        let mut parser = ResponseParser::new().set_interim_handler(move |response| {
+            handler_codes.lock().unwrap().push(response.status_code())
+        });
+        parser.feed(b"HTTP/1.1 100 Continue\r\n\r\n\
+                      HTTP/1.1 103 Early Hints\r\n\
+                      Link: </style.css>; rel=preload\r\n\r\n")
+            .unwrap();
+        assert_eq!(*codes.lock().unwrap(), vec![100, 103]);
+        assert!(!parser.is_head_complete());
+        parser.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOk").unwrap();
+        assert!(parser.is_head_complete());
+        assert_eq!(parser.interim_responses()
+                       .iter()
+                       .map(|response| response.status_code())
+                       .collect::<Vec<_>>(),
+                   vec![100, 103]);
+        assert_eq!(parser.interim_responses()[1].header("Link"),
+                   Some("</style.css>; rel=preload"));
+        let response = parser.finish().unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.headers(), &["Content-Length: 2"]);
+        assert_eq!(response.body_as_string().unwrap(), "Ok");
+
+        assert_eq!(parse_error_message(b"HTTP/1.1 100 Continue\r\n\r\n"),
+                   "Unexpected end of response at byte 25");
+        assert_eq!(parse_error_message(b"HTTP/1.1 100 Continue\r\n"),
+                   "Unexpected end of response at byte 23");
+    }
+
+    #[test]
+    fn test_parse_without_body() {
+        let mut parser = ResponseParser::new().set_http_method("HEAD");
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n";
+        assert_eq!(parser.feed(response).unwrap(), response.len());
+        assert!(parser.is_complete());
+        let response = parser.finish().unwrap();
+        assert_eq!(response.header("Content-Length"), Some("12"));
+        assert_eq!(response.body(), None);
+
+        for response in &[&b"HTTP/1.1 204 No Content\r\nContent-Length: 5\r\n\r\nHello"[..],
+                          &b"HTTP/1.1 304 Not Modified\r\nTransfer-Encoding: chunked\r\n\r\n"[..]] {
+            let mut parser = ResponseParser::new();
+            parser.feed(response).unwrap();
+            assert!(parser.is_complete());
+            assert_eq!(parser.finish().unwrap().body(), None);
+        }
+
+        let response = parse(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert_eq!(response.body(), Some(&b""[..]));
+    }
+
+    #[test]
+    fn test_parse_switching_protocols() {
+        let mut parser = ResponseParser::new();
+        let head = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        let mut buf = head.to_vec();
+        buf.extend_from_slice(b"\x81\x02Hi");
+        assert_eq!(parser.feed(&buf).unwrap(), head.len());
+        assert!(parser.is_complete());
+        assert!(parser.interim_responses().is_empty());
+        assert_eq!(parser.finish().unwrap().status_code(), 101);
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let mut parser = ResponseParser::new().set_max_headers(1);
+        let err = parser.feed(b"HTTP/1.1 200 OK\r\nA: 1\r\nB: 2\r\n\r\n").unwrap_err();
+        match err {
+            CabotError::HttpResponseParseError(message) => {
+                assert_eq!(message, "More than 1 headers at byte 23")
+            }
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        // The limit is reached before the end of the line is received
+        let mut parser = ResponseParser::new().set_max_head_size(32);
+        parser.feed(b"HTTP/1.1 200 OK\r\nX-Long: ").unwrap();
+        let err = parser.feed(&[b'a'; 64]).unwrap_err();
+        match err {
+            CabotError::HttpResponseParseError(message) => {
+                assert_eq!(message, "Line too long at byte 17")
+            }
+            err => panic!("Unexpected error {:?}", err),
+        }
+        assert!(parser.feed(b"\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_truncated_head() {
+        let response = parse(b"HTTP/1.1 302 Found\r\nLocation: /").unwrap();
+        assert_eq!(response.headers(), &["Location: /"]);
+        assert_eq!(response.body_as_string().unwrap(), "");
+    }
+
+    #[test]
+    fn test_build_http_response_from_string() {
+        let response = vec!["HTTP/1.1 200 Ok",
+                            "Content-Type: text/plain",
+                            "Content-Length: 12",
+                            "",
+                            "Hello World!"];
+        let response = response.join("\r\n");
+
+        let mut out = ResponseParser::new();
+        out.write_all(response.as_bytes()).unwrap();
+        let response = out.finish().unwrap();
+        assert_eq!(response.http_version(), "HTTP/1.1");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.status_line(), "200 Ok");
+        let headers: &[&str] = &["Content-Type: text/plain", "Content-Length: 12"];
+        assert_eq!(response.headers(), headers);
+        assert_eq!(response.body_as_string().unwrap(),
+                   "Hello World!".to_owned());
+
+    }
+
+    #[test]
+    fn test_build_http_header_obsolete_line_folding() {
+        let response = vec!["HTTP/1.1 200 Ok",
+                            "ows: https://tools.ietf.org/html/rfc7230",
+                            "  #section-3.2.4",
+                            "Content-Length: 12",
+                            "",
+                            "Hello World!"];
+        let response = response.join("\r\n");
+
+        let mut out = ResponseParser::new();
+        out.write_all(response.as_bytes()).unwrap();
+        let response = out.finish().unwrap();
+        assert_eq!(response.http_version(), "HTTP/1.1");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.status_line(), "200 Ok");
+        let headers: &[&str] = &["ows: https://tools.ietf.org/html/rfc7230 #section-3.2.4",
+                                 "Content-Length: 12"];
+        assert_eq!(response.headers(), headers);
+        assert_eq!(response.body_as_string().unwrap(),
+                   "Hello World!".to_owned());
+
+    }
+
+    #[test]
+    fn test_build_http_header_obsolete_line_folding_tab() {
+        let response = vec!["HTTP/1.1 200 Ok",
+                            "ows: https://tools.ietf.org/html/rfc7230",
+                            "\t#section-3.2.4",
+                            "Content-Length: 12",
+                            "",
+                            "Hello World!"];
+        let response = response.join("\r\n");
+
+        let mut out = ResponseParser::new();
+        out.write_all(response.as_bytes()).unwrap();
+        let response = out.finish().unwrap();
+        assert_eq!(response.http_version(), "HTTP/1.1");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.status_line(), "200 Ok");
+        let headers: &[&str] = &["ows: https://tools.ietf.org/html/rfc7230 #section-3.2.4",
+                                 "Content-Length: 12"];
+        assert_eq!(response.headers(), headers);
+        assert_eq!(response.body_as_string().unwrap(),
+                   "Hello World!".to_owned());
+
+    }
+
+    #[test]
+    fn test_build_http_no_response_body() {
+        let response = vec!["HTTP/1.1 302 Moved",
+                            "Location: https://tools.ietf.org/html/rfc7230#section-3.3"];
+        let response = response.join("\r\n");
+
+        let mut out = ResponseParser::new();
+        out.write_all(response.as_bytes()).unwrap();
+        let response = out.finish().unwrap();
+        assert_eq!(response.http_version(), "HTTP/1.1");
+        assert_eq!(response.status_code(), 302);
+        assert_eq!(response.status_line(), "302 Moved");
+        let headers: &[&str] = &["Location: https://tools.ietf.org/html/rfc7230#section-3.3"];
+        assert_eq!(response.headers(), headers);
+        assert_eq!(response.body_as_string().unwrap(), "");
+    }
+
+    fn random_split(response: &[u8]) -> CabotResult<Response> {
+        let mut parser = ResponseParser::new();
+        let mut remaining = response;
+        while !remaining.is_empty() {
+            let count = 1 + rand::random::<usize>() % remaining.len();
+            let consumed = parser.feed(&remaining[..count])?;
+            remaining = &remaining[consumed..];
+            if parser.is_complete() {
+                break;
+            }
+        }
+        parser.finish()
+    }
+
+    #[test]
+    fn test_fuzz_split() {
+        let responses: Vec<&[u8]> =
+            vec![b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nHello World!",
+                 b"HTTP/1.1 200 OK\nTransfer-Encoding: chunked\n\n5\nHello\n7\n World!\n0\n\n",
+                 b"HTTP/1.1 200 OK\r\nX-Folded: a\r\n\tb\r\n\r\nHello World!",
+                 b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5;a=b\r\nHello\r\n7\r\n World!\r\n0\r\nX-Trailer: 1\r\n\r\n"];
+        for response in responses {
+            let expected = parse(response).unwrap();
+            for _ in 0..100 {
+                let parsed = random_split(response).unwrap();
+                assert_eq!(parsed.headers(), expected.headers());
+                assert_eq!(parsed.body(), expected.body());
+                assert_eq!(parsed.body_as_string().unwrap(), "Hello World!");
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_mutations() {
+        let response: &[u8] = b"HTTP/1.1 200 OK\r\n\
+                                Content-Type: text/plain\r\n\
+                                Transfer-Encoding: chunked\r\n\
+                                Content-Length: 12\r\n\
+                                \r\n\
+                                5\r\nHello\r\n7\r\n World!\r\n0\r\n\r\n";
+        let alphabet = b"\r\n \t:;0123456789abcdefHTP/\xff";
+        for _ in 0..2000 {
+            let mut mutated = response.to_vec();
+            for _ in 0..(1 + rand::random::<usize>() % 4) {
+                let index = rand::random::<usize>() % mutated.len();
+                match rand::random::<usize>() % 3 {
+                    0 => mutated[index] = alphabet[rand::random::<usize>() % alphabet.len()],
+                    1 => {
+                        mutated.remove(index);
+                    }
+                    _ => mutated.truncate(index),
+                }
+                if mutated.is_empty() {
+                    break;
+                }
+            }
+            // Never panics, and the split does not change the result
+            match (parse(&mutated), random_split(&mutated)) {
+                (Ok(response), Ok(split)) => {
+                    assert_eq!(response.headers(), split.headers(), "{:?}", mutated);
+                    assert_eq!(response.body(), split.body(), "{:?}", mutated);
+                }
+                (Err(CabotError::HttpResponseParseError(err)),
+                 Err(CabotError::HttpResponseParseError(split))) => {
+                    assert_eq!(err, split, "{:?}", mutated)
+                }
+                (response, split) => {
+                    panic!("Unexpected results {:?} {:?} for {:?}", response, split, mutated)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Progress of the transfers.
+//!
+//! A [Monitor](struct.Monitor.html) reports the progress of the transfers
+//! to a callback, limits their rate, and aborts them if they are too slow.
+//!
+//! # Example
+//! ```
+//! use std::time::Duration;
+//! use cabot::Client;
+//! use cabot::progress::Monitor;
+//!
+//! let monitor = Monitor::new()
+//!     .set_callback(|progress| {
+//!         println!("{} bytes of {:?}", progress.downloaded(), progress.download_total())
+//!     })
+//!     .set_limit_rate(100 * 1024)
+//!     .set_speed_limit(1024, Duration::from_secs(30));
+//! let client = Client::new().set_monitor(monitor);
+//! ```
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::results::{CabotResult, CabotError};
+
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+
+/// The progress of a transfer.
+///
+/// Bodies are counted, the request and response heads and the chunked
+/// transfer coding are not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    upload_total: Option<u64>,
+    uploaded: u64,
+    download_total: Option<u64>,
+    downloaded: u64,
+    elapsed: Duration,
+    finished: bool,
+}
+
+
+impl Progress {
+    /// The length of the request body, if known.
+    pub fn upload_total(&self) -> Option<u64> {
+        self.upload_total
+    }
+
+    /// Bytes of the request body sent.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    /// The length of the response body, from its `Content-Length` header.
+    pub fn download_total(&self) -> Option<u64> {
+        self.download_total
+    }
+
+    /// Bytes of the response body received.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Time since the start of the transfer.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// True for the last report of the transfer.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Average upload speed, in bytes per second.
+    pub fn upload_speed(&self) -> u64 {
+        self.uploaded * 1000 / (millis(self.elapsed) + 1)
+    }
+
+    /// Average download speed, in bytes per second.
+    pub fn download_speed(&self) -> u64 {
+        self.downloaded * 1000 / (millis(self.elapsed) + 1)
+    }
+}
+
+
+/// Watch the transfers.
+#[derive(Clone)]
+pub struct Monitor {
+    callback: Option<Arc<Fn(&Progress) + Send + Sync>>,
+    limit_rate: Option<u64>,
+    speed_limit: Option<(u64, Duration)>,
+}
+
+
+impl Monitor {
+    /// Create a monitor that doesn't report nor limit the transfers.
+    pub fn new() -> Self {
+        Monitor {
+            callback: None,
+            limit_rate: None,
+            speed_limit: None,
+        }
+    }
+
+    /// Call the callback every time data are sent or received.
+    pub fn set_callback<F>(mut self, callback: F) -> Self
+        where F: Fn(&Progress) + Send + Sync + 'static
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Limit the upload and the download speed, in bytes per second.
+    pub fn set_limit_rate(mut self, bytes_per_second: u64) -> Self {
+        self.limit_rate = if bytes_per_second > 0 {
+            Some(bytes_per_second)
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Abort the transfer if it is slower than `bytes_per_second` during
+    /// `time`.
+    ///
+    /// The speed is checked when data are received, a server that doesn't
+    /// send anything is detected by the read timeout of the connection.
+    pub fn set_speed_limit(mut self, bytes_per_second: u64, time: Duration) -> Self {
+        self.speed_limit = Some((bytes_per_second, time));
+        self
+    }
+
+    /// Start a transfer, the length of the request body is reported if
+    /// known.
+    pub(crate) fn start(&self, upload_total: Option<u64>) -> Transfer {
+        let now = Instant::now();
+        Transfer {
+            monitor: self.clone(),
+            progress: Progress {
+                upload_total: upload_total,
+                uploaded: 0,
+                download_total: None,
+                downloaded: 0,
+                elapsed: Duration::from_secs(0),
+                finished: false,
+            },
+            started: now,
+            checkpoint: (now, 0),
+            slow_since: None,
+        }
+    }
+}
+
+
+/// A transfer in progress.
+pub(crate) struct Transfer {
+    monitor: Monitor,
+    progress: Progress,
+    started: Instant,
+    // Time and bytes transferred at the last speed check
+    checkpoint: (Instant, u64),
+    slow_since: Option<Instant>,
+}
+
+
+impl Transfer {
+    /// Report bytes of the request body sent.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::TimeoutError in case the transfer is too slow
+    pub fn sent(&mut self, count: usize) -> CabotResult<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        self.progress.uploaded += count as u64;
+        let uploaded = self.progress.uploaded;
+        self.limit_rate(uploaded);
+        self.update()
+    }
+
+    /// Report the length of the response body, once the head of the
+    /// response has been received.
+    pub fn set_download_total(&mut self, download_total: Option<u64>) {
+        self.progress.download_total = download_total;
+    }
+
+    /// Report bytes of the response body received, the response is
+    /// delimited by a [ResponseParser](../parser/struct.ResponseParser.html).
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::TimeoutError in case the transfer is too slow
+    pub fn received(&mut self, count: usize) -> CabotResult<()> {
+        self.progress.downloaded += count as u64;
+        let downloaded = self.progress.downloaded;
+        self.limit_rate(downloaded);
+        self.update()
+    }
+
+    /// Report the end of the transfer.
+    pub fn finish(&mut self) {
+        self.progress.elapsed = self.started.elapsed();
+        self.progress.finished = true;
+        if let Some(ref callback) = self.monitor.callback {
+            callback(&self.progress);
+        }
+    }
+
+    /// Sleep until the transferred bytes match the rate limit.
+    fn limit_rate(&self, transferred: u64) {
+        if let Some(rate) = self.monitor.limit_rate {
+            let expected = Duration::from_millis(transferred * 1000 / rate);
+            let elapsed = self.started.elapsed();
+            if expected > elapsed {
+                thread::sleep(expected - elapsed);
+            }
+        }
+    }
+
+    fn update(&mut self) -> CabotResult<()> {
+        let now = Instant::now();
+        self.progress.elapsed = now.duration_since(self.started);
+        if let Some(ref callback) = self.monitor.callback {
+            callback(&self.progress);
+        }
+        if let Some((bytes_per_second, time)) = self.monitor.speed_limit {
+            let transferred = self.progress.uploaded + self.progress.downloaded;
+            let (since, checked) = self.checkpoint;
+            let interval = millis(now.duration_since(since));
+            if interval >= 1000 {
+                let speed = (transferred - checked) * 1000 / interval;
+                if speed < bytes_per_second {
+                    let slow_since = *self.slow_since.get_or_insert(since);
+                    if now.duration_since(slow_since) >= time {
+                        return Err(CabotError::TimeoutError(format!("Operation too slow. Less \
+                                                                     than {} bytes/sec \
+                                                                     transferred the last {} \
+                                                                     seconds",
+                                                                    bytes_per_second,
+                                                                    time.as_secs())));
+                    }
+                } else {
+                    self.slow_since = None;
+                }
+                self.checkpoint = (now, transferred);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::transport::MockConnector;
+
+    /// The last progress reported for the response.
+    fn response_progress(response: &'static [u8]) -> Progress {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let monitor = {
+            let reports = reports.clone();
+            Monitor::new()
+                .set_callback(move |progress| reports.lock().unwrap().push(progress.clone()))
+        };
+        let client = Client::new()
+            .set_connector(MockConnector::new().add_response(response))
+            .set_monitor(monitor);
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        client.execute(&request).unwrap();
+        let last = reports.lock().unwrap().pop().unwrap();
+        assert!(last.is_finished());
+        last
+    }
+
+    #[test]
+    fn test_response_progress() {
+        let progress = response_progress(b"HTTP/1.1 200 OK\nContent-Length: 5\n\nHello");
+        assert_eq!((progress.downloaded(), progress.download_total()), (5, Some(5)));
+        let progress = response_progress(b"HTTP/1.1 100 Continue\r\nContent-Length: 99\r\n\r\n\
+                                           HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHello");
+        assert_eq!((progress.downloaded(), progress.download_total()), (5, Some(5)));
+        let progress = response_progress(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                           5\r\nHello\r\n0\r\n\r\n");
+        assert_eq!((progress.downloaded(), progress.download_total()), (5, None));
+    }
+
+    #[test]
+    fn test_transfer_progress() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let monitor = {
+            let reports = reports.clone();
+            Monitor::new().set_callback(move |progress| {
+                reports.lock()
+                    .unwrap()
+                    .push((progress.uploaded(), progress.downloaded(), progress.download_total()))
+            })
+        };
+        {
+            let mut transfer = monitor.start(Some(4));
+            transfer.sent(4).unwrap();
+            transfer.received(0).unwrap();
+            transfer.set_download_total(Some(12));
+            transfer.received(5).unwrap();
+            transfer.received(7).unwrap();
+            transfer.finish();
+        }
+        assert_eq!(*reports.lock().unwrap(),
+                   vec![(4, 0, None),
+                        (4, 0, None),
+                        (4, 5, Some(12)),
+                        (4, 12, Some(12)),
+                        (4, 12, Some(12))]);
+    }
+
+    #[test]
+    fn test_transfer_limit_rate() {
+        let monitor = Monitor::new().set_limit_rate(1000);
+        let mut transfer = monitor.start(None);
+        let started = Instant::now();
+        transfer.sent(200).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_transfer_speed_limit() {
+        let monitor = Monitor::new().set_speed_limit(1000, Duration::from_secs(0));
+        let mut transfer = monitor.start(None);
+        transfer.checkpoint.0 -= Duration::from_secs(1);
+        match transfer.sent(10) {
+            Err(CabotError::TimeoutError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
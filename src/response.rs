@@ -22,16 +22,85 @@
 
 use std::num::ParseIntError;
 
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde_json;
+
+use super::cache::CacheStatus;
+use super::charset;
+use super::compression;
+use super::timings::Timings;
 use super::results::{CabotResult, CabotError};
 
+/// The range of a partial content, parsed from a `Content-Range` header
+/// ([rfc7233](https://tools.ietf.org/html/rfc7233#section-4.2)).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentRange {
+    range: Option<(u64, u64)>,
+    complete_length: Option<u64>,
+}
+
+
+impl ContentRange {
+    /// Parse a `Content-Range` header value in bytes, such as
+    /// `bytes 0-499/1234`, `bytes 0-499/*` or `bytes */1234`.
+    pub fn from_header(value: &str) -> Option<Self> {
+        let mut split = value.trim().splitn(2, ' ');
+        if !split.next().unwrap().eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let mut split = split.next()?.trim().splitn(2, '/');
+        let range = split.next().unwrap().trim();
+        let complete_length = match split.next()?.trim() {
+            "*" => None,
+            length => Some(length.parse().ok()?),
+        };
+        let range = match range {
+            "*" => None,
+            range => {
+                let mut split = range.splitn(2, '-');
+                let first: u64 = split.next().unwrap().parse().ok()?;
+                let last: u64 = split.next()?.parse().ok()?;
+                if last < first || complete_length.map(|length| last >= length) == Some(true) {
+                    return None;
+                }
+                Some((first, last))
+            }
+        };
+        if range.is_none() && complete_length.is_none() {
+            return None;
+        }
+        Some(ContentRange {
+            range: range,
+            complete_length: complete_length,
+        })
+    }
+
+    /// The first and the last byte positions of the part, included,
+    /// `None` for an unsatisfied range.
+    pub fn range(&self) -> Option<(u64, u64)> {
+        self.range
+    }
+
+    /// The length of the complete content, if known.
+    pub fn complete_length(&self) -> Option<u64> {
+        self.complete_length
+    }
+}
+
+
 /// Represent the parsed HTTP response.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Response {
     http_version: String,
     status_code: usize,
     status_line: String,
     headers: Vec<String>,
     body: Option<Vec<u8>>,
+    raw_body: Option<Vec<u8>>,
+    cache_status: Option<CacheStatus>,
+    timings: Option<Timings>,
 }
 
 
@@ -48,9 +117,67 @@ impl Response {
             status_line: status_line,
             headers: headers,
             body: body,
+            raw_body: None,
+            cache_status: None,
+            timings: None,
         }
     }
 
+    /// Decode the body according to its `Content-Encoding` headers,
+    /// the body as received stays available using `raw_body()`.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::EncodingError in case of an unsupported encoding or
+    ///    a corrupted body.
+    pub(crate) fn decode_content(&mut self) -> CabotResult<()> {
+        if self.raw_body.is_some() {
+            // Decoded while it was received
+            return Ok(());
+        }
+        let decoded = {
+            let content_encoding = self.header_values("Content-Encoding").join(", ");
+            match self.body {
+                Some(ref body) if !content_encoding.is_empty() => {
+                    debug!("Decoding body using {}", content_encoding);
+                    compression::decode(content_encoding.as_str(), body)?
+                }
+                _ => return Ok(()),
+            }
+        };
+        self.raw_body = self.body.take();
+        self.body = Some(decoded);
+        Ok(())
+    }
+
+    /// Set the body decoded while it was received, and the body as
+    /// received.
+    pub(crate) fn set_decoded_body(&mut self, raw_body: Vec<u8>, body: Vec<u8>) {
+        self.raw_body = Some(raw_body);
+        self.body = Some(body);
+    }
+
+    /// How the response has been obtained from the
+    /// [cache](../cache/index.html) of the client, `None` if the client has
+    /// no cache or the request can't be cached.
+    pub fn cache_status(&self) -> Option<CacheStatus> {
+        self.cache_status
+    }
+
+    pub(crate) fn set_cache_status(&mut self, cache_status: CacheStatus) {
+        self.cache_status = Some(cache_status);
+    }
+
+    /// The [timings](../timings/struct.Timings.html) of the query,
+    /// `None` if the response has not been received from the network.
+    pub fn timings(&self) -> Option<&Timings> {
+        self.timings.as_ref()
+    }
+
+    pub(crate) fn set_timings(&mut self, timings: Timings) {
+        self.timings = Some(timings);
+    }
+
     /// The response http version such as `HTTP/1.1` extracted from the
     /// repsonse status line.
     pub fn http_version(&self) -> &str {
@@ -81,6 +208,51 @@ impl Response {
         headers
     }
 
+    /// Get the value of the first header named `name`.
+    ///
+    /// The header name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).into_iter().next()
+    }
+
+    /// Add a header to a received response,
+    /// used by [middlewares](../middleware/index.html).
+    pub fn add_header(&mut self, header: &str) {
+        self.headers.push(header.to_owned());
+    }
+
+    /// Remove the headers named `name` of a received response.
+    ///
+    /// The header name is case insensitive.
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|header| {
+            !header.splitn(2, ':').next().unwrap().trim().eq_ignore_ascii_case(name)
+        });
+    }
+
+    /// Get the values of all headers named `name`, in the received order.
+    ///
+    /// The header name is case insensitive.
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter_map(|header| {
+                let mut split = header.splitn(2, ':');
+                let key = split.next().unwrap();
+                match split.next() {
+                    Some(value) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// The range of a partial content, sent with the `206 Partial Content`
+    /// and the `416 Range Not Satisfiable` responses.
+    pub fn content_range(&self) -> Option<ContentRange> {
+        self.header("Content-Range").and_then(ContentRange::from_header)
+    }
+
     /// Get the body in raw format.
     pub fn body(&self) -> Option<&[u8]> {
         match self.body {
@@ -89,9 +261,88 @@ impl Response {
         }
     }
 
+    /// Get the body as received, before decoding its `Content-Encoding`.
+    ///
+    /// The raw body is the body if the content has not been decoded.
+    pub fn raw_body(&self) -> Option<&[u8]> {
+        match self.raw_body {
+            None => self.body(),
+            Some(ref body) => Some(body.as_slice()),
+        }
+    }
+
+    /// The charset of the body, read from its byte order mark, the
+    /// `Content-Type` header or a `<meta>` tag of an HTML body.
+    pub fn charset(&self) -> Option<String> {
+        let body = self.body().unwrap_or(&[]);
+        if let Some((charset, _)) = charset::from_bom(body) {
+            return Some(charset.to_owned());
+        }
+        let content_type = self.header("Content-Type");
+        if let Some(charset) = content_type.and_then(charset::from_content_type) {
+            return Some(charset);
+        }
+        match content_type {
+            Some(content_type) if !content_type.to_lowercase().contains("html") => None,
+            _ => charset::sniff_html(body),
+        }
+    }
+
+    fn decode_text(&self, lossy: bool) -> CabotResult<String> {
+        let body = match self.body {
+            None => return Ok("".to_owned()),
+            Some(ref body) => body.as_slice(),
+        };
+        let body = match charset::from_bom(body) {
+            Some((_, bom_len)) => &body[bom_len..],
+            None => body,
+        };
+        let charset = self.charset().unwrap_or("utf-8".to_owned());
+        charset::decode(body, charset.as_str(), lossy)
+    }
+
+    /// Decode the body in a String object using its
+    /// [charset](#method.charset), utf-8 if the charset is unknown.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::EncodingError in case the charset is not supported or
+    ///    the body is not valid in this charset.
+    pub fn text(&self) -> CabotResult<String> {
+        self.decode_text(false)
+    }
+
+    /// Decode the body in a String object using its
+    /// [charset](#method.charset), invalid sequences are replaced by
+    /// `U+FFFD` and unsupported charsets are decoded as utf-8.
+    pub fn text_lossy(&self) -> String {
+        match self.decode_text(true) {
+            Ok(text) => text,
+            Err(_) => {
+                let body = self.body().unwrap_or(&[]);
+                String::from_utf8_lossy(body).into_owned()
+            }
+        }
+    }
+
+    /// Deserialize the JSON body.
+    ///
+    /// Available with the `json` feature.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::JsonError in case the body is not a valid JSON
+    ///    representation of `T`.
+    #[cfg(feature = "json")]
+    pub fn json<T: DeserializeOwned>(&self) -> CabotResult<T> {
+        serde_json::from_slice(self.body().unwrap_or(&[]))
+            .map_err(|err| CabotError::JsonError(format!("{}", err)))
+    }
+
     /// Clone the body and retrieve it in a String object.
     ///
-    /// Important: Currently assume the body is encoded in utf-8.
+    /// Important: Currently assume the body is encoded in utf-8,
+    ///            use `text()` to decode the body using its charset.
     ///
     /// Errors:
     ///
@@ -196,6 +447,9 @@ impl ResponseBuilder {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "json")]
+    use std::collections::BTreeMap;
+
     use super::*;
 
     #[test]
@@ -215,6 +469,174 @@ mod tests {
         assert_eq!(response.body_as_string().unwrap(), "{}");
     }
 
+    #[test]
+    fn test_response_header() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     401,
+                                     "401 Unauthorized".to_owned(),
+                                     vec!["WWW-Authenticate: Basic realm=\"a\"".to_owned(),
+                                          "Content-Type: text/plain".to_owned(),
+                                          "www-authenticate: Digest realm=\"b\"".to_owned()],
+                                     None);
+
+        assert_eq!(response.header("content-type"), Some("text/plain"));
+        assert_eq!(response.header("Content-Length"), None);
+        assert_eq!(response.header_values("WWW-Authenticate"),
+                   vec!["Basic realm=\"a\"", "Digest realm=\"b\""]);
+    }
+
+    #[test]
+    fn test_response_add_remove_header() {
+        let mut response = Response::new("HTTP/1.1".to_owned(),
+                                         200,
+                                         "200 OK".to_owned(),
+                                         vec!["Set-Cookie: a=1".to_owned(),
+                                              "Content-Type: text/plain".to_owned(),
+                                              "set-cookie: b=2".to_owned()],
+                                         None);
+        response.remove_header("Set-Cookie");
+        response.add_header("X-Checked: yes");
+        assert_eq!(response.headers(), &["Content-Type: text/plain", "X-Checked: yes"]);
+    }
+
+    #[test]
+    fn test_content_range() {
+        let range = ContentRange::from_header("bytes 0-499/1234").unwrap();
+        assert_eq!(range.range(), Some((0, 499)));
+        assert_eq!(range.complete_length(), Some(1234));
+        let range = ContentRange::from_header("bytes 500-999/*").unwrap();
+        assert_eq!(range.range(), Some((500, 999)));
+        assert_eq!(range.complete_length(), None);
+        let range = ContentRange::from_header("bytes */1234").unwrap();
+        assert_eq!(range.range(), None);
+        assert_eq!(range.complete_length(), Some(1234));
+
+        assert_eq!(ContentRange::from_header("bytes */*"), None);
+        assert_eq!(ContentRange::from_header("bytes 500-499/1234"), None);
+        assert_eq!(ContentRange::from_header("bytes 0-1234/1234"), None);
+        assert_eq!(ContentRange::from_header("items 0-4/5"), None);
+        assert_eq!(ContentRange::from_header("bytes 0-"), None);
+    }
+
+    #[test]
+    fn test_response_content_range() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     206,
+                                     "206 Partial Content".to_owned(),
+                                     vec!["Content-Range: bytes 21010-47021/47022".to_owned()],
+                                     None);
+        assert_eq!(response.content_range().and_then(|range| range.range()),
+                   Some((21010, 47021)));
+    }
+
+    #[test]
+    fn test_response_decode_content() {
+        let raw: &[u8] = include_bytes!("../tests/fixtures/body.txt.gz");
+        let mut response = Response::new("HTTP/1.1".to_owned(),
+                                         200,
+                                         "200 Ok".to_owned(),
+                                         vec!["Content-Encoding: gzip".to_owned()],
+                                         Some(raw.to_vec()));
+        response.decode_content().unwrap();
+        let body: &[u8] = include_bytes!("../tests/fixtures/body.txt");
+        assert_eq!(response.body(), Some(body));
+        assert_eq!(response.raw_body(), Some(raw));
+    }
+
+    #[test]
+    fn test_response_decode_identity() {
+        let mut response = Response::new("HTTP/1.1".to_owned(),
+                                         200,
+                                         "200 Ok".to_owned(),
+                                         vec![],
+                                         Some(vec![123, 125]));
+        response.decode_content().unwrap();
+        let body: &[u8] = &[123, 125];
+        assert_eq!(response.body(), Some(body));
+        assert_eq!(response.raw_body(), Some(body));
+    }
+
+    #[test]
+    fn test_response_text() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     200,
+                                     "200 Ok".to_owned(),
+                                     vec!["Content-Type: text/plain; charset=ISO-8859-1"
+                                              .to_owned()],
+                                     Some(b"caf\xe9".to_vec()));
+        assert_eq!(response.charset(), Some("ISO-8859-1".to_owned()));
+        assert_eq!(response.text().unwrap(), "café");
+        assert!(response.body_as_string().is_err());
+    }
+
+    #[test]
+    fn test_response_text_no_body() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     204,
+                                     "204 No Content".to_owned(),
+                                     vec![],
+                                     None);
+        assert_eq!(response.text().unwrap(), "".to_string());
+        assert_eq!(response.text_lossy(), "".to_string());
+    }
+
+    #[test]
+    fn test_response_text_bom() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     200,
+                                     "200 Ok".to_owned(),
+                                     vec!["Content-Type: text/plain; charset=utf-8".to_owned()],
+                                     Some(b"\xFF\xFEc\x00a\x00".to_vec()));
+        assert_eq!(response.charset(), Some("utf-16le".to_owned()));
+        assert_eq!(response.text().unwrap(), "ca");
+    }
+
+    #[test]
+    fn test_response_text_html_meta() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     200,
+                                     "200 Ok".to_owned(),
+                                     vec!["Content-Type: text/html".to_owned()],
+                                     Some(b"<meta charset=\"shift_jis\">\x93\xfa".to_vec()));
+        assert_eq!(response.charset(), Some("shift_jis".to_owned()));
+        assert_eq!(response.text().unwrap(), "<meta charset=\"shift_jis\">日");
+    }
+
+    #[test]
+    fn test_response_text_lossy() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     200,
+                                     "200 Ok".to_owned(),
+                                     vec!["Content-Type: text/plain".to_owned()],
+                                     Some(b"caf\xe9".to_vec()));
+        assert_eq!(response.charset(), None);
+        assert!(response.text().is_err());
+        assert_eq!(response.text_lossy(), "caf\u{FFFD}");
+
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     200,
+                                     "200 Ok".to_owned(),
+                                     vec!["Content-Type: text/plain; charset=unknown"
+                                              .to_owned()],
+                                     Some(b"caf\xc3\xa9".to_vec()));
+        assert!(response.text().is_err());
+        assert_eq!(response.text_lossy(), "café");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_response_json() {
+        let response = Response::new("HTTP/1.1".to_owned(),
+                                     200,
+                                     "200 Ok".to_owned(),
+                                     vec!["Content-Type: application/json".to_owned()],
+                                     Some(b"{\"answer\": 42}".to_vec()));
+        let body: BTreeMap<String, u32> = response.json().unwrap();
+        assert_eq!(body.get("answer"), Some(&42));
+        let body: CabotResult<Vec<u32>> = response.json();
+        assert!(body.is_err());
+    }
+
     #[test]
     fn test_response_ok_no_body() {
         let response = Response::new("HTTP/1.1".to_owned(),
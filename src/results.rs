@@ -13,6 +13,19 @@ pub enum CabotError {
     UrlParseError(url::ParseError),
     HttpResponseParseError(String),
     EncodingError(String),
+    AuthenticationError(String),
+    NetrcError(String),
+    JsonError(String),
+    CacheError(String),
+    RangeError(String),
+    TimeoutError(String),
+    UpgradeError(String),
+    WebSocketError(String),
+    EventSourceError(String),
+    DnsError(String),
+    ConnectError(String),
+    WriteError(String),
+    StatusError(String),
 }
 
 /// Result used by method that can failed.
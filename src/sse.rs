@@ -0,0 +1,436 @@
+//! Server-Sent Events client.
+//!
+//! The [EventSource](struct.EventSource.html) reads the events of a
+//! `text/event-stream` response as they are received, and reconnects when
+//! the connection is lost, sending the id of the last event received in
+//! the `Last-Event-ID` header.
+//!
+//! The events are returned by an iterator, the connection errors are
+//! returned before reconnecting. The server stops the reconnections by
+//! answering `204 No Content`.
+//!
+//! # Example
+//! ```no_run
+//! use cabot::{Client, RequestBuilder};
+//! use cabot::sse::EventSource;
+//!
+//! let request = RequestBuilder::new("http://localhost/events").build().unwrap();
+//! for event in EventSource::new(Client::new(), &request) {
+//!     let event = event.unwrap();
+//!     println!("{}: {}", event.event_type(), event.data());
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+use super::client::Client;
+use super::request::Request;
+use super::results::{CabotResult, CabotError};
+use super::transport::ResponseStream;
+
+const BUFFER_PAGE_SIZE: usize = 4096;
+const BYTE_ORDER_MARK: &'static str = "\u{feff}";
+
+
+/// An event received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    event_type: String,
+    data: String,
+    id: String,
+}
+
+
+impl Event {
+    /// The type of the event, `message` by default.
+    pub fn event_type(&self) -> &str {
+        self.event_type.as_str()
+    }
+
+    /// The data of the event, the lines of its `data` fields.
+    pub fn data(&self) -> &str {
+        self.data.as_str()
+    }
+
+    /// The last event id received, empty if the server did not set it.
+    pub fn id(&self) -> &str {
+        self.id.as_str()
+    }
+}
+
+
+/// Parse the fields of an event stream.
+struct EventParser {
+    // The pending line, without its line ending
+    line: Vec<u8>,
+    // The previous line ended with a carriage return
+    after_cr: bool,
+    first_line: bool,
+    event_type: String,
+    data: String,
+    last_event_id: String,
+    retry: Option<Duration>,
+}
+
+
+impl EventParser {
+    fn new() -> Self {
+        EventParser {
+            line: Vec::new(),
+            after_cr: false,
+            first_line: true,
+            event_type: String::new(),
+            data: String::new(),
+            last_event_id: String::new(),
+            retry: None,
+        }
+    }
+
+    /// Drop the pending event of a lost connection, the last event id is
+    /// kept.
+    fn reset(&mut self) {
+        self.line.clear();
+        self.after_cr = false;
+        self.first_line = true;
+        self.event_type.clear();
+        self.data.clear();
+    }
+
+    /// Consume the bytes of the stream, return the events dispatched.
+    fn feed(&mut self, buf: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+        for &byte in buf {
+            if self.after_cr {
+                self.after_cr = false;
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+            match byte {
+                b'\r' | b'\n' => {
+                    self.after_cr = byte == b'\r';
+                    let line = mem::replace(&mut self.line, Vec::new());
+                    if let Some(event) = self.process_line(line) {
+                        events.push(event);
+                    }
+                }
+                byte => self.line.push(byte),
+            }
+        }
+        events
+    }
+
+    fn process_line(&mut self, line: Vec<u8>) -> Option<Event> {
+        let line = String::from_utf8_lossy(&line).into_owned();
+        let line = if self.first_line && line.starts_with(BYTE_ORDER_MARK) {
+            &line[BYTE_ORDER_MARK.len()..]
+        } else {
+            line.as_str()
+        };
+        self.first_line = false;
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            debug!("Event stream comment {}", &line[1..]);
+            return None;
+        }
+        let (field, value) = match line.find(':') {
+            Some(pos) => {
+                let value = &line[pos + 1..];
+                (&line[..pos], if value.starts_with(' ') { &value[1..] } else { value })
+            }
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event_type = value.to_owned(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.last_event_id = value.to_owned(),
+            "retry" if !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) => {
+                if let Ok(retry) = value.parse() {
+                    self.retry = Some(Duration::from_millis(retry));
+                }
+            }
+            _ => debug!("Ignoring event stream field {:?}", field),
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Event> {
+        let event_type = mem::replace(&mut self.event_type, String::new());
+        let mut data = mem::replace(&mut self.data, String::new());
+        if data.is_empty() {
+            return None;
+        }
+        data.pop();
+        Some(Event {
+            event_type: if event_type.is_empty() { "message".to_owned() } else { event_type },
+            data: data,
+            id: self.last_event_id.clone(),
+        })
+    }
+}
+
+
+fn stream_error(err: io::Error) -> CabotError {
+    match err.kind() {
+        io::ErrorKind::WouldBlock |
+        io::ErrorKind::TimedOut => CabotError::TimeoutError(format!("{}", err)),
+        _ => CabotError::IOError(format!("{}", err)),
+    }
+}
+
+
+/// Receive the events of a `text/event-stream` response.
+pub struct EventSource {
+    client: Client,
+    request: Request,
+    retry: Duration,
+    read_timeout: Option<Duration>,
+    stream: Option<ResponseStream>,
+    parser: EventParser,
+    events: VecDeque<Event>,
+    reconnect: bool,
+    closed: bool,
+}
+
+
+impl EventSource {
+    /// Construct a new `EventSource` receiving the events of the request
+    /// using the client, the connection is opened by the first call to
+    /// `next()`.
+    pub fn new(client: Client, request: &Request) -> Self {
+        EventSource {
+            client: client,
+            request: request.clone(),
+            retry: Duration::new(3, 0),
+            read_timeout: None,
+            stream: None,
+            parser: EventParser::new(),
+            events: VecDeque::new(),
+            reconnect: false,
+            closed: false,
+        }
+    }
+
+    /// Set the delay before reconnecting, 3 seconds by default, the server
+    /// may change it using the `retry` field.
+    pub fn set_retry(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Set the timeout of the reads of the stream, `None` to wait for the
+    /// events, the default.
+    pub fn set_read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// The id of the last event received, sent when reconnecting.
+    pub fn last_event_id(&self) -> &str {
+        self.parser.last_event_id.as_str()
+    }
+
+    /// Close the connection, the iterator stops.
+    pub fn close(&mut self) {
+        self.stream = None;
+        self.closed = true;
+    }
+
+    /// Open the event stream, `None` if the server answered `204 No
+    /// Content`.
+    fn connect(&mut self) -> CabotResult<Option<ResponseStream>> {
+        let mut request = self.request.clone();
+        request.remove_header("Last-Event-ID");
+        if !self.parser.last_event_id.is_empty() {
+            request.add_header(&format!("Last-Event-ID: {}", self.parser.last_event_id));
+        }
+        if request.header("Accept").is_none() {
+            request.add_header("Accept: text/event-stream");
+        }
+        if request.header("Cache-Control").is_none() {
+            request.add_header("Cache-Control: no-cache");
+        }
+        let stream = self.client.stream(&request)?;
+        match stream.response().status_code() {
+            200 => {}
+            204 => return Ok(None),
+            _ => {
+                return Err(CabotError::EventSourceError(format!("The server answered {}",
+                                                                stream.response()
+                                                                    .status_line())))
+            }
+        }
+        let content_type = stream.response().header("Content-Type").unwrap_or("");
+        let media_type = content_type.split(';').next().unwrap().trim();
+        if !media_type.eq_ignore_ascii_case("text/event-stream") {
+            return Err(CabotError::EventSourceError(format!("Unexpected Content-Type {:?}",
+                                                            content_type)));
+        }
+        stream.set_read_timeout(self.read_timeout).map_err(stream_error)?;
+        Ok(Some(stream))
+    }
+}
+
+
+impl Iterator for EventSource {
+    type Item = CabotResult<Event>;
+
+    /// Wait for the next event, reconnecting if the connection is closed.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::EventSourceError if the server did not answer an
+    ///    event stream, the iterator stops
+    ///  - The errors of the connection, the next call reconnects
+    fn next(&mut self) -> Option<CabotResult<Event>> {
+        let mut buf = [0; BUFFER_PAGE_SIZE];
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.closed {
+                return None;
+            }
+            if self.stream.is_none() {
+                if self.reconnect {
+                    info!("Reconnecting to the event stream in {:?}", self.retry);
+                    thread::sleep(self.retry);
+                }
+                self.reconnect = true;
+                self.parser.reset();
+                match self.connect() {
+                    Ok(Some(stream)) => self.stream = Some(stream),
+                    Ok(None) => {
+                        info!("The server closed the event stream");
+                        self.closed = true;
+                        return None;
+                    }
+                    Err(err @ CabotError::EventSourceError(_)) => {
+                        self.closed = true;
+                        return Some(Err(err));
+                    }
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            let read = self.stream.as_mut().unwrap().read(&mut buf);
+            match read {
+                Ok(0) => {
+                    info!("The event stream has been closed");
+                    self.stream = None;
+                }
+                Ok(count) => {
+                    let events = self.parser.feed(&buf[..count]);
+                    self.events.extend(events);
+                    if let Some(retry) = self.parser.retry.take() {
+                        self.retry = retry;
+                    }
+                }
+                Err(err) => {
+                    self.stream = None;
+                    return Some(Err(stream_error(err)));
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::request::RequestBuilder;
+    use super::super::testing::{MockServer, MockResponse};
+
+    fn event(event_type: &str, data: &str, id: &str) -> Event {
+        Event {
+            event_type: event_type.to_owned(),
+            data: data.to_owned(),
+            id: id.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let mut parser = EventParser::new();
+        let events = parser.feed(b"\xef\xbb\xbfdata: Hello\n\n: comment\nevent: greeting\r\n\
+                                   data:World\rdata\r\ndata:  !\nid: 1\nunknown: field\n\n\
+                                   retry: 1500\nretry: soon\nid\n\nevent: ignored\n\n");
+        assert_eq!(events,
+                   vec![event("message", "Hello", ""), event("greeting", "World\n\n !", "1")]);
+        assert_eq!(parser.retry, Some(Duration::from_millis(1500)));
+        assert_eq!(parser.last_event_id, "");
+
+        parser.feed(b"id: 2\r");
+        assert_eq!(parser.feed(b"\ndata: split\r"), vec![]);
+        assert_eq!(parser.feed(b"\n"), vec![]);
+        parser.feed(b"data: partial");
+        assert_eq!(parser.feed(b"\n\r\n"), vec![event("message", "split\npartial", "2")]);
+
+        parser.feed(b"data: lost\n");
+        parser.reset();
+        assert_eq!(parser.feed(b"\n"), vec![]);
+        assert_eq!(parser.last_event_id, "2");
+    }
+
+    #[test]
+    fn test_event_source() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK")
+            .add_header("Content-Type: text/event-stream; charset=utf-8")
+            .set_body(b"retry: 10\nid: 1\ndata: first\n\nid: 2\ndata: second\n\ndata: lost\n")
+            .set_chunked(8));
+        server.add_response(MockResponse::new("200 OK")
+            .add_header("Content-Type: text/event-stream")
+            .set_body(b"event: last\ndata: third\n\n"));
+        server.add_response(MockResponse::new("204 No Content"));
+
+        let request = RequestBuilder::new(&server.url("/events")).build().unwrap();
+        let events: Vec<Event> = EventSource::new(Client::new(), &request)
+            .map(|event| event.unwrap())
+            .collect();
+        assert_eq!(events,
+                   vec![event("message", "first", "1"),
+                        event("message", "second", "2"),
+                        event("last", "third", "2")]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].header("Accept"), Some("text/event-stream"));
+        assert_eq!(requests[0].header("Last-Event-ID"), None);
+        assert_eq!(requests[1].header("Last-Event-ID"), Some("2"));
+    }
+
+    #[test]
+    fn test_event_source_errors() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK")
+            .add_header("Content-Type: text/plain")
+            .set_body(b"data: Hello\n\n"));
+        let request = RequestBuilder::new(&server.url("/events")).build().unwrap();
+        let mut source = EventSource::new(Client::new(), &request);
+        match source.next() {
+            Some(Err(CabotError::EventSourceError(message))) => {
+                assert_eq!(message, "Unexpected Content-Type \"text/plain\"")
+            }
+            _ => panic!("The response should be rejected"),
+        }
+        assert!(source.next().is_none());
+
+        let mut source = EventSource::new(Client::new(), &request);
+        match source.next() {
+            Some(Err(CabotError::EventSourceError(message))) => {
+                assert_eq!(message, "The server answered 404 Not Found")
+            }
+            _ => panic!("The response should be rejected"),
+        }
+    }
+}
@@ -0,0 +1,772 @@
+//! An HTTP server to test the clients, enabled by the `testing` feature.
+//!
+//! The [MockServer](struct.MockServer.html) listens on localhost, from a
+//! thread of the test process, and answers the requests with the scripted
+//! [responses](struct.MockResponse.html), in order. A response can be
+//! delayed, sent using the chunked transfer coding, interrupted by
+//! closing the connection, or answer the byte ranges requested. It can
+//! also be preceded by interim responses once the head of the request is
+//! received, or sent without reading the body of the request, to test the
+//! `Expect: 100-continue` header. The requests received are recorded.
+//!
+//! A response can also accept a WebSocket handshake, and echo the
+//! messages received.
+//!
+//! The https server presents a certificate for `localhost` signed by a
+//! testing certificate authority, trusted by the
+//! [connector](struct.MockServer.html#method.connector) of the server.
+//!
+//! # Example
+//! ```
+//! use cabot::{Client, RequestBuilder};
+//! use cabot::testing::{MockServer, MockResponse};
+//!
+//! let server = MockServer::start();
+//! server.add_response(MockResponse::new("200 OK").set_body(b"Hello World!"));
+//!
+//! let request = RequestBuilder::new(&server.url("/hello")).build().unwrap();
+//! let response = Client::new().execute(&request).unwrap();
+//! assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+//! assert_eq!(server.requests()[0].request_uri(), "/hello");
+//! ```
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rustls::{Session, Certificate, NoClientAuth, PrivateKey, ServerConfig, ServerSession};
+
+use super::transport::TcpConnector;
+use super::websocket::{self, Frame};
+
+/// The certificate of the testing certificate authority, DER encoded.
+pub const CA_CERTIFICATE: &'static [u8] = include_bytes!("testing/ca.der");
+const SERVER_CERTIFICATE: &'static [u8] = include_bytes!("testing/localhost.der");
+const SERVER_PRIVATE_KEY: &'static [u8] = include_bytes!("testing/localhost.key.der");
+
+
+/// A response of the [MockServer](struct.MockServer.html).
+///
+/// The `Content-Length` or the `Transfer-Encoding` header, the
+/// `Accept-Ranges` header if ranges are accepted, and the
+/// `Connection: close` header are added when the response is sent. The
+/// body is not sent to `HEAD` requests.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status_line: String,
+    headers: Vec<String>,
+    body: Vec<u8>,
+    delay: Option<Duration>,
+    chunk_size: Option<usize>,
+    drop_after: Option<usize>,
+    accept_ranges: bool,
+    interim_responses: Vec<String>,
+    read_body: bool,
+    // Whether the permessage-deflate extension is accepted
+    websocket: Option<bool>,
+}
+
+
+impl MockResponse {
+    /// Construct a new `MockResponse` with a status line such as `200 OK`,
+    /// without body.
+    pub fn new(status_line: &str) -> Self {
+        MockResponse {
+            status_line: status_line.to_owned(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            delay: None,
+            chunk_size: None,
+            drop_after: None,
+            accept_ranges: false,
+            interim_responses: Vec::new(),
+            read_body: true,
+            websocket: None,
+        }
+    }
+
+    /// Construct a `MockResponse` accepting a WebSocket handshake, then
+    /// echoing the messages received until the client closes the
+    /// connection. The `permessage-deflate` extension is accepted if
+    /// `deflate` is true and the client offers it, the compressed
+    /// messages are echoed as received.
+    pub fn websocket_echo(deflate: bool) -> Self {
+        let mut response = MockResponse::new("101 Switching Protocols");
+        response.websocket = Some(deflate);
+        response
+    }
+
+    /// Add a header to the response.
+    pub fn add_header(mut self, header: &str) -> Self {
+        self.headers.push(header.to_owned());
+        self
+    }
+
+    /// Set the body of the response.
+    pub fn set_body(mut self, body: &[u8]) -> Self {
+        self.body = body.to_vec();
+        self
+    }
+
+    /// Wait before sending the response.
+    pub fn set_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Send the body using the chunked transfer coding, every chunk is
+    /// written separately.
+    pub fn set_chunked(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = if chunk_size > 0 { Some(chunk_size) } else { None };
+        self
+    }
+
+    /// Close the connection once `count` bytes of the response have been
+    /// sent, `0` to close it without answering.
+    pub fn set_drop_after(mut self, count: usize) -> Self {
+        self.drop_after = Some(count);
+        self
+    }
+
+    /// Answer a `Range` request with `206 Partial Content` and the bytes
+    /// requested, or `416 Range Not Satisfiable`, unless the `If-Range`
+    /// header of the request is not the `ETag` header of the response.
+    pub fn set_accept_ranges(mut self, accept_ranges: bool) -> Self {
+        self.accept_ranges = accept_ranges;
+        self
+    }
+
+    /// Send an interim response, such as `100 Continue`, once the head of
+    /// the request is received, before reading its body.
+    pub fn add_interim_response(mut self, status_line: &str) -> Self {
+        self.interim_responses.push(status_line.to_owned());
+        self
+    }
+
+    /// Whether the body of the request is read before answering, true by
+    /// default. Otherwise the response is sent once the head of the
+    /// request is received, and the request is recorded without body.
+    pub fn set_read_body(mut self, read_body: bool) -> Self {
+        self.read_body = read_body;
+        self
+    }
+
+    /// The response to the request, the bytes requested if the response
+    /// accepts ranges.
+    fn answer(&self, request: &MockRequest) -> MockResponse {
+        let mut response = self.clone();
+        let range = match request.header("Range") {
+            Some(range) if self.accept_ranges => range,
+            _ => return response,
+        };
+        if let Some(etag) = request.header("If-Range") {
+            if header(&self.headers, "ETag") != Some(etag) {
+                return response;
+            }
+        }
+        let length = self.body.len();
+        match byte_range(range, length) {
+            Some((first, last)) => {
+                response.status_line = "206 Partial Content".to_owned();
+                response.headers
+                    .push(format!("Content-Range: bytes {}-{}/{}", first, last, length));
+                response.body = self.body[first..last + 1].to_vec();
+            }
+            None => {
+                response.status_line = "416 Range Not Satisfiable".to_owned();
+                response.headers.push(format!("Content-Range: bytes */{}", length));
+                response.body = Vec::new();
+            }
+        }
+        response
+    }
+
+    /// The response as sent, split in the parts written separately.
+    fn to_parts(&self) -> Vec<Vec<u8>> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status_line);
+        for header in &self.headers {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+        if self.accept_ranges {
+            head.push_str("Accept-Ranges: bytes\r\n");
+        }
+        match self.chunk_size {
+            Some(_) => head.push_str("Transfer-Encoding: chunked\r\n"),
+            None => head.push_str(&format!("Content-Length: {}\r\n", self.body.len())),
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        let mut parts = vec![head.into_bytes()];
+        match self.chunk_size {
+            Some(chunk_size) => {
+                for chunk in self.body.chunks(chunk_size) {
+                    let mut part = format!("{:x}\r\n", chunk.len()).into_bytes();
+                    part.extend_from_slice(chunk);
+                    part.extend_from_slice(b"\r\n");
+                    parts.push(part);
+                }
+                parts.push(b"0\r\n\r\n".to_vec());
+            }
+            None => parts.push(self.body.clone()),
+        }
+        parts
+    }
+
+    fn write_to(&self, out: &mut Write, head_only: bool) -> io::Result<()> {
+        if let Some(delay) = self.delay {
+            thread::sleep(delay);
+        }
+        let mut parts = self.to_parts();
+        if head_only {
+            parts.truncate(1);
+        }
+        let mut remaining = self.drop_after.unwrap_or(usize::max_value());
+        for part in parts {
+            if part.len() >= remaining {
+                out.write_all(&part[..remaining])?;
+                return out.flush();
+            }
+            remaining -= part.len();
+            out.write_all(&part)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+
+/// A request received by the [MockServer](struct.MockServer.html).
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    http_method: String,
+    request_uri: String,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+
+impl MockRequest {
+    /// The HTTP verb of the request.
+    pub fn http_method(&self) -> &str {
+        self.http_method.as_str()
+    }
+
+    /// The path and the querystring of the request.
+    pub fn request_uri(&self) -> &str {
+        self.request_uri.as_str()
+    }
+
+    /// The request headers, in the received order.
+    pub fn headers(&self) -> Vec<&str> {
+        self.headers.iter().map(|header| header.as_str()).collect()
+    }
+
+    /// Get the value of the first header named `name`.
+    ///
+    /// The header name is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// The request body, decoded if it was sent using the chunked transfer
+    /// coding.
+    pub fn body(&self) -> &[u8] {
+        self.body.as_slice()
+    }
+
+    /// Read the head of a request, `None` if the connection is closed
+    /// first.
+    fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let (http_method, request_uri) = {
+            let mut split = line.split_whitespace();
+            match (split.next(), split.next()) {
+                (Some(http_method), Some(request_uri)) => {
+                    (http_method.to_owned(), request_uri.to_owned())
+                }
+                _ => return Err(invalid_data(format!("Invalid request line {:?}", line))),
+            }
+        };
+        let mut request = MockRequest {
+            http_method: http_method,
+            request_uri: request_uri,
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let header = line.trim_right();
+            if header.is_empty() {
+                break;
+            }
+            request.headers.push(header.to_owned());
+        }
+        Ok(Some(request))
+    }
+
+    /// Read the body of the request.
+    fn read_body<R: BufRead>(&mut self, reader: &mut R) -> io::Result<()> {
+        let mut line = String::new();
+        let chunked = self.header("Transfer-Encoding")
+            .map(|coding| coding.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+        if chunked {
+            loop {
+                line.clear();
+                reader.read_line(&mut line)?;
+                let size = usize::from_str_radix(line.trim(), 16)
+                    .map_err(|_| invalid_data(format!("Invalid chunk size {:?}", line)))?;
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk)?;
+                if size == 0 {
+                    break;
+                }
+                self.body.extend_from_slice(&chunk[..size]);
+            }
+        } else if let Some(length) = self.header("Content-Length") {
+            let length = length.parse()
+                .map_err(|_| invalid_data(format!("Invalid Content-Length {:?}", length)))?;
+            self.body = vec![0; length];
+            reader.read_exact(&mut self.body)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Get the value of the first header named `name`, case insensitive.
+fn header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .filter_map(|header| {
+            let mut split = header.splitn(2, ':');
+            let key = split.next().unwrap();
+            match split.next() {
+                Some(value) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+
+/// The first and the last byte of a `bytes=first-last` range of a content
+/// of `length` bytes, the last byte is optional. `None` if the range is
+/// not satisfiable.
+fn byte_range(range: &str, length: usize) -> Option<(usize, usize)> {
+    if !range.starts_with("bytes=") {
+        return None;
+    }
+    let mut bounds = range["bytes=".len()..].splitn(2, '-');
+    let first = bounds.next()?.trim().parse().ok()?;
+    let end = length.checked_sub(1)?;
+    let last = match bounds.next()?.trim() {
+        "" => end,
+        last => cmp::min(last.parse().ok()?, end),
+    };
+    if first > last {
+        return None;
+    }
+    Some((first, last))
+}
+
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+/// An HTTP/1.1 server answering scripted responses.
+///
+/// The server is stopped when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    scheme: &'static str,
+    responses: Arc<Mutex<VecDeque<MockResponse>>>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+
+impl MockServer {
+    /// Start an http server.
+    pub fn start() -> Self {
+        MockServer::listen("http", None)
+    }
+
+    /// Start an https server, using a certificate for `localhost`.
+    pub fn start_https() -> Self {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(vec![Certificate(SERVER_CERTIFICATE.to_vec())],
+                               PrivateKey(SERVER_PRIVATE_KEY.to_vec()));
+        MockServer::listen("https", Some(Arc::new(config)))
+    }
+
+    fn listen(scheme: &'static str, tls_config: Option<Arc<ServerConfig>>) -> Self {
+        // Listen on the address the clients connect to
+        let addr = ("localhost", 0).to_socket_addrs().unwrap().next().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let server = MockServer {
+            addr: listener.local_addr().unwrap(),
+            scheme: scheme,
+            responses: Arc::new(Mutex::new(VecDeque::new())),
+            requests: Arc::new(Mutex::new(Vec::new())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let responses = server.responses.clone();
+        let requests = server.requests.clone();
+        let stopped = server.stopped.clone();
+        thread::spawn(move || for sock in listener.incoming() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let sock = match sock {
+                Ok(sock) => sock,
+                Err(_) => continue,
+            };
+            let tls_config = tls_config.clone();
+            let responses = responses.clone();
+            let requests = requests.clone();
+            thread::spawn(move || {
+                let result = match tls_config {
+                    Some(config) => {
+                        let mut stream = TlsStream {
+                            session: ServerSession::new(&config),
+                            sock: sock,
+                        };
+                        serve(&mut stream, &responses, &requests)
+                    }
+                    None => serve(&mut { sock }, &responses, &requests),
+                };
+                if let Err(err) = result {
+                    debug!("Mock server connection error: {}", err);
+                }
+            });
+        });
+        server
+    }
+
+    /// The url of the path on the server, such as `http://localhost:8000/`.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://localhost:{}{}", self.scheme, self.addr.port(), path)
+    }
+
+    /// A connector trusting the certificate of the https server.
+    pub fn connector(&self) -> TcpConnector {
+        TcpConnector::new().add_root_certificate(CA_CERTIFICATE)
+    }
+
+    /// Add the response to the next request, requests without a response
+    /// are answered `404 Not Found`.
+    pub fn add_response(&self, response: MockResponse) {
+        self.responses.lock().unwrap().push_back(response);
+    }
+
+    /// The requests received, in order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the listening thread
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+
+/// Answer the request of a connection.
+fn serve<S: Read + Write>(stream: &mut S,
+                          responses: &Mutex<VecDeque<MockResponse>>,
+                          requests: &Mutex<Vec<MockRequest>>)
+                          -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request = match MockRequest::read_head(&mut reader)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let response = responses.lock()
+        .unwrap()
+        .pop_front()
+        .unwrap_or_else(|| MockResponse::new("404 Not Found"));
+    for status_line in &response.interim_responses {
+        reader.get_mut().write_all(format!("HTTP/1.1 {}\r\n\r\n", status_line).as_bytes())?;
+        reader.get_mut().flush()?;
+    }
+    if response.read_body {
+        request.read_body(&mut reader)?;
+    }
+    requests.lock().unwrap().push(request.clone());
+    match response.websocket {
+        Some(deflate) => websocket_echo(&mut reader, &request, &response, deflate),
+        None => {
+            response.answer(&request)
+                .write_to(reader.get_mut(), request.http_method == "HEAD")
+        }
+    }
+}
+
+
+/// Accept the WebSocket handshake, then echo the frames received.
+fn websocket_echo<S: Read + Write>(reader: &mut BufReader<S>,
+                                   request: &MockRequest,
+                                   response: &MockResponse,
+                                   deflate: bool)
+                                   -> io::Result<()> {
+    let key = request.header("Sec-WebSocket-Key")
+        .ok_or_else(|| invalid_data("Missing Sec-WebSocket-Key header".to_owned()))?;
+    let mut head = format!("HTTP/1.1 {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                            Sec-WebSocket-Accept: {}\r\n",
+                           response.status_line,
+                           websocket::accept_key(key));
+    let offered = request.header("Sec-WebSocket-Extensions").unwrap_or("");
+    if deflate && offered.contains("permessage-deflate") {
+        head.push_str("Sec-WebSocket-Extensions: permessage-deflate\r\n");
+    }
+    for header in &response.headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+    reader.get_mut().write_all(head.as_bytes())?;
+    reader.get_mut().flush()?;
+
+    let mut incoming = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let decoded = Frame::decode(&incoming, usize::max_value())
+            .map_err(|err| invalid_data(format!("{:?}", err)))?;
+        let frame = match decoded {
+            Some((frame, consumed)) => {
+                incoming.drain(..consumed);
+                frame
+            }
+            None => {
+                match reader.read(&mut buf)? {
+                    0 => return Ok(()),
+                    count => incoming.extend_from_slice(&buf[..count]),
+                }
+                continue;
+            }
+        };
+        let (opcode, closed) = match frame.opcode {
+            // Ping
+            0x9 => (0xA, false),
+            // Close
+            0x8 => (0x8, true),
+            opcode => (opcode, false),
+        };
+        let echo = Frame::new(frame.fin, frame.rsv, opcode, frame.payload);
+        reader.get_mut().write_all(&echo.encode(None))?;
+        reader.get_mut().flush()?;
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+
+/// The server side of a TLS connection.
+struct TlsStream {
+    session: ServerSession,
+    sock: TcpStream,
+}
+
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            while self.session.wants_write() {
+                self.session.write_tls(&mut self.sock)?;
+            }
+            let count = self.session.read(buf)?;
+            if count > 0 {
+                return Ok(count);
+            }
+            if self.session.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+            self.session
+                .process_new_packets()
+                .map_err(|err| invalid_data(format!("{}", err)))?;
+        }
+    }
+}
+
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.session.write(buf)?;
+        self.flush()?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.flush()?;
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.sock)?;
+        }
+        self.sock.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use super::super::client::Client;
+    use super::super::http;
+    use super::super::request::RequestBuilder;
+    use super::super::results::CabotError;
+
+    #[test]
+    fn test_mock_server() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("201 Created")
+            .add_header("Location: /items/1")
+            .set_body(b"Created"));
+        let request = RequestBuilder::new(&server.url("/items?debug=1"))
+            .set_http_method("POST")
+            .add_header("Content-Type: text/plain")
+            .set_body(b"Hello")
+            .build()
+            .unwrap();
+        let response = Client::new().execute(&request).unwrap();
+        assert_eq!(response.status_code(), 201);
+        assert_eq!(response.header("Location"), Some("/items/1"));
+        assert_eq!(response.body_as_string().unwrap(), "Created");
+
+        let response = Client::new().execute(&request).unwrap();
+        assert_eq!(response.status_code(), 404);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].http_method(), "POST");
+        assert_eq!(requests[0].request_uri(), "/items?debug=1");
+        assert_eq!(requests[0].header("content-type"), Some("text/plain"));
+        assert_eq!(requests[0].body(), b"Hello");
+    }
+
+    #[test]
+    fn test_mock_server_chunked_request() {
+        let server = MockServer::start();
+        let request = RequestBuilder::new(&server.url("/"))
+            .set_http_method("PUT")
+            .set_body_stream(&b"Hello World!"[..], None)
+            .build()
+            .unwrap();
+        Client::new().execute(&request).unwrap();
+        assert_eq!(server.requests()[0].header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(server.requests()[0].body(), b"Hello World!");
+    }
+
+    #[test]
+    fn test_mock_response_chunked() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello World!").set_chunked(5));
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let mut out = Vec::new();
+        http::http_query(&request, &mut out, false).unwrap();
+        assert!(out.ends_with(b"\r\n\r\n5\r\nHello\r\n5\r\n Worl\r\n2\r\nd!\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_mock_response_delay() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("200 OK").set_delay(Duration::from_millis(100)));
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let started = Instant::now();
+        let response = Client::new().execute(&request).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(response.timings().unwrap().first_byte().unwrap() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_mock_response_accept_ranges() {
+        let server = MockServer::start();
+        let response = MockResponse::new("200 OK")
+            .add_header("ETag: \"v1\"")
+            .set_body(b"Hello World!")
+            .set_accept_ranges(true);
+        for _ in 0..4 {
+            server.add_response(response.clone());
+        }
+        let client = Client::new();
+        let request = RequestBuilder::new(&server.url("/"))
+            .set_http_method("HEAD")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.header("Content-Length"), Some("12"));
+        assert_eq!(response.body(), None);
+
+        let request = RequestBuilder::new(&server.url("/")).range(6, None).build().unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.status_code(), 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 6-11/12"));
+        assert_eq!(response.body_as_string().unwrap(), "World!");
+
+        let request = RequestBuilder::new(&server.url("/"))
+            .range(6, None)
+            .add_header("If-Range: \"v2\"")
+            .build()
+            .unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+
+        let request = RequestBuilder::new(&server.url("/")).range(12, None).build().unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.status_code(), 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */12"));
+    }
+
+    #[test]
+    fn test_mock_response_drop_after() {
+        let server = MockServer::start();
+        let response = MockResponse::new("200 OK").set_body(b"Hello World!");
+        let head_length = response.to_parts()[0].len();
+        server.add_response(response.set_drop_after(head_length + 5));
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        let mut out = Vec::new();
+        http::http_query(&request, &mut out, false).unwrap();
+        assert_eq!(out.len(), head_length + 5);
+        assert!(out.ends_with(b"\r\n\r\nHello"));
+    }
+
+    #[test]
+    fn test_mock_server_https() {
+        let server = MockServer::start_https();
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello World!"));
+        let request = RequestBuilder::new(&server.url("/secure")).build().unwrap();
+        let response = Client::new().set_connector(server.connector()).execute(&request).unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+        assert!(response.timings().unwrap().tls_handshake().is_some());
+        assert_eq!(server.requests()[0].request_uri(), "/secure");
+    }
+
+    #[test]
+    fn test_mock_server_https_untrusted() {
+        let server = MockServer::start_https();
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        match Client::new().execute(&request) {
+            Err(CabotError::CertificateError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(server.requests().is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Timings of the phases of a query.
+//!
+//! Like curl, every phase is measured from the start of the query: the
+//! connection is established after the DNS lookup, so its time includes
+//! the DNS lookup time.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+
+/// The timings of a query, and the address of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timings {
+    dns_lookup: Duration,
+    connect: Duration,
+    tls_handshake: Option<Duration>,
+    first_byte: Option<Duration>,
+    total: Duration,
+    remote_addr: Option<SocketAddr>,
+}
+
+
+impl Timings {
+    /// Time until the name of the server has been resolved.
+    pub fn dns_lookup(&self) -> Duration {
+        self.dns_lookup
+    }
+
+    /// Time until the TCP connection has been established.
+    pub fn connect(&self) -> Duration {
+        self.connect
+    }
+
+    /// Time until the TLS handshake has been completed, `None` for http.
+    pub fn tls_handshake(&self) -> Option<Duration> {
+        self.tls_handshake
+    }
+
+    /// Time until the first byte of the response has been received,
+    /// `None` if the server sent nothing.
+    pub fn first_byte(&self) -> Option<Duration> {
+        self.first_byte
+    }
+
+    /// Time until the response has been completely received.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// The address of the server.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+}
+
+
+/// Measure the phases of a query, the DNS lookup and the connection are
+/// recorded by the [connectors](../transport/trait.Connector.html).
+pub struct Timer {
+    started: Instant,
+    timings: Timings,
+}
+
+
+impl Timer {
+    /// Start measuring a query.
+    pub(crate) fn new() -> Self {
+        Timer {
+            started: Instant::now(),
+            timings: Timings {
+                dns_lookup: Duration::from_secs(0),
+                connect: Duration::from_secs(0),
+                tls_handshake: None,
+                first_byte: None,
+                total: Duration::from_secs(0),
+                remote_addr: None,
+            },
+        }
+    }
+
+    /// Record the end of the DNS lookup.
+    pub fn dns_lookup(&mut self) {
+        self.timings.dns_lookup = self.started.elapsed();
+    }
+
+    /// Record the connection to the server, and its address if any.
+    pub fn connect(&mut self, remote_addr: Option<SocketAddr>) {
+        self.timings.connect = self.started.elapsed();
+        self.timings.remote_addr = remote_addr;
+    }
+
+    pub(crate) fn tls_handshake(&mut self) {
+        self.timings.tls_handshake = Some(self.started.elapsed());
+    }
+
+    /// Record the first byte, following bytes are ignored.
+    pub(crate) fn first_byte(&mut self) {
+        if self.timings.first_byte.is_none() {
+            self.timings.first_byte = Some(self.started.elapsed());
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> Timings {
+        self.timings.total = self.started.elapsed();
+        self.timings
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Timer::new();
+        timer.dns_lookup();
+        timer.connect(Some("127.0.0.1:80".parse().unwrap()));
+        timer.first_byte();
+        let first_byte = timer.timings.first_byte;
+        thread::sleep(Duration::from_millis(10));
+        timer.first_byte();
+        let timings = timer.finish();
+        assert!(timings.dns_lookup() <= timings.connect());
+        assert_eq!(timings.tls_handshake(), None);
+        assert_eq!(timings.first_byte(), first_byte);
+        assert!(timings.total() >= Duration::from_millis(10));
+        assert_eq!(timings.remote_addr(), Some("127.0.0.1:80".parse().unwrap()));
+    }
+}
@@ -0,0 +1,194 @@
+//! Trace of the exchanges with the servers.
+//!
+//! A [Tracer](struct.Tracer.html) receives the bytes sent and received on
+//! the connections of the client, as written and read by the client, and
+//! information on the connections such as the TLS protocol negotiated.
+//!
+//! # Example
+//! ```
+//! use cabot::Client;
+//! use cabot::trace::{Tracer, TraceEvent};
+//!
+//! let tracer = Tracer::new(|event: &TraceEvent| match *event {
+//!     TraceEvent::Info(message) => println!("== Info: {}", message),
+//!     TraceEvent::Sent(bytes) => println!("=> Send {} bytes", bytes.len()),
+//!     TraceEvent::Received(bytes) => println!("<= Recv {} bytes", bytes.len()),
+//! });
+//! let client = Client::new().set_tracer(tracer);
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::transport::Stream;
+
+
+/// An event of a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent<'a> {
+    /// Information on the connection.
+    Info(&'a str),
+    /// Bytes sent to the server, before their TLS encryption.
+    Sent(&'a [u8]),
+    /// Bytes received from the server, after their TLS decryption.
+    Received(&'a [u8]),
+}
+
+
+/// Report the events of the connections to a callback, the tracers cloned
+/// share their callback.
+#[derive(Clone)]
+pub struct Tracer {
+    callback: Arc<Fn(&TraceEvent) + Send + Sync>,
+}
+
+
+impl Tracer {
+    /// Construct a new `Tracer` calling the callback with every event.
+    pub fn new<F>(callback: F) -> Self
+        where F: Fn(&TraceEvent) + Send + Sync + 'static
+    {
+        Tracer { callback: Arc::new(callback) }
+    }
+
+    pub(crate) fn info(&self, message: &str) {
+        (self.callback)(&TraceEvent::Info(message))
+    }
+}
+
+
+/// A stream reporting the bytes sent and received to a tracer.
+pub(crate) struct TracedStream {
+    stream: Box<Stream>,
+    tracer: Tracer,
+}
+
+
+impl TracedStream {
+    pub fn new(stream: Box<Stream>, tracer: Tracer) -> Self {
+        TracedStream {
+            stream: stream,
+            tracer: tracer,
+        }
+    }
+}
+
+
+impl Read for TracedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.stream.read(buf)?;
+        if count > 0 {
+            (self.tracer.callback)(&TraceEvent::Received(&buf[..count]));
+        }
+        Ok(count)
+    }
+}
+
+
+impl Write for TracedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.stream.write(buf)?;
+        if count > 0 {
+            (self.tracer.callback)(&TraceEvent::Sent(&buf[..count]));
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+
+impl Stream for TracedStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.stream.remote_addr()
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.stream.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+    use super::super::testing::{MockServer, MockResponse};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Info(String),
+        Sent(Vec<u8>),
+        Received(Vec<u8>),
+    }
+
+    fn trace(https: bool) -> Vec<Event> {
+        let server = if https {
+            MockServer::start_https()
+        } else {
+            MockServer::start()
+        };
+        server.add_response(MockResponse::new("200 OK").set_body(b"Hello"));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let traced = events.clone();
+        let tracer = Tracer::new(move |event: &TraceEvent| {
+            traced.lock().unwrap().push(match *event {
+                TraceEvent::Info(message) => Event::Info(message.to_owned()),
+                TraceEvent::Sent(bytes) => Event::Sent(bytes.to_vec()),
+                TraceEvent::Received(bytes) => Event::Received(bytes.to_vec()),
+            })
+        });
+        let client = Client::new().set_connector(server.connector()).set_tracer(tracer);
+        let request = RequestBuilder::new(&server.url("/")).build().unwrap();
+        assert_eq!(client.execute(&request).unwrap().body_as_string().unwrap(), "Hello");
+        let events = events.lock().unwrap().drain(..).collect();
+        events
+    }
+
+    #[test]
+    fn test_trace() {
+        let events = trace(false);
+        match events[0] {
+            Event::Info(ref message) => assert!(message.starts_with("Connected to ")),
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        let mut sent = Vec::new();
+        let mut received = Vec::new();
+        for event in &events[1..] {
+            match *event {
+                Event::Sent(ref bytes) => sent.extend_from_slice(bytes),
+                Event::Received(ref bytes) => received.extend_from_slice(bytes),
+                ref event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        assert!(sent.starts_with(b"GET / HTTP/1.1\r\n"));
+        assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(received.ends_with(b"\r\n\r\nHello"));
+    }
+
+    #[test]
+    fn test_trace_https() {
+        let events = trace(true);
+        match events[1] {
+            Event::Info(ref message) => {
+                assert!(message.starts_with("TLS handshake with localhost complete, protocol "))
+            }
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        match events[2] {
+            Event::Sent(ref bytes) => assert!(bytes.starts_with(b"GET / HTTP/1.1\r\n")),
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,673 @@
+//! Connections to the servers.
+//!
+//! A [Connector](trait.Connector.html) opens the stream used to send a
+//! request, the [Client](../client/struct.Client.html) uses a TCP
+//! connection by default, or a Unix domain socket using the
+//! [UnixConnector](struct.UnixConnector.html). The TLS session of https
+//! requests is established over the stream.
+//!
+//! The [MockConnector](struct.MockConnector.html) replays canned responses
+//! without a server.
+//!
+//! # Example
+//! ```
+//! use cabot::{Client, RequestBuilder};
+//! use cabot::transport::MockConnector;
+//!
+//! let connector = MockConnector::new()
+//!     .add_response(b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nHello World!");
+//! let client = Client::new().set_connector(connector.clone());
+//! let request = RequestBuilder::new("http://localhost/hello").build().unwrap();
+//! let response = client.execute(&request).unwrap();
+//! assert_eq!(response.body_as_string().unwrap(), "Hello World!");
+//! assert!(connector.requests()[0].starts_with(b"GET /hello HTTP/1.1\r\n"));
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::compression;
+use super::dns::Resolver;
+use super::parser::ResponseParser;
+use super::progress::Transfer;
+use super::request::Request;
+use super::response::Response;
+use super::results::{CabotResult, CabotError};
+use super::timings::Timer;
+
+
+/// A connection to a server.
+pub trait Stream: Read + Write + Send {
+    /// The address of the server, if any.
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The timeout of the reads, `None` for blocking reads.
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Set the timeout of the reads, ignored if the stream does not
+    /// support it.
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+impl Stream for TcpStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+
+/// Open the connections to the servers.
+pub trait Connector: Send + Sync {
+    /// Open a stream to the authority of the request, the DNS lookup and
+    /// the connection are recorded in the timer.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::DnsError in case the authority can't be resolved
+    ///  - CabotError::ConnectError in case the connection is refused
+    fn connect(&self, request: &Request, timer: &mut Timer, verbose: bool)
+               -> CabotResult<Box<Stream>>;
+
+    /// Root certificates trusted for the https requests, DER encoded, in
+    /// addition to the Mozilla root certificates.
+    fn root_certificates(&self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+
+impl<C: Connector + ?Sized> Connector for Arc<C> {
+    fn connect(&self, request: &Request, timer: &mut Timer, verbose: bool)
+               -> CabotResult<Box<Stream>> {
+        (**self).connect(request, timer, verbose)
+    }
+
+    fn root_certificates(&self) -> Vec<Vec<u8>> {
+        (**self).root_certificates()
+    }
+}
+
+
+/// Connect to the servers using TCP.
+#[derive(Debug, Clone)]
+pub struct TcpConnector {
+    read_timeout: Option<Duration>,
+    root_certificates: Vec<Vec<u8>>,
+}
+
+
+impl TcpConnector {
+    /// Construct a new `TcpConnector`, reading with a 5 seconds timeout.
+    pub fn new() -> Self {
+        TcpConnector {
+            read_timeout: Some(Duration::new(5, 0)),
+            root_certificates: Vec::new(),
+        }
+    }
+
+    /// Set the timeout of the reads of the connections, `None` for blocking
+    /// reads.
+    pub fn set_read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Trust the DER encoded root certificate for the https requests,
+    /// such as the certificate of a private certificate authority.
+    pub fn add_root_certificate(mut self, certificate: &[u8]) -> Self {
+        self.root_certificates.push(certificate.to_vec());
+        self
+    }
+}
+
+
+impl Connector for TcpConnector {
+    fn connect(&self, request: &Request, timer: &mut Timer, verbose: bool)
+               -> CabotResult<Box<Stream>> {
+        let resolver = Resolver::new(verbose);
+        let addr = resolver.get_addr(request.authority())?;
+        timer.dns_lookup();
+
+        info!("Connecting to {}", addr);
+        let stream = TcpStream::connect(addr)
+            .map_err(|err| CabotError::ConnectError(format!("{}: {}", addr, err)))?;
+        timer.connect(Some(addr));
+        stream.set_read_timeout(self.read_timeout)
+            .map_err(|err| CabotError::IOError(format!("{}", err)))?;
+        Ok(Box::new(stream))
+    }
+
+    fn root_certificates(&self) -> Vec<Vec<u8>> {
+        self.root_certificates.clone()
+    }
+}
+
+
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+enum UnixAddr {
+    Path(PathBuf),
+    #[cfg(target_os = "linux")]
+    Abstract(String),
+}
+
+
+/// Connect to a Unix domain socket, whatever the authority of the
+/// requests.
+///
+/// The request line and the `Host` header are still built from the url.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixConnector {
+    addr: UnixAddr,
+    read_timeout: Option<Duration>,
+}
+
+
+#[cfg(unix)]
+impl UnixConnector {
+    /// Construct a new `UnixConnector` to the socket file at `path`,
+    /// reading with a 5 seconds timeout.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        UnixConnector {
+            addr: UnixAddr::Path(path.as_ref().to_path_buf()),
+            read_timeout: Some(Duration::new(5, 0)),
+        }
+    }
+
+    /// Construct a new `UnixConnector` to the socket in the abstract
+    /// namespace named `name`, reading with a 5 seconds timeout.
+    #[cfg(target_os = "linux")]
+    pub fn abstract_name(name: &str) -> Self {
+        UnixConnector {
+            addr: UnixAddr::Abstract(name.to_owned()),
+            read_timeout: Some(Duration::new(5, 0)),
+        }
+    }
+
+    /// Set the timeout of the reads of the connections, `None` for blocking
+    /// reads.
+    pub fn set_read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+}
+
+
+#[cfg(unix)]
+impl Connector for UnixConnector {
+    fn connect(&self, _: &Request, timer: &mut Timer, _: bool) -> CabotResult<Box<Stream>> {
+        let (stream, addr) = match self.addr {
+            UnixAddr::Path(ref path) => {
+                (UnixStream::connect(path), format!("{}", path.display()))
+            }
+            #[cfg(target_os = "linux")]
+            UnixAddr::Abstract(ref name) => {
+                (UnixSocketAddr::from_abstract_name(name.as_bytes())
+                     .and_then(|addr| UnixStream::connect_addr(&addr)),
+                 format!("@{}", name))
+            }
+        };
+        info!("Connecting to {}", addr);
+        let stream =
+            stream.map_err(|err| CabotError::ConnectError(format!("{}: {}", addr, err)))?;
+        timer.connect(None);
+        stream.set_read_timeout(self.read_timeout)
+            .map_err(|err| CabotError::IOError(format!("{}", err)))?;
+        Ok(Box::new(stream))
+    }
+}
+
+
+/// The stream of a connection switched to another protocol, after a
+/// `101 Switching Protocols` response.
+///
+/// The bytes sent by the server after the head of the response are read
+/// first.
+pub struct Upgraded {
+    response: Response,
+    stream: Box<Stream>,
+    buffered: Cursor<Vec<u8>>,
+}
+
+
+impl Upgraded {
+    pub(crate) fn new(response: Response, stream: Box<Stream>, buffered: Vec<u8>) -> Self {
+        Upgraded {
+            response: response,
+            stream: stream,
+            buffered: Cursor::new(buffered),
+        }
+    }
+
+    /// The response that switched the protocol.
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+}
+
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+
+impl Stream for Upgraded {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.stream.remote_addr()
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.stream.read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+
+/// A response whose body is read as it is received.
+///
+/// The chunked transfer coding is decoded, the `Content-Encoding` is
+/// decoded if the response has been requested by a client that accepts
+/// compressed responses.
+pub struct ResponseStream {
+    response: Response,
+    raw_body: Arc<Mutex<RawBody>>,
+    // Reads the raw body once its content is decoded
+    decoder: Option<Box<Read + Send>>,
+}
+
+
+impl ResponseStream {
+    /// Construct a `ResponseStream` reading the body using the parser of
+    /// the response, `None` if the response has been read. The body read
+    /// is reported to the transfer, until the end of the response.
+    pub(crate) fn new(response: Response,
+                      stream: Box<Stream>,
+                      mut parser: Option<ResponseParser>,
+                      transfer: Option<Transfer>)
+                      -> Self {
+        let body = parser.as_mut().map(|parser| parser.take_body()).unwrap_or_default();
+        let mut raw_body = RawBody {
+            stream: stream,
+            parser: parser,
+            body: Cursor::new(Vec::new()),
+            transfer: transfer,
+        };
+        // The bytes of the body received with the head are reported as well
+        let _ = raw_body.received(body);
+        ResponseStream {
+            response: response,
+            raw_body: Arc::new(Mutex::new(raw_body)),
+            decoder: None,
+        }
+    }
+
+    /// Decode the body according to its `Content-Encoding` headers while
+    /// it is read.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::EncodingError in case of an unsupported encoding
+    pub(crate) fn decode_content(&mut self) -> CabotResult<()> {
+        let content_encoding = self.response.header_values("Content-Encoding").join(", ");
+        let has_body = self.raw_body
+            .lock()
+            .unwrap()
+            .parser
+            .as_ref()
+            .map(|parser| parser.body_length() != Some(0))
+            .unwrap_or(false);
+        if self.decoder.is_none() && has_body && !content_encoding.is_empty() {
+            debug!("Decoding body using {}", content_encoding);
+            let raw_body = SharedBody(self.raw_body.clone());
+            self.decoder = Some(compression::decoder(content_encoding.as_str(), raw_body)?);
+        }
+        Ok(())
+    }
+
+    /// The head of the response.
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    /// Set the timeout of the reads of the body, `None` for blocking reads.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.raw_body.lock().unwrap().stream.set_read_timeout(timeout)
+    }
+}
+
+
+impl Read for ResponseStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.decoder {
+            Some(ref mut decoder) => decoder.read(buf),
+            None => self.raw_body.lock().unwrap().read(buf),
+        }
+    }
+}
+
+
+/// The body of a response stream, as received.
+struct RawBody {
+    stream: Box<Stream>,
+    parser: Option<ResponseParser>,
+    body: Cursor<Vec<u8>>,
+    transfer: Option<Transfer>,
+}
+
+
+impl RawBody {
+
+    /// Report the bytes of the body received to the transfer, and the end
+    /// of the transfer once the response is complete.
+    fn received(&mut self, body: Vec<u8>) -> io::Result<()> {
+        let complete = self.parser.as_ref().map(|parser| parser.is_complete()).unwrap_or(true);
+        if let Some(ref mut transfer) = self.transfer {
+            transfer.received(body.len()).map_err(|err| match err {
+                    CabotError::TimeoutError(message) => {
+                        io::Error::new(io::ErrorKind::TimedOut, message)
+                    }
+                    err => io::Error::new(io::ErrorKind::Other, format!("{:?}", err)),
+                })?;
+        }
+        if complete {
+            if let Some(mut transfer) = self.transfer.take() {
+                transfer.finish();
+            }
+        }
+        self.body = Cursor::new(body);
+        Ok(())
+    }
+}
+
+
+impl Read for RawBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = [0; 4096];
+        loop {
+            let count = self.body.read(buf)?;
+            if count > 0 {
+                return Ok(count);
+            }
+            if self.parser.as_ref().map(|parser| parser.is_complete()).unwrap_or(true) {
+                return Ok(0);
+            }
+            let count = self.stream.read(&mut received)?;
+            if count == 0 {
+                // Check that the body is delimited by the end of the connection
+                let parser = self.parser.take().unwrap();
+                let finished = parser.finish()
+                    .map(|_| 0)
+                    .map_err(|err| parse_error(io::ErrorKind::UnexpectedEof, err));
+                if let Some(mut transfer) = self.transfer.take() {
+                    transfer.finish();
+                }
+                return finished;
+            }
+            let body = {
+                let parser = self.parser.as_mut().unwrap();
+                parser.feed(&received[..count])
+                    .map_err(|err| parse_error(io::ErrorKind::InvalidData, err))?;
+                parser.take_body()
+            };
+            self.received(body)?;
+        }
+    }
+}
+
+
+/// The raw body read by the decoder of a response stream.
+struct SharedBody(Arc<Mutex<RawBody>>);
+
+
+impl Read for SharedBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+
+fn parse_error(kind: io::ErrorKind, err: CabotError) -> io::Error {
+    match err {
+        CabotError::HttpResponseParseError(message) => io::Error::new(kind, message),
+        err => io::Error::new(kind, format!("{:?}", err)),
+    }
+}
+
+
+/// Replay canned responses, and record the requests.
+///
+/// Every connection reads the next response, in the order they have been
+/// added, then is closed. The connectors cloned share their responses and
+/// requests.
+#[derive(Debug, Clone)]
+pub struct MockConnector {
+    responses: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+
+impl MockConnector {
+    /// Construct a new `MockConnector`, without responses.
+    pub fn new() -> Self {
+        MockConnector {
+            responses: Arc::new(Mutex::new(VecDeque::new())),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Add the raw response read by the next connection.
+    pub fn add_response(self, response: &[u8]) -> Self {
+        self.responses.lock().unwrap().push_back(response.to_vec());
+        self
+    }
+
+    /// The bytes written on every connection, in the order they have been
+    /// opened.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+
+impl Connector for MockConnector {
+    fn connect(&self, request: &Request, _: &mut Timer, _: bool) -> CabotResult<Box<Stream>> {
+        let response = self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| {
+                CabotError::IOError(format!("No response left to connect to {}",
+                                            request.authority()))
+            })?;
+        let mut requests = self.requests.lock().unwrap();
+        requests.push(Vec::new());
+        Ok(Box::new(MockStream {
+            response: Cursor::new(response),
+            requests: self.requests.clone(),
+            index: requests.len() - 1,
+        }))
+    }
+}
+
+
+struct MockStream {
+    response: Cursor<Vec<u8>>,
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    index: usize,
+}
+
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.response.read(buf)
+    }
+}
+
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.requests.lock().unwrap()[self.index].extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+impl Stream for MockStream {}
+
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::{env, fs, process, thread};
+    use std::net::TcpListener;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+    use super::super::client::Client;
+    use super::super::request::RequestBuilder;
+
+    #[test]
+    fn test_mock_connector() {
+        let connector = MockConnector::new()
+            .add_response(b"HTTP/1.1 200 OK\r\n\r\nfirst")
+            .add_response(b"HTTP/1.1 200 OK\r\n\r\nsecond");
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let mut timer = Timer::new();
+        for expected in &[&b"HTTP/1.1 200 OK\r\n\r\nfirst"[..], b"HTTP/1.1 200 OK\r\n\r\nsecond"] {
+            let mut stream = connector.clone().connect(&request, &mut timer, false).unwrap();
+            stream.write_all(b"ping").unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            assert_eq!(response.as_slice(), *expected);
+        }
+        assert_eq!(connector.requests(), vec![b"ping".to_vec(), b"ping".to_vec()]);
+        match connector.connect(&request, &mut timer, false) {
+            Err(CabotError::IOError(_)) => {}
+            _ => panic!("The connection should fail"),
+        }
+    }
+
+    #[test]
+    fn test_tcp_connector_errors() {
+        let connector = TcpConnector::new();
+        let mut timer = Timer::new();
+        let request = RequestBuilder::new("http://cabot.invalid/").build().unwrap();
+        match connector.connect(&request, &mut timer, false) {
+            Err(CabotError::DnsError(_)) => {}
+            _ => panic!("The DNS lookup should fail"),
+        }
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let request = RequestBuilder::new(&format!("http://127.0.0.1:{}/", port)).build().unwrap();
+        match connector.connect(&request, &mut timer, false) {
+            Err(CabotError::ConnectError(_)) => {}
+            _ => panic!("The connection should be refused"),
+        }
+    }
+
+    #[cfg(unix)]
+    fn serve_unix(listener: UnixListener) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let count = sock.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..count]);
+            }
+            sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOk").unwrap();
+            request
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_connector() {
+        let path = env::temp_dir().join(format!("cabot-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let server = serve_unix(UnixListener::bind(&path).unwrap());
+        let client = Client::new().set_connector(UnixConnector::new(&path));
+        let request = RequestBuilder::new("http://docker/v1.24/info").build().unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Ok");
+        let request = String::from_utf8(server.join().unwrap()).unwrap();
+        assert!(request.starts_with("GET /v1.24/info HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nHost: docker\r\n"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_abstract_unix_connector() {
+        let name = format!("cabot-{}", process::id());
+        let addr = UnixSocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let server = serve_unix(UnixListener::bind_addr(&addr).unwrap());
+        let client = Client::new().set_connector(UnixConnector::abstract_name(&name));
+        let request = RequestBuilder::new("http://localhost/").build().unwrap();
+        let response = client.execute(&request).unwrap();
+        assert_eq!(response.body_as_string().unwrap(), "Ok");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,778 @@
+//! WebSocket client, as defined in RFC 6455.
+//!
+//! The [WebSocket](struct.WebSocket.html) connects to a `ws://` or a
+//! `wss://` url, the handshake is an HTTP request upgraded by the
+//! [Client](../client/struct.Client.html). The messages are sent masked,
+//! fragmented if they are larger than the fragment size, and compressed
+//! if the server accepted the `permessage-deflate` extension.
+//!
+//! The pings of the server are answered with a pong, and its close is
+//! acknowledged.
+//!
+//! # Example
+//! ```no_run
+//! use cabot::{Client, RequestBuilder};
+//! use cabot::websocket::{Message, WebSocket};
+//!
+//! let request = RequestBuilder::new("wss://echo.websocket.org/").build().unwrap();
+//! let mut websocket = WebSocket::connect(&Client::new(), &request).unwrap();
+//! websocket.send(Message::Text("Hello".to_owned())).unwrap();
+//! assert_eq!(websocket.receive().unwrap(), Message::Text("Hello".to_owned()));
+//! websocket.close(1000, "").unwrap();
+//! ```
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::time::Duration;
+
+use base64;
+use flate2::Compression;
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use rand;
+use sha1::{Sha1, Digest};
+
+use super::client::Client;
+use super::request::Request;
+use super::response::Response;
+use super::results::{CabotResult, CabotError};
+use super::transport::{Stream, Upgraded};
+
+/// Default size of the payload of the frames sent.
+pub const FRAGMENT_SIZE: usize = 64 * 1024;
+/// Default maximum size of the messages received.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const BUFFER_PAGE_SIZE: usize = 4096;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+// The bytes ending a block compressed with a sync flush
+const DEFLATE_TRAILER: &'static [u8] = b"\x00\x00\xff\xff";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+const RSV1: u8 = 0x40;
+
+
+/// A message sent or received.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code and the reason of the close, if any.
+    Close(Option<(u16, String)>),
+}
+
+
+/// A frame, unmasked.
+#[derive(Debug, Clone)]
+pub(crate) struct Frame {
+    pub fin: bool,
+    pub rsv: u8,
+    pub opcode: u8,
+    pub masked: bool,
+    pub payload: Vec<u8>,
+}
+
+
+impl Frame {
+    pub fn new(fin: bool, rsv: u8, opcode: u8, payload: Vec<u8>) -> Self {
+        Frame {
+            fin: fin,
+            rsv: rsv,
+            opcode: opcode,
+            masked: false,
+            payload: payload,
+        }
+    }
+
+    /// Decode the frame at the start of `buf`, with the count of bytes
+    /// consumed, `None` if the frame is incomplete.
+    pub fn decode(buf: &[u8], max_size: usize) -> CabotResult<Option<(Frame, usize)>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let masked = buf[1] & 0x80 != 0;
+        let (length, mut position) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (((buf[2] as u64) << 8) | buf[3] as u64, 4),
+            127 if buf.len() >= 10 => {
+                (buf[2..10].iter().fold(0, |length, &byte| (length << 8) | byte as u64), 10)
+            }
+            126 | 127 => return Ok(None),
+            length => (length as u64, 2),
+        };
+        if length > max_size as u64 {
+            return Err(CabotError::WebSocketError(format!("Frame larger than {} bytes",
+                                                          max_size)));
+        }
+        let mask = if masked {
+            if buf.len() < position + 4 {
+                return Ok(None);
+            }
+            position += 4;
+            Some(&buf[position - 4..position])
+        } else {
+            None
+        };
+        let length = length as usize;
+        if buf.len() < position + length {
+            return Ok(None);
+        }
+        let mut payload = buf[position..position + length].to_vec();
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+        let frame = Frame {
+            fin: buf[0] & 0x80 != 0,
+            rsv: buf[0] & 0x70,
+            opcode: buf[0] & 0x0F,
+            masked: masked,
+            payload: payload,
+        };
+        Ok(Some((frame, position + length)))
+    }
+
+    /// Encode the frame, masked with the key if any.
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.payload.len() + 14);
+        buf.push(if self.fin { 0x80 } else { 0 } | self.rsv | self.opcode);
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let length = self.payload.len();
+        if length < 126 {
+            buf.push(mask_bit | length as u8);
+        } else if length <= 0xFFFF {
+            buf.push(mask_bit | 126);
+            buf.extend_from_slice(&[(length >> 8) as u8, length as u8]);
+        } else {
+            buf.push(mask_bit | 127);
+            for shift in (0..8).rev() {
+                buf.push(((length as u64) >> (shift * 8)) as u8);
+            }
+        }
+        let start = buf.len();
+        if let Some(mask) = mask {
+            buf.extend_from_slice(&mask);
+        }
+        buf.extend_from_slice(&self.payload);
+        if let Some(mask) = mask {
+            apply_mask(&mut buf[start + 4..], &mask);
+        }
+        buf
+    }
+}
+
+
+fn apply_mask(payload: &mut [u8], mask: &[u8]) {
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+}
+
+
+/// The `Sec-WebSocket-Accept` header expected for the key of the
+/// handshake.
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::default();
+    hasher.input(key.as_bytes());
+    hasher.input(GUID.as_bytes());
+    base64::encode(&hasher.result())
+}
+
+
+/// The `permessage-deflate` extension, as negotiated.
+struct Deflate {
+    client_no_context_takeover: bool,
+    server_no_context_takeover: bool,
+    encoder: DeflateEncoder<Vec<u8>>,
+    decoder: DeflateDecoder<Vec<u8>>,
+}
+
+
+impl Deflate {
+    /// Negotiate the extension from the `Sec-WebSocket-Extensions` header
+    /// of the response, `None` if the server did not accept it.
+    fn from_response(response: &Response) -> CabotResult<Option<Self>> {
+        let mut deflate = None;
+        for extension in response.header_values("Sec-WebSocket-Extensions") {
+            for extension in extension.split(',') {
+                let mut params = extension.split(';').map(|param| param.trim());
+                let name = params.next().unwrap();
+                if !name.eq_ignore_ascii_case("permessage-deflate") || deflate.is_some() {
+                    return Err(CabotError::WebSocketError(format!("Unexpected extension {}",
+                                                                  extension.trim())));
+                }
+                let mut client_no_context_takeover = false;
+                let mut server_no_context_takeover = false;
+                for param in params {
+                    let name = param.splitn(2, '=').next().unwrap().trim();
+                    match name.to_lowercase().as_str() {
+                        "client_no_context_takeover" => client_no_context_takeover = true,
+                        "server_no_context_takeover" => server_no_context_takeover = true,
+                        // The decoder accepts any window size
+                        "server_max_window_bits" => {}
+                        _ => {
+                            return Err(CabotError::WebSocketError(format!("Unexpected \
+                                                                           permessage-deflate \
+                                                                           parameter {}",
+                                                                          param)))
+                        }
+                    }
+                }
+                deflate = Some(Deflate {
+                    client_no_context_takeover: client_no_context_takeover,
+                    server_no_context_takeover: server_no_context_takeover,
+                    encoder: DeflateEncoder::new(Vec::new(), Compression::default()),
+                    decoder: DeflateDecoder::new(Vec::new()),
+                });
+            }
+        }
+        Ok(deflate)
+    }
+
+    fn compress(&mut self, payload: &[u8]) -> CabotResult<Vec<u8>> {
+        if self.client_no_context_takeover {
+            self.encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        }
+        self.encoder.write_all(payload).map_err(deflate_error)?;
+        self.encoder.flush().map_err(deflate_error)?;
+        let mut compressed = mem::replace(self.encoder.get_mut(), Vec::new());
+        if compressed.ends_with(DEFLATE_TRAILER) {
+            let length = compressed.len() - DEFLATE_TRAILER.len();
+            compressed.truncate(length);
+        }
+        Ok(compressed)
+    }
+
+    fn decompress(&mut self, payload: &[u8], max_size: usize) -> CabotResult<Vec<u8>> {
+        if self.server_no_context_takeover {
+            self.decoder = DeflateDecoder::new(Vec::new());
+        }
+        for chunk in payload.chunks(BUFFER_PAGE_SIZE).chain(Some(DEFLATE_TRAILER)) {
+            self.decoder.write_all(chunk).map_err(deflate_error)?;
+            if self.decoder.get_ref().len() > max_size {
+                return Err(CabotError::WebSocketError(format!("Message larger than {} bytes",
+                                                              max_size)));
+            }
+        }
+        self.decoder.flush().map_err(deflate_error)?;
+        Ok(mem::replace(self.decoder.get_mut(), Vec::new()))
+    }
+}
+
+
+fn deflate_error(err: io::Error) -> CabotError {
+    CabotError::WebSocketError(format!("Invalid compressed message: {}", err))
+}
+
+
+fn stream_error(err: io::Error) -> CabotError {
+    match err.kind() {
+        io::ErrorKind::WouldBlock |
+        io::ErrorKind::TimedOut => CabotError::TimeoutError(format!("{}", err)),
+        _ => CabotError::IOError(format!("{}", err)),
+    }
+}
+
+
+/// A WebSocket connection to a server.
+pub struct WebSocket {
+    stream: Upgraded,
+    protocol: Option<String>,
+    deflate: Option<Deflate>,
+    fragment_size: usize,
+    max_message_size: usize,
+    // Bytes received, not parsed yet
+    incoming: Vec<u8>,
+    // Opcode, compression and payload of the fragmented message received
+    fragments: Option<(u8, bool, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+
+impl WebSocket {
+    /// Open a WebSocket connection using the client, the handshake
+    /// headers are added to the request. The `permessage-deflate`
+    /// extension is offered, unless the request has a
+    /// `Sec-WebSocket-Extensions` header.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::UpgradeError if the server did not switch protocols
+    ///  - CabotError::WebSocketError if the handshake of the server is
+    ///    invalid
+    pub fn connect(client: &Client, request: &Request) -> CabotResult<Self> {
+        let key_bytes: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
+        let key = base64::encode(&key_bytes);
+        let mut request = request.clone();
+        request.add_header("Upgrade: websocket");
+        request.add_header("Connection: Upgrade");
+        request.add_header(&format!("Sec-WebSocket-Key: {}", key));
+        request.add_header("Sec-WebSocket-Version: 13");
+        if request.header("Sec-WebSocket-Extensions").is_none() {
+            request.add_header("Sec-WebSocket-Extensions: permessage-deflate");
+        }
+
+        let stream = client.upgrade(&request)?;
+        let protocol = {
+            let response = stream.response();
+            let upgrade = response.header("Upgrade").unwrap_or("");
+            if !upgrade.eq_ignore_ascii_case("websocket") {
+                return Err(CabotError::WebSocketError(format!("Invalid Upgrade header {:?}",
+                                                              upgrade)));
+            }
+            let connection = response.header_values("Connection").join(",");
+            if !connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")) {
+                return Err(CabotError::WebSocketError(format!("Invalid Connection header {:?}",
+                                                              connection)));
+            }
+            let accept = response.header("Sec-WebSocket-Accept").unwrap_or("");
+            if accept != accept_key(&key) {
+                return Err(CabotError::WebSocketError(format!("Invalid Sec-WebSocket-Accept \
+                                                               header {:?}",
+                                                              accept)));
+            }
+            match response.header("Sec-WebSocket-Protocol") {
+                Some(protocol) => {
+                    let offered = request.header("Sec-WebSocket-Protocol").unwrap_or("");
+                    if !offered.split(',').any(|offered| offered.trim() == protocol) {
+                        return Err(CabotError::WebSocketError(format!("Unexpected protocol {}",
+                                                                      protocol)));
+                    }
+                    Some(protocol.to_owned())
+                }
+                None => None,
+            }
+        };
+        let deflate = Deflate::from_response(stream.response())?;
+        info!("WebSocket connected{}",
+              if deflate.is_some() { " using permessage-deflate" } else { "" });
+        Ok(WebSocket {
+            stream: stream,
+            protocol: protocol,
+            deflate: deflate,
+            fragment_size: FRAGMENT_SIZE,
+            max_message_size: MAX_MESSAGE_SIZE,
+            incoming: Vec::new(),
+            fragments: None,
+            close_sent: false,
+            close_received: false,
+        })
+    }
+
+    /// Set the maximum size of the payload of the frames sent, larger
+    /// messages are fragmented.
+    pub fn set_fragment_size(mut self, fragment_size: usize) -> Self {
+        self.fragment_size = if fragment_size > 0 { fragment_size } else { 1 };
+        self
+    }
+
+    /// Set the maximum size of the messages received.
+    pub fn set_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Set the timeout of [receive](#method.receive), `None` to wait for
+    /// the messages.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> CabotResult<()> {
+        self.stream.set_read_timeout(timeout).map_err(stream_error)
+    }
+
+    /// The subprotocol selected by the server, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|protocol| protocol.as_str())
+    }
+
+    /// The response of the handshake.
+    pub fn response(&self) -> &Response {
+        self.stream.response()
+    }
+
+    /// Send a message.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::WebSocketError if the connection is closed, or if
+    ///    the payload of a control message is larger than 125 bytes
+    pub fn send(&mut self, message: Message) -> CabotResult<()> {
+        if self.close_sent {
+            return Err(CabotError::WebSocketError("The connection is closed".to_owned()));
+        }
+        match message {
+            Message::Text(text) => self.send_data(TEXT, text.as_bytes()),
+            Message::Binary(data) => self.send_data(BINARY, data.as_slice()),
+            Message::Ping(payload) => self.send_control(PING, payload),
+            Message::Pong(payload) => self.send_control(PONG, payload),
+            Message::Close(close) => {
+                let payload = match close {
+                    Some((code, reason)) => {
+                        let mut payload = vec![(code >> 8) as u8, code as u8];
+                        payload.extend_from_slice(reason.as_bytes());
+                        payload
+                    }
+                    None => Vec::new(),
+                };
+                self.send_control(CLOSE, payload)?;
+                self.close_sent = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn send_data(&mut self, opcode: u8, payload: &[u8]) -> CabotResult<()> {
+        let (rsv, payload) = match self.deflate {
+            Some(ref mut deflate) => (RSV1, deflate.compress(payload)?),
+            None => (0, payload.to_vec()),
+        };
+        let mut fragments = payload.chunks(self.fragment_size).peekable();
+        let mut first = true;
+        loop {
+            let fragment = fragments.next().unwrap_or(&[]);
+            let fin = fragments.peek().is_none();
+            let frame = if first {
+                Frame::new(fin, rsv, opcode, fragment.to_vec())
+            } else {
+                Frame::new(fin, 0, CONTINUATION, fragment.to_vec())
+            };
+            self.write_frame(&frame)?;
+            first = false;
+            if fin {
+                break;
+            }
+        }
+        self.stream.flush().map_err(stream_error)
+    }
+
+    fn send_control(&mut self, opcode: u8, payload: Vec<u8>) -> CabotResult<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(CabotError::WebSocketError(format!("Control frame payload larger than \
+                                                           {} bytes",
+                                                          MAX_CONTROL_PAYLOAD)));
+        }
+        self.write_frame(&Frame::new(true, 0, opcode, payload))?;
+        self.stream.flush().map_err(stream_error)
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> CabotResult<()> {
+        debug!("Sending frame opcode {:x} of {} bytes",
+               frame.opcode,
+               frame.payload.len());
+        let mask = [rand::random::<u8>(),
+                    rand::random::<u8>(),
+                    rand::random::<u8>(),
+                    rand::random::<u8>()];
+        self.stream.write_all(&frame.encode(Some(mask))).map_err(stream_error)
+    }
+
+    /// Receive the next message. The pings are answered, and the close of
+    /// the server is acknowledged, before they are returned.
+    ///
+    /// A timeout does not lose the message being received, it is returned
+    /// by the next call.
+    ///
+    /// Errors:
+    ///
+    ///  - CabotError::TimeoutError if no message has been received before
+    ///    the read timeout
+    ///  - CabotError::WebSocketError if a frame is invalid, or if the
+    ///    connection is closed
+    pub fn receive(&mut self) -> CabotResult<Message> {
+        if self.close_received {
+            return Err(CabotError::WebSocketError("The connection is closed".to_owned()));
+        }
+        loop {
+            let frame = self.read_frame()?;
+            let allowed_rsv = match (frame.opcode, &self.deflate) {
+                (TEXT, &Some(_)) |
+                (BINARY, &Some(_)) => RSV1,
+                _ => 0,
+            };
+            if frame.rsv & !allowed_rsv != 0 {
+                return Err(CabotError::WebSocketError(format!("Unexpected RSV bits {:x}",
+                                                              frame.rsv)));
+            }
+            match frame.opcode {
+                PING | PONG | CLOSE if !frame.fin ||
+                                       frame.payload.len() > MAX_CONTROL_PAYLOAD => {
+                    return Err(CabotError::WebSocketError("Invalid control frame".to_owned()));
+                }
+                PING => {
+                    if !self.close_sent {
+                        self.send_control(PONG, frame.payload.clone())?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                PONG => return Ok(Message::Pong(frame.payload)),
+                CLOSE => return self.received_close(frame.payload),
+                TEXT | BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(CabotError::WebSocketError("Expected a continuation frame"
+                            .to_owned()));
+                    }
+                    if frame.fin {
+                        return self.message(frame.opcode, frame.rsv == RSV1, frame.payload);
+                    }
+                    self.fragments = Some((frame.opcode, frame.rsv == RSV1, frame.payload));
+                }
+                CONTINUATION => {
+                    let (opcode, compressed, mut payload) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => {
+                            return Err(CabotError::WebSocketError("Unexpected continuation \
+                                                                   frame"
+                                .to_owned()))
+                        }
+                    };
+                    payload.extend_from_slice(&frame.payload);
+                    if payload.len() > self.max_message_size {
+                        return Err(CabotError::WebSocketError(format!("Message larger than \
+                                                                       {} bytes",
+                                                                      self.max_message_size)));
+                    }
+                    if frame.fin {
+                        return self.message(opcode, compressed, payload);
+                    }
+                    self.fragments = Some((opcode, compressed, payload));
+                }
+                opcode => {
+                    return Err(CabotError::WebSocketError(format!("Unknown opcode {:x}",
+                                                                  opcode)))
+                }
+            }
+        }
+    }
+
+    /// Read the next frame, the bytes received are kept if the read times
+    /// out.
+    fn read_frame(&mut self) -> CabotResult<Frame> {
+        let mut buf = [0; BUFFER_PAGE_SIZE];
+        loop {
+            if let Some((frame, consumed)) = Frame::decode(&self.incoming,
+                                                           self.max_message_size)? {
+                self.incoming.drain(..consumed);
+                if frame.masked {
+                    return Err(CabotError::WebSocketError("Masked frame received".to_owned()));
+                }
+                debug!("Received frame opcode {:x} of {} bytes",
+                       frame.opcode,
+                       frame.payload.len());
+                return Ok(frame);
+            }
+            match self.stream.read(&mut buf).map_err(stream_error)? {
+                0 => {
+                    return Err(CabotError::WebSocketError("The connection has been closed"
+                        .to_owned()))
+                }
+                count => self.incoming.extend_from_slice(&buf[..count]),
+            }
+        }
+    }
+
+    fn message(&mut self, opcode: u8, compressed: bool, payload: Vec<u8>) -> CabotResult<Message> {
+        let payload = match self.deflate {
+            Some(ref mut deflate) if compressed => {
+                deflate.decompress(&payload, self.max_message_size)?
+            }
+            _ => payload,
+        };
+        if opcode == TEXT {
+            String::from_utf8(payload)
+                .map(Message::Text)
+                .map_err(|_| CabotError::WebSocketError("Invalid UTF-8 text message".to_owned()))
+        } else {
+            Ok(Message::Binary(payload))
+        }
+    }
+
+    fn received_close(&mut self, payload: Vec<u8>) -> CabotResult<Message> {
+        self.close_received = true;
+        let close = match payload.len() {
+            0 => None,
+            1 => return Err(CabotError::WebSocketError("Invalid close frame".to_owned())),
+            _ => {
+                let code = ((payload[0] as u16) << 8) | payload[1] as u16;
+                let reason = String::from_utf8(payload[2..].to_vec())
+                    .map_err(|_| CabotError::WebSocketError("Invalid UTF-8 close reason"
+                        .to_owned()))?;
+                Some((code, reason))
+            }
+        };
+        if !self.close_sent {
+            // Acknowledge the close with its status code
+            self.send_control(CLOSE, payload[..payload.len().min(2)].to_vec())?;
+            self.close_sent = true;
+        }
+        Ok(Message::Close(close))
+    }
+
+    /// Close the connection with a status code, such as `1000` for a
+    /// normal closure, and wait for the close of the server.
+    ///
+    /// The messages received meanwhile are dropped.
+    pub fn close(&mut self, code: u16, reason: &str) -> CabotResult<()> {
+        if !self.close_sent {
+            self.send(Message::Close(Some((code, reason.to_owned()))))?;
+        }
+        while !self.close_received {
+            match self.receive() {
+                Ok(_) => {}
+                // The server closed the connection, or did not answer
+                Err(CabotError::WebSocketError(_)) |
+                Err(CabotError::TimeoutError(_)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::request::RequestBuilder;
+    use super::super::testing::{MockServer, MockResponse};
+
+    fn connect(server: &MockServer, deflate: bool) -> WebSocket {
+        server.add_response(MockResponse::websocket_echo(deflate));
+        let url = server.url("/echo").replacen("http", "ws", 1);
+        let request = RequestBuilder::new(&url).build().unwrap();
+        let client = Client::new().set_connector(server.connector());
+        WebSocket::connect(&client, &request).unwrap()
+    }
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+                   "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let frame = Frame::new(true, 0, TEXT, b"Hello".to_vec());
+        assert_eq!(frame.encode(None), b"\x81\x05Hello");
+        let encoded = frame.encode(Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(encoded, b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58");
+        let (decoded, consumed) = Frame::decode(&encoded, 125).unwrap().unwrap();
+        assert_eq!(consumed, encoded.len());
+        assert!(decoded.fin && decoded.masked);
+        assert_eq!(decoded.payload, b"Hello");
+        assert!(Frame::decode(&encoded[..8], 125).unwrap().is_none());
+
+        let frame = Frame::new(false, 0, BINARY, vec![0; 70000]);
+        let encoded = frame.encode(None);
+        assert_eq!(&encoded[..10], b"\x02\x7f\x00\x00\x00\x00\x00\x01\x11\x70");
+        let (decoded, _) = Frame::decode(&encoded, 70000).unwrap().unwrap();
+        assert!(!decoded.fin);
+        assert_eq!(decoded.payload.len(), 70000);
+        match Frame::decode(&encoded, 1024) {
+            Err(CabotError::WebSocketError(message)) => {
+                assert_eq!(message, "Frame larger than 1024 bytes")
+            }
+            _ => panic!("The frame should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_echo() {
+        let server = MockServer::start();
+        let mut websocket = connect(&server, false);
+        assert_eq!(websocket.response().status_code(), 101);
+        assert_eq!(websocket.protocol(), None);
+        websocket.send(Message::Text("Hello".to_owned())).unwrap();
+        assert_eq!(websocket.receive().unwrap(), Message::Text("Hello".to_owned()));
+        websocket.send(Message::Binary(vec![0, 1, 2])).unwrap();
+        assert_eq!(websocket.receive().unwrap(), Message::Binary(vec![0, 1, 2]));
+        websocket.send(Message::Ping(b"ping".to_vec())).unwrap();
+        assert_eq!(websocket.receive().unwrap(), Message::Pong(b"ping".to_vec()));
+        websocket.close(1000, "Bye").unwrap();
+        match websocket.send(Message::Text("Hello".to_owned())) {
+            Err(CabotError::WebSocketError(message)) => {
+                assert_eq!(message, "The connection is closed")
+            }
+            _ => panic!("The connection should be closed"),
+        }
+
+        let request = &server.requests()[0];
+        assert_eq!(request.header("Upgrade"), Some("websocket"));
+        assert_eq!(request.header("Connection"), Some("Upgrade"));
+        assert_eq!(request.header("Sec-WebSocket-Version"), Some("13"));
+    }
+
+    #[test]
+    fn test_echo_fragmented() {
+        let server = MockServer::start();
+        let mut websocket = connect(&server, false).set_fragment_size(10);
+        let text = "Hello World! ".repeat(10);
+        websocket.send(Message::Text(text.clone())).unwrap();
+        assert_eq!(websocket.receive().unwrap(), Message::Text(text));
+        websocket.send(Message::Text(String::new())).unwrap();
+        assert_eq!(websocket.receive().unwrap(), Message::Text(String::new()));
+
+        let mut websocket = connect(&server, false).set_max_message_size(64);
+        websocket.send(Message::Binary(vec![0; 100])).unwrap();
+        match websocket.receive() {
+            Err(CabotError::WebSocketError(message)) => {
+                assert_eq!(message, "Frame larger than 64 bytes")
+            }
+            _ => panic!("The message should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_echo_compressed() {
+        let server = MockServer::start();
+        let mut websocket = connect(&server, true).set_fragment_size(16);
+        assert_eq!(websocket.response().header("Sec-WebSocket-Extensions"),
+                   Some("permessage-deflate"));
+        for _ in 0..3 {
+            let text = "Hello World! ".repeat(20);
+            websocket.send(Message::Text(text.clone())).unwrap();
+            assert_eq!(websocket.receive().unwrap(), Message::Text(text));
+        }
+        websocket.close(1000, "").unwrap();
+        assert_eq!(server.requests()[0].header("Sec-WebSocket-Extensions"),
+                   Some("permessage-deflate"));
+    }
+
+    #[test]
+    fn test_echo_https() {
+        let server = MockServer::start_https();
+        let mut websocket = connect(&server, true);
+        websocket.send(Message::Text("Hello".to_owned())).unwrap();
+        assert_eq!(websocket.receive().unwrap(), Message::Text("Hello".to_owned()));
+        websocket.close(1000, "").unwrap();
+    }
+
+    #[test]
+    fn test_invalid_handshake() {
+        let server = MockServer::start();
+        server.add_response(MockResponse::new("101 Switching Protocols")
+            .add_header("Upgrade: websocket")
+            .add_header("Connection: Upgrade")
+            .add_header("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        server.add_response(MockResponse::new("200 OK"));
+        let url = server.url("/echo").replacen("http", "ws", 1);
+        let request = RequestBuilder::new(&url).build().unwrap();
+        match WebSocket::connect(&Client::new(), &request) {
+            Err(CabotError::WebSocketError(message)) => {
+                assert_eq!(message,
+                           "Invalid Sec-WebSocket-Accept header \
+                            \"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\"")
+            }
+            _ => panic!("The handshake should be rejected"),
+        }
+        match WebSocket::connect(&Client::new(), &request) {
+            Err(CabotError::UpgradeError(message)) => {
+                assert_eq!(message, "The server answered 200 OK")
+            }
+            _ => panic!("The handshake should be rejected"),
+        }
+    }
+}